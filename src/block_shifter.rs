@@ -1,145 +1,1222 @@
 //! This defines our algorithm for determining what kinds of 
 //! algorithmic shenanigans will be done to shift about partitions
 
+use uefi::prelude::*;
+use uefi::Guid;
+use uefi::proto::media::block::BlockIO;
+use crate::alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::cmp;
+
 // import the required MBR/GPT struct definitions
 use crate::partitions::{
-    GPT,
+    Extent,
+    GPTDisk,
     GPTPartition,
     MBR
 };
+use crate::partitions::extents::align_up;
+use crate::fs::{
+    copy_tree,
+    find_collision,
+    probe,
+    Filesystem,
+    PartitionDevice
+};
 
 // import the required helper functions
 use crate::helpers::{
-    get_free_ram_size,
+    crc32,
+    random_guid,
+    read_char
+};
+use crate::memory::{
+    allocate_copy_buffer,
+    PageBuffer
+};
+
+use crate::journal::{
+    Journal,
+    JournalOp
 };
 
+/// describes which end of a range a copy starts from
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CopyDirection {
+    Forward,    // lowest LBA first
+    Backward    // highest LBA first
+}
+
 /*
-Assuming we know the two partitions we want to swap on disk
+Assuming we know the two partitions we want to swap on disk 
 the approach we are gonna use for swapping is as follows: 
 
-1. Allocate as much memory (disk block aligned) as possible FOR EACH PARTITION
-    - Call these two chunks PBLOCK_1 and PBLOCK_2
-    - Note that the requirement is that (SIZE/2) % BLOCK_SIZE = 0
-      which needs to be gueranteed so we can properly shift the blocks around
-      on the disk
-2. Read as many blocks from partition 1 -> PBLOCK_1
-3. Read as many blocks from parititon 2 -> PBLOCK_2
-4. Write PBLOCK_1 to the head of partition 2, and PBLOCK_2 to head of part1
-5. Increment counters and offset, ZERO PBLOCK MEMORY, then repeat for the next offset
-    - ISSUE: if the partitions are unequally sized, then program needs to be 
-             able to determine how to place the partitions such that the growth 
-             of a partition doesn't impact
+1. Work out where each partition ends up: the second one starts where the 
+   first one started, and the first one ends where the second one ended.
+   Anything between them (free space) stays between them.
+2. Pick the smaller of the two partitions (X) and find a free spot on the disk
+   big enough to stage it in, which doesn't overlap either final position
+3. Move X into the staging area
+4. Move the other partition (Y) into its final position
+5. Move X from the staging area into its final position
+
+Every step is a normal journaled move that commits the partition table at the
+end, so if we lose power in the middle the table always describes where the 
+data really is. Unequal sizes don't matter since nothing is swapped in place.
 
+(if we only want to change the order of the entries, swapping the partition 
+entries with `GPTDisk::swap_entries` is a lot cheaper than this)
 
+*/
 
+/// the alignment we give to partitions we place ourselves
+const ALIGNMENT_BYTES: u64 = 1024 * 1024;
 
-OR WE COULD BE SMART AND JUST SWAP THE PARTITION ENTRIES ON THE DISK LOL
+/// defines a single partition move we intend to make
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct PlannedMove {
+    pub part_guid:  Guid,
+    pub from:       Extent,
+    pub to:         Extent
+}
 
-*/
+/// defines the list of moves needed to swap two partitions
+pub struct SwapPlan {
+    moves:      Vec<PlannedMove>,
+    blocksize:  u32
+}
+
+
+/// returns the partition alignment in sectors for the given block size
+pub fn alignment_sectors(blocksize: u32) -> u64 {
+    cmp::max(ALIGNMENT_BYTES / blocksize as u64, 1)
+}
+
+
+////////////////////////// SWAPPLAN IMPL //////////////////////////////
+impl SwapPlan {
+    /// returns the moves in the order they will be made
+    pub fn moves(&self) -> &Vec<PlannedMove> {
+        &self.moves
+    }
+
+    /// returns the number of bytes that will be read
+    /// note every chunk is read once to copy it and once to verify it
+    pub fn bytes_read(&self) -> u64 {
+        2 * self.bytes_written()
+    }
+
+    /// returns the number of bytes that will be written
+    pub fn bytes_written(&self) -> u64 {
+        self.moves.iter()
+                  .map(|m| m.from.len() * self.blocksize as u64)
+                  .sum()
+    }
+
+    /// prints the plan to the console
+    pub fn print(&self) {
+        for (i, m) in self.moves.iter().enumerate() {
+            info!(
+                "Step {}: move {} from LBA {}-{} to LBA {}-{}",
+                i + 1,
+                m.part_guid,
+                m.from.first_lba(),
+                m.from.last_lba(),
+                m.to.first_lba(),
+                m.to.last_lba()
+            );
+        }
+        info!(
+            "Swap will read {} bytes and write {} bytes",
+            self.bytes_read(),
+            self.bytes_written()
+        );
+    }
+}
+
+
+/// works out the moves needed to swap the on-disk positions of two partitions
+pub fn plan_gpt_swap(disk: &GPTDisk, part_1: Guid, part_2: Guid) -> uefi::Result<SwapPlan> {
+    let (p1, p2) = match (disk.partition(part_1), disk.partition(part_2)) {
+        (Some(p1), Some(p2)) if part_1 != part_2 => (*p1, *p2),
+        _ => return Err(Status::INVALID_PARAMETER.into())
+    };
+
+    // order the partitions by where they sit on the disk
+    let (low, high) = if p1.first_lba() < p2.first_lba() { (p1, p2) } else { (p2, p1) };
+    let low_len = low.extent().len();
+    let high_len = high.extent().len();
+
+    // the high partition moves to the front, the low one to the back
+    let high_final = Extent::with_len(low.first_lba(), high_len);
+    let low_final = Extent::new(high.last_lba() - low_len + 1, high.last_lba());
+
+    // make sure nothing else lives where we want to put them
+    let mut others = disk.free_space_excluding(low.part_guid());
+    others.release(high.extent());
+    if others.overlaps(&high_final) || others.overlaps(&low_final) {
+        warn!("Partitions between the two being swapped would be overwritten");
+        return Err(Status::INVALID_PARAMETER.into());
+    }
 
-/// swaps two GPT partitions with eachother
+    // stage the smaller partition somewhere neither partition will end up
+    let (staged, other, staged_final, other_final) = if low_len <= high_len {
+        (low, high, low_final, high_final)
+    } else {
+        (high, low, high_final, low_final)
+    };
+    let mut free = disk.free_space();
+    free.reserve(high_final);
+    free.reserve(low_final);
+    let staging = match free.first_fit(staged.extent().len(), alignment_sectors(disk.blocksize())) {
+        Some(extent) => extent,
+        None => {
+            warn!(
+                "Need {} free sectors to stage partition {}, swap isn't possible",
+                staged.extent().len(),
+                staged.part_guid()
+            );
+            return Err(Status::VOLUME_FULL.into());
+        }
+    };
+
+    let mut moves: Vec<PlannedMove> = Vec::new();
+    moves.push(PlannedMove{part_guid: staged.part_guid(), from: staged.extent(), to: staging});
+    moves.push(PlannedMove{part_guid: other.part_guid(), from: other.extent(), to: other_final});
+    moves.push(PlannedMove{part_guid: staged.part_guid(), from: staging, to: staged_final});
+
+    // skip anything that is already where it needs to be
+    moves.retain(|m| m.from != m.to);
+
+    Ok(SwapPlan {
+        moves,
+        blocksize: disk.blocksize()
+    })
+}
+
+/// swaps the on-disk positions of two GPT partitions with eachother
+///
+/// With `dry_run` set nothing is written, the plan is only printed
 pub fn swap_gpt_partitions(
     st: &mut SystemTable<Boot>,
-    disk: GPT,
-    part_1: GPTPartition,
-    part_2: GPTPartition
-) -> u32 {
-    
-    0
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_1: Guid,
+    part_2: Guid,
+    dry_run: bool
+) -> uefi::Result<SwapPlan> {
+    let plan = plan_gpt_swap(disk, part_1, part_2)?;
+    plan.print();
+    if dry_run {
+        return Ok(plan);
+    }
+
+    for m in plan.moves().iter() {
+        move_gpt_partition_safe(st, img_handle, disk, m.part_guid, m.to.first_lba())?;
+    }
+
+    Ok(plan)
 }
 
-/// Moves a GPT partition to begin at a different LBA (UNSAFE)
-pub fn move_gpt_partition_safe(
+
+/// determines which direction to copy `src` to `dst` in so we never
+/// overwrite source sectors before they have been copied
+pub fn copy_direction(src: Extent, dst: Extent) -> CopyDirection {
+    // only a destination that overlaps the tail of the source needs to go
+    // backwards, everything else is safe to copy front to back
+    if dst.first_lba() > src.first_lba() && dst.overlaps(&src) {
+        CopyDirection::Backward
+    } else {
+        CopyDirection::Forward
+    }
+}
+
+/// writes a chunk and verifies it by reading it back
+fn write_verified(
+    bi: &mut BlockIO,
+    media_id: u32,
+    dst_lba: u64,
+    buf: &mut [u8],
+    checksum: u32
+) -> uefi::Result {
+    // try to write the data to the new LBA
+    bi.write_blocks(media_id, dst_lba, buf)?;
+    bi.flush_blocks()?;
+
+    // read it back to make sure the disk actually stored what we gave it
+    bi.read_blocks(media_id, dst_lba, buf)?;
+    if crc32(buf) != checksum {
+        error!("Checksum mismatch after writing LBA {}", dst_lba);
+        return Err(Status::CRC_ERROR.into());
+    }
+
+    Ok(())
+}
+
+/// copies a single chunk and verifies it by reading it back
+///
+/// Returns the checksum of the chunk's data
+fn copy_chunk(
+    bi: &mut BlockIO,
+    media_id: u32,
+    src_lba: u64,
+    dst_lba: u64,
+    buf: &mut [u8]
+) -> uefi::Result<u32> {
+    // read the data and remember what it should look like
+    bi.read_blocks(media_id, src_lba, buf)?;
+    let checksum = crc32(buf);
+
+    write_verified(bi, media_id, dst_lba, buf, checksum)?;
+    Ok(checksum)
+}
+
+/// copies `num_sectors` sectors from `src_lba` to `dst_lba` on the same disk
+///
+/// The ranges are allowed to overlap, in which case the copy direction is
+/// picked so that no sector is overwritten before it was copied
+pub fn copy_sectors(
+    bi: &mut BlockIO,
+    media_id: u32,
+    src_lba: u64,
+    dst_lba: u64,
+    num_sectors: u64,
+    buf: &mut [u8]
+) -> uefi::Result {
+    if num_sectors == 0 || src_lba == dst_lba {
+        return Ok(());
+    }
+
+    let blocksize = bi.media().block_size() as u64;
+    let chunk_len = cmp::min(buf.len() as u64 / blocksize, num_sectors);
+    if chunk_len == 0 {
+        return Err(Status::BUFFER_TOO_SMALL.into());
+    }
+
+    // make sure we include the remainder chunk at the tail
+    let num_chunks = (num_sectors + chunk_len - 1) / chunk_len;
+    let direction = copy_direction(
+        Extent::with_len(src_lba, num_sectors),
+        Extent::with_len(dst_lba, num_sectors)
+    );
+
+    for i in 0..num_chunks {
+        let chunk = match direction {
+            CopyDirection::Forward => i,
+            CopyDirection::Backward => num_chunks - 1 - i
+        };
+        let offset = chunk * chunk_len;
+        let len = cmp::min(chunk_len, num_sectors - offset);
+
+        copy_chunk(
+            bi,
+            media_id,
+            src_lba + offset,
+            dst_lba + offset,
+            &mut buf[..(len * blocksize) as usize]
+        )?;
+    }
+
+    Ok(())
+}
+
+
+/// copies `num_sectors` sectors from one disk to another, verifying every chunk
+/// note both disks must have the same block size
+pub fn copy_between_disks(
+    src: &BlockIO,
+    dst: &mut BlockIO,
+    src_lba: u64,
+    dst_lba: u64,
+    num_sectors: u64,
+    buf: &mut [u8]
+) -> uefi::Result {
+    let blocksize = src.media().block_size() as u64;
+    if dst.media().block_size() as u64 != blocksize {
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+    let chunk_len = cmp::min(buf.len() as u64 / blocksize, num_sectors);
+    if num_sectors == 0 {
+        return Ok(());
+    }
+    if chunk_len == 0 {
+        return Err(Status::BUFFER_TOO_SMALL.into());
+    }
+
+    let src_media = src.media().media_id();
+    let dst_media = dst.media().media_id();
+    let mut offset = 0;
+    while offset < num_sectors {
+        let len = cmp::min(chunk_len, num_sectors - offset);
+        let data = &mut buf[..(len * blocksize) as usize];
+
+        // the source is never written to, so no journal is needed here
+        src.read_blocks(src_media, src_lba + offset, data)?;
+        let checksum = crc32(data);
+        write_verified(dst, dst_media, dst_lba + offset, data, checksum)?;
+
+        offset += len;
+    }
+
+    Ok(())
+}
+
+/// determines the chunk size for a journaled copy
+///
+/// When the ranges overlap we never let a chunk be bigger than the distance
+/// between them, so writing a chunk can't clobber its own source. That way the
+/// chunk in flight can always be copied again after a power loss.
+fn journal_chunk_sectors(src_lba: u64, dst_lba: u64, num_sectors: u64, max_sectors: u64) -> u64 {
+    let shift = if src_lba > dst_lba { src_lba - dst_lba } else { dst_lba - src_lba };
+    let mut chunk_len = cmp::min(max_sectors, num_sectors);
+    if shift < num_sectors {
+        chunk_len = cmp::min(chunk_len, shift);
+    }
+    cmp::max(chunk_len, 1)
+}
+
+/// runs (or resumes) the copy described by a journal, updating it as we go
+fn run_journal(
+    bi: &mut BlockIO,
+    media_id: u32,
+    journal: &mut Journal,
+    buf: &mut [u8],
+    resuming: bool
+) -> uefi::Result {
+    let blocksize = bi.media().block_size() as u64;
+    let chunk_len = journal.chunk_sectors();
+    let num_sectors = journal.num_sectors();
+    if (buf.len() as u64 / blocksize) < chunk_len {
+        return Err(Status::BUFFER_TOO_SMALL.into());
+    }
+
+    let num_chunks = journal.num_chunks();
+    let direction = copy_direction(
+        Extent::with_len(journal.src_lba(), num_sectors),
+        Extent::with_len(journal.dst_lba(), num_sectors)
+    );
+
+    let mut verify_source = resuming;
+    for i in journal.next_chunk()..num_chunks {
+        let chunk = match direction {
+            CopyDirection::Forward => i,
+            CopyDirection::Backward => num_chunks - 1 - i
+        };
+        let offset = chunk * chunk_len;
+        let len = cmp::min(chunk_len, num_sectors - offset);
+        let data = &mut buf[..(len * blocksize) as usize];
+
+        // read the data and remember what it should look like
+        bi.read_blocks(media_id, journal.src_lba() + offset, data)?;
+        let checksum = crc32(data);
+
+        // if we are picking up after a power loss, make sure the source of
+        // the chunk that was in flight is still what we saw back then
+        if verify_source && checksum != journal.chunk_crc() {
+            error!("Source of chunk {} changed since it was journaled, giving up", i);
+            return Err(Status::CRC_ERROR.into());
+        }
+        verify_source = false;
+
+        // record the chunk before we touch the destination
+        journal.start_chunk(i, checksum);
+        journal.write(bi, media_id)?;
+
+        write_verified(bi, media_id, journal.dst_lba() + offset, data, checksum)?;
+    }
+
+    // mark every chunk as done
+    journal.start_chunk(num_chunks, 0);
+    journal.write(bi, media_id)
+}
+
+/// updates the partition table for a finished journal, then clears the journal
+fn finish_journal(bi: &mut BlockIO, disk: &mut GPTDisk, journal: &Journal) -> uefi::Result {
+    let copied_to = Extent::with_len(journal.dst_lba(), journal.num_sectors());
+    match journal.operation() {
+        JournalOp::Move => {
+            // finally point the partition entry at the new location
+            match disk.partition_mut(journal.part_guid()) {
+                Some(part) => part.set_extent(copied_to),
+                None => return Err(Status::NOT_FOUND.into())
+            }
+            disk.write(bi)?;
+        },
+        JournalOp::Restore => {
+            // if the move had already been committed, point the entry back
+            let copied_from = Extent::with_len(journal.src_lba(), journal.num_sectors());
+            if let Some(part) = disk.partition_mut(journal.part_guid()) {
+                if part.extent() == copied_from {
+                    part.set_extent(copied_to);
+                    disk.write(bi)?;
+                }
+            }
+        }
+    }
+
+    journal.clear(bi, disk.media_id())
+}
+
+/// creates the journal that puts back everything an interrupted move did
+fn rollback_journal(journal: &Journal) -> Journal {
+    // work out which sectors were already copied, note the chunk in flight
+    // never touched its own source so it doesn't need to be put back
+    let num_sectors = journal.num_sectors();
+    let chunk_len = journal.chunk_sectors();
+    let done = cmp::min(journal.next_chunk(), journal.num_chunks());
+    let direction = copy_direction(
+        Extent::with_len(journal.src_lba(), num_sectors),
+        Extent::with_len(journal.dst_lba(), num_sectors)
+    );
+    let (start, end) = match direction {
+        CopyDirection::Forward => (0, cmp::min(done * chunk_len, num_sectors)),
+        CopyDirection::Backward => ((journal.num_chunks() - done) * chunk_len, num_sectors)
+    };
+    let start = cmp::min(start, end);
+
+    Journal::new(
+        journal.lba(),
+        JournalOp::Restore,
+        journal.part_guid(),
+        journal.dst_lba() + start,
+        journal.src_lba() + start,
+        end - start,
+        chunk_len
+    )
+}
+
+/// checks a disk for an operation that was interrupted (e.g. by a dead battery)
+/// and lets the user decide whether to finish it or roll it back
+pub fn recover_interrupted(
     st: &mut SystemTable<Boot>,
-    disk: GPT,
-    target: GPTPartition,
-    new_lba_start: u64
-) -> u32 {
-    unimplemented!();
+    img_handle: Handle,
+    disk: &mut GPTDisk
+) -> uefi::Result {
+    // look for a journal, note we close the disk again so we can ask the user
+    let journal = {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        let bi = unsafe{&*bi.interface.get()};
+        Journal::find(bi, disk)
+    };
+    let mut journal = match journal {
+        Some(journal) => journal,
+        None => return Ok(())
+    };
+
+    warn!(
+        "Found an interrupted {:?} of partition {} (LBA {} -> {}, {} of {} chunks done)",
+        journal.operation(),
+        journal.part_guid(),
+        journal.src_lba(),
+        journal.dst_lba(),
+        journal.next_chunk(),
+        journal.num_chunks()
+    );
+
+    // a roll back can only be finished, anything else is up to the user
+    let resume = match journal.operation() {
+        JournalOp::Restore => true,
+        JournalOp::Move => {
+            info!("Press 'r' to resume the operation, or 'b' to roll it back");
+            loop {
+                match read_char(st) {
+                    'r' | 'R' => break true,
+                    'b' | 'B' => break false,
+                    _ => ()
+                }
+            }
+        }
+    };
+
+    let bs = st.boot_services();
+    let bi = match disk.open(bs, img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let bi = unsafe{&mut *bi.interface.get()};
+
+    if !resume {
+        journal = rollback_journal(&journal);
+    }
+
+    // note the chunk size is baked into the journal, so we can't shrink it now,
+    // and settling for a smaller buffer would only fail in `run_journal`
+    if !journal.is_copy_done() {
+        let len = journal.chunk_sectors() * disk.blocksize() as u64;
+        let mut buf = match PageBuffer::new(bs, len as usize) {
+            Ok(buf) => buf,
+            Err(e) => {
+                error!(
+                    "Failed to allocate the {} bytes a journaled chunk needs, free up some memory and try again",
+                    len
+                );
+                return Err(e);
+            }
+        };
+        run_journal(bi, disk.media_id(), &mut journal, buf.as_mut_slice(), resume)?;
+    }
+    finish_journal(bi, disk, &journal)?;
+
+    info!("Recovered interrupted operation");
+    Ok(())
 }
 
 
 /// Moves a GPT partition to begin at a different LBA 
 /// 
-/// Panics if `new_lba_start` overlaps with another partition's existing domain
+/// Fails if the new location overlaps with another partition's existing domain
 /// If you want something that would automatically try to move the partitions 
 /// to allow the move to occur, see `move_gpt_partition_unsafe`
+///
+/// The move is journaled, so if we lose power it can be resumed or rolled
+/// back with `recover_interrupted` on the next launch
 pub fn move_gpt_partition_safe(
     st: &mut SystemTable<Boot>,
-    disk: GPTDisk,
-    target: GPTPartition,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
     new_lba_start: u64
-) -> u32 {
+) -> uefi::Result {
+    let target = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+
+    // save the disk length for when we update 
+    // the partition information later
+    let target_len = target.last_lba() - target.first_lba() + 1;
 
     // check if the partition is going to overlap with any existing partitions
-    for part in disk.partitions().iter() {
-        if part.part_guid() != target.part_guid() {
-            // check for overlaps
-            if new_lba_start < part.last_lba() && 
-               new_lba_start > part.first_lba() {
-                // what should we do here?
-                // panicking for the moment...
-                panic!("Selected partition start LBA overlaps another partition!");
-            } 
+    // note the target itself is treated as free space so it can slide over itself
+    let destination = Extent::with_len(new_lba_start, target_len);
+    if !disk.free_space_excluding(target.part_guid()).is_free(&destination) {
+        warn!("Selected partition range overlaps another partition!");
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+    if new_lba_start == target.first_lba() {
+        return Ok(());
+    }
 
+    // find somewhere to keep the journal that the move won't touch
+    let journal_lba = match Journal::pick_location(disk, &[target.extent(), destination]) {
+        Some(lba) => lba,
+        None => {
+            warn!("No free sector left to journal the move in, refusing to move");
+            return Err(Status::VOLUME_FULL.into());
         }
-    }
+    };
 
-    // save the disk length for when we update 
-    // the partition information later
-    let target_len = target.last_lba() - target.first_lba();
+    let bs = st.boot_services();
+    let bi = match disk.open(bs, img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let bi = unsafe{&mut *bi.interface.get()};
+
+    // make sure the disk is writable
+    if bi.media().is_read_only() {
+        return Err(Status::WRITE_PROTECTED.into());
+    }
 
     // determine how much memory we can use and allocate that much 
-    // note we save 32 MB for overheads just in case
-    let curr_free_ram = get_free_ram_size(st.boot_services()) - 32*1024*1024;
-    let usable_ram = curr_free_ram - (curr_free_ram % disk.blocksize);
-    let mut part_1_data = vec![0u8; usable_ram as usize]; 
-    
-    let bs = st.boot_services();
-    let handles2 = bs.find_handles::<BlockIO>()
-                         .expect_success("Failed to find handles for `BlockIO`");
-
-    // loop over all handles and see if they are for the media we want
-    for handle in handles2 {
-        let bi = bs.handle_protocol::<BlockIO>(handle)
-                   .expect_success("Failed to get `BlockIO` protocol");
-        let bi = unsafe {&* bi.get()};
-
-        // get the variables of the media we need
-        let test_media_id = bi.media().media_id();
-        let blocksize = bi.media().block_size();
-
-        // check the device's media id against the target one
-        if test_media_id == disk.media_id {
-            // make sure the disk is writable
-            if !bi.media().is_writable() {
-                panic!("Disk is not writable");
-            } 
-
-            // calculate the number of loops we should do
-            let num_loops = target_len / usable_ram;
-            // now that we know its not overlapping anything, 
-            // shift the bytes to the new position
-            for i in 0..num_loops {
-                // try to read the data
-                bi.read_blocks(disk.media_id, 
-                    target.lba_start() + (i*usable_ram), 
-                    &mut part_1_data)
-                    .expect("Failed to read bytes")
-                    .unwrap();
-
-                // try to write the data to the new LBA
-                bi.write_blocks(
-                    disk.media_id,
-                    new_lba_start + (i*usable_ram), 
-                    &mut part_1_data)
-                    .expect("Failed to write bytes")
-                    .unwrap();
-            }
-        } 
+    let blocksize = disk.blocksize();
+    let mut buf = allocate_copy_buffer(bs, blocksize, target_len)?;
+    let chunk_len = journal_chunk_sectors(
+        target.first_lba(),
+        new_lba_start,
+        target_len,
+        buf.sectors(blocksize)
+    );
+
+    // now that we know its not overlapping anything, 
+    // shift the bytes to the new position
+    let mut journal = Journal::new(
+        journal_lba,
+        JournalOp::Move,
+        part_guid,
+        target.first_lba(),
+        new_lba_start,
+        target_len,
+        chunk_len
+    );
+    run_journal(bi, disk.media_id(), &mut journal, buf.as_mut_slice(), false)?;
+    finish_journal(bi, disk, &journal)
+}
+
+
+/// works out where the partitions after `part_guid` need to go so it can grow
+/// to `num_sectors`, note they are only ever pushed towards the end of the disk
+/// 
+/// The moves are returned furthest partition first, so that every destination
+/// is already free by the time we get to it
+pub fn plan_make_room(disk: &GPTDisk, part_guid: Guid, num_sectors: u64) -> uefi::Result<Vec<PlannedMove>> {
+    let target = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+
+    // find everything that lives after the partition
+    let followers: Vec<GPTPartition> = disk.partitions()
+                                           .iter()
+                                           .filter(|p| p.is_used() && p.first_lba() > target.first_lba())
+                                           .copied()
+                                           .collect();
+    plan_push_back(disk, followers, target.first_lba() + num_sectors)
+}
+
+/// works out how to push `parts` back so none of them start before `needed_start`
+fn plan_push_back(
+    disk: &GPTDisk,
+    mut parts: Vec<GPTPartition>,
+    mut needed_start: u64
+) -> uefi::Result<Vec<PlannedMove>> {
+    let alignment = alignment_sectors(disk.blocksize());
+    parts.sort_by_key(|p| p.first_lba());
+
+    // push each partition back until the one after it is clear of it
+    let mut moves: Vec<PlannedMove> = Vec::new();
+    for part in parts.iter() {
+        if part.first_lba() >= needed_start {
+            break;
+        }
+        let to = Extent::with_len(align_up(needed_start, alignment), part.extent().len());
+        moves.push(PlannedMove{part_guid: part.part_guid(), from: part.extent(), to});
+        needed_start = to.last_lba() + 1;
+    }
+
+    if needed_start - 1 > disk.header().last_lba() {
+        warn!("Not enough space on the disk to push the other partitions back");
+        return Err(Status::VOLUME_FULL.into());
+    }
+
+    moves.reverse();
+    Ok(moves)
+}
+
+/// checks that shrinking the partition at `extent` to `num_sectors` doesn't
+/// cut off the end of whatever filesystem is on it now
+fn check_fs_fits(bi: &UnsafeCell<BlockIO>, extent: Extent, num_sectors: u64) -> uefi::Result {
+    let blocksize = unsafe{&*bi.get()}.media().block_size() as u64;
+    if num_sectors >= extent.len() {
+        return Ok(());
+    }
+    let dev = PartitionDevice::new(bi, extent);
+    let kind = match probe::probe(&dev) {
+        Some(info) => info.kind,
+        None => return Ok(())
+    };
+    match probe::fs_size(&dev) {
+        Some(size) if size <= num_sectors * blocksize => Ok(()),
+        Some(size) => {
+            warn!(
+                "{} filesystem spans {} sectors, shrink it before shrinking its partition to {}",
+                kind.name(),
+                (size + blocksize - 1) / blocksize,
+                num_sectors
+            );
+            Err(Status::INVALID_PARAMETER.into())
+        },
+        None => {
+            warn!("Can't tell how big the {} filesystem is, refusing to shrink its partition", kind.name());
+            Err(Status::UNSUPPORTED.into())
+        }
+    }
+}
+
+/// grows or shrinks a GPT partition
+///
+/// `min_sectors` is the smallest size the partition's filesystem can live with
+/// (if we have a driver that knows), and with `move_neighbours` set the
+/// partitions after it are pushed back to make room when growing
+///
+/// Only the table is changed, so the partition is never shrunk past the end
+/// of the filesystem on it (see `resize_gpt_filesystem`)
+pub fn resize_gpt_partition(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
+    num_sectors: u64,
+    min_sectors: Option<u64>,
+    move_neighbours: bool
+) -> uefi::Result {
+    let target = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        check_fs_fits(bi.interface, target.extent(), num_sectors)?;
+    }
+    set_gpt_partition_size(st, img_handle, disk, part_guid, num_sectors, min_sectors, move_neighbours)
+}
+
+/// changes the size of a GPT partition in the table, leaving its contents be
+fn set_gpt_partition_size(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
+    num_sectors: u64,
+    min_sectors: Option<u64>,
+    move_neighbours: bool
+) -> uefi::Result {
+    let target = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+
+    // never cut off the end of a filesystem
+    if let Some(min) = min_sectors {
+        if num_sectors < min {
+            warn!("Filesystem needs at least {} sectors, refusing to shrink to {}", min, num_sectors);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+    }
+
+    // make room if the partition would grow into its neighbours
+    let new_extent = Extent::with_len(target.first_lba(), num_sectors);
+    if !disk.free_space_excluding(part_guid).is_free(&new_extent) {
+        if !move_neighbours {
+            warn!("Not enough free space after the partition to grow it");
+            return Err(Status::VOLUME_FULL.into());
+        }
+        for m in plan_make_room(disk, part_guid, num_sectors)?.iter() {
+            move_gpt_partition_safe(st, img_handle, disk, m.part_guid, m.to.first_lba())?;
+        }
+    }
+
+    disk.resize_partition(part_guid, num_sectors)
+        .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    disk.write(unsafe{&mut *bi.interface.get()})
+}
+
+/// grows or shrinks an MBR partition into the free space right after it
+/// note we can't move MBR partitions yet, so neighbours are never pushed back
+///
+/// Like `resize_gpt_partition` this never shrinks past the end of the
+/// filesystem on the partition
+pub fn resize_mbr_partition(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut MBR,
+    index: usize,
+    num_sectors: u64,
+    min_sectors: Option<u64>
+) -> uefi::Result {
+    let extent = match disk.partitions().get(index) {
+        Some(part) => part.extent(),
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        check_fs_fits(bi.interface, extent, num_sectors)?;
+    }
+    set_mbr_partition_size(st, img_handle, disk, index, num_sectors, min_sectors)
+}
+
+/// changes the size of an MBR partition in the table, leaving its contents be
+fn set_mbr_partition_size(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut MBR,
+    index: usize,
+    num_sectors: u64,
+    min_sectors: Option<u64>
+) -> uefi::Result {
+    // never cut off the end of a filesystem
+    if let Some(min) = min_sectors {
+        if num_sectors < min {
+            warn!("Filesystem needs at least {} sectors, refusing to shrink to {}", min, num_sectors);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+    }
+
+    disk.resize_partition(index, num_sectors)
+        .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    disk.write(unsafe{&mut *bi.interface.get()})
+}
+
+/// returns the smallest number of sectors a mounted filesystem fits in
+fn min_fs_sectors<'a, F: Filesystem<'a>>(fs: &mut F, blocksize: u32) -> uefi::Result<u64> {
+    let blocksize = blocksize as u64;
+    Ok((fs.min_size()? + blocksize - 1) / blocksize)
+}
+
+/// grows or shrinks a GPT partition along with the filesystem inside it
+/// 
+/// When shrinking the filesystem goes first so nothing is cut off, when
+/// growing the partition goes first so the filesystem has room to grow into.
+/// With `cluster_bytes` set the filesystem's cluster size changes too, which
+/// only some drivers (e.g. FAT) can do
+pub fn resize_gpt_filesystem<F: for<'a> Filesystem<'a>>(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
+    num_sectors: u64,
+    move_neighbours: bool,
+    cluster_bytes: Option<u64>
+) -> uefi::Result {
+    let part = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let shrinking = num_sectors < part.extent().len();
+
+    // check the driver is up to it before touching anything
+    let min = {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        let mut fs = F::mount(PartitionDevice::new(bi.interface, part.extent()))?;
+        let caps = fs.capabilities();
+        if (shrinking && !caps.shrink) || (!shrinking && !caps.grow) {
+            warn!("{} driver can't resize the filesystem", fs.name());
+            return Err(Status::UNSUPPORTED.into());
+        }
+        if cluster_bytes.is_some() && !caps.recluster {
+            warn!("{} driver can't change the cluster size", fs.name());
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let min = min_fs_sectors(&mut fs, disk.blocksize())?;
+        if num_sectors < min {
+            warn!("Filesystem needs at least {} sectors, refusing to shrink to {}", min, num_sectors);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if shrinking {
+            fs.resize_with_cluster_size(num_sectors, cluster_bytes)?;
+        }
+        min
+    };
+
+    // the filesystem has already been shrunk if need be
+    set_gpt_partition_size(st, img_handle, disk, part_guid, num_sectors, Some(min), move_neighbours)?;
+    if shrinking {
+        return Ok(());
+    }
+
+    // now the filesystem can take over the extra space
+    let extent = Extent::with_len(part.first_lba(), num_sectors);
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let mut fs = F::mount(PartitionDevice::new(bi.interface, extent))?;
+    fs.resize_with_cluster_size(num_sectors, cluster_bytes)
+}
+
+/// grows or shrinks an MBR partition along with the filesystem inside it, see
+/// `resize_gpt_filesystem` for `cluster_bytes`
+/// note like `resize_mbr_partition` this only grows into free space
+pub fn resize_mbr_filesystem<F: for<'a> Filesystem<'a>>(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut MBR,
+    index: usize,
+    num_sectors: u64,
+    cluster_bytes: Option<u64>
+) -> uefi::Result {
+    let extent = match disk.partitions().get(index) {
+        Some(part) => part.extent(),
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let shrinking = num_sectors < extent.len();
+
+    // check the driver is up to it before touching anything
+    let min = {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        let blocksize = unsafe{&*bi.interface.get()}.media().block_size();
+        let mut fs = F::mount(PartitionDevice::new(bi.interface, extent))?;
+        let caps = fs.capabilities();
+        if (shrinking && !caps.shrink) || (!shrinking && !caps.grow) {
+            warn!("{} driver can't resize the filesystem", fs.name());
+            return Err(Status::UNSUPPORTED.into());
+        }
+        if cluster_bytes.is_some() && !caps.recluster {
+            warn!("{} driver can't change the cluster size", fs.name());
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let min = min_fs_sectors(&mut fs, blocksize)?;
+        if num_sectors < min {
+            warn!("Filesystem needs at least {} sectors, refusing to shrink to {}", min, num_sectors);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if shrinking {
+            fs.resize_with_cluster_size(num_sectors, cluster_bytes)?;
+        }
+        min
+    };
+
+    set_mbr_partition_size(st, img_handle, disk, index, num_sectors, Some(min))?;
+    if shrinking {
+        return Ok(());
+    }
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let mut fs = F::mount(PartitionDevice::new(bi.interface, Extent::with_len(extent.first_lba(), num_sectors)))?;
+    fs.resize_with_cluster_size(num_sectors, cluster_bytes)
+}
+
+
+/// merges two adjacent partitions of the same type into the first one
+/// 
+/// Only the partition table is touched, whatever filesystem lives in the first
+/// partition doesn't know about the extra space (see `merge_gpt_filesystems`)
+pub fn merge_gpt_partitions(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    first: Guid,
+    second: Guid
+) -> uefi::Result {
+    disk.merge_partitions(first, second)
+        .map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    disk.write(unsafe{&mut *bi.interface.get()})
+}
+
+/// merges two adjacent partitions, then grows the first one's filesystem over
+/// the space of the second
+/// 
+/// With `copy_files` set every file of the second filesystem is copied into
+/// the first one before it is overwritten
+pub fn merge_gpt_filesystems<F: for<'a> Filesystem<'a>>(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    first: Guid,
+    second: Guid,
+    copy_files: bool
+) -> uefi::Result {
+    if !disk.can_merge(first, second) {
+        warn!("Partitions must be next to eachother and of the same type to merge");
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+    let p1 = *disk.partition(first).unwrap();
+    let p2 = *disk.partition(second).unwrap();
+
+    // rescue the files first, they only have the first filesystem's
+    // current free space to go into
+    if copy_files {
+        let bi = match disk.open(st.boot_services(), img_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        let mut src = F::mount(PartitionDevice::new(bi.interface, p2.extent()))?;
+        let mut dst = F::mount(PartitionDevice::new(bi.interface, p1.extent()))?;
+        if !dst.capabilities().write {
+            warn!("Filesystem driver can't write files, refusing to merge");
+            return Err(Status::UNSUPPORTED.into());
+        }
+        if let Some(path) = find_collision(&mut src, &mut dst, "/")? {
+            warn!("{} is on both filesystems, refusing to merge", path);
+            return Err(Status::ACCESS_DENIED.into());
+        }
+        copy_tree(&mut src, &mut dst, "/")?;
+    }
+
+    merge_gpt_partitions(st, img_handle, disk, first, second)?;
+
+    // finally let the filesystem take over the extra space
+    let merged = Extent::new(p1.first_lba(), p2.last_lba());
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let mut fs = F::mount(PartitionDevice::new(bi.interface, merged))?;
+    if !fs.capabilities().grow {
+        warn!("Filesystem driver can't grow, the extra space is left unused");
+        return Ok(());
     }
+    fs.resize(merged.len())
+}
+
+
+/// splits a partition in two: its filesystem is shrunk to `num_sectors`, the
+/// entry is truncated, and a new partition is created in the freed tail
+/// 
+/// Returns the GUID of the new partition
+pub fn split_gpt_partition<F: for<'a> Filesystem<'a>>(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
+    num_sectors: u64,
+    new_type: Guid,
+    new_name: &str
+) -> uefi::Result<Guid> {
+    let part = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
 
-    
+    // the new partition starts on the next aligned boundary
+    let tail_start = align_up(
+        part.first_lba() + num_sectors,
+        alignment_sectors(disk.blocksize())
+    );
+    if num_sectors == 0 || tail_start > part.last_lba() {
+        warn!("Nothing would be left over to split off");
+        return Err(Status::INVALID_PARAMETER.into());
+    }
 
-    0
-}
\ No newline at end of file
+    if !disk.partitions().iter().any(|p| !p.is_used()) {
+        warn!("No free slots left in the partition entry array");
+        return Err(Status::VOLUME_FULL.into());
+    }
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+
+    // make sure we can name the new partition before changing anything
+    let new_guid = random_guid(st.boot_services())?;
+
+    // shrink the filesystem before we take any space away from it
+    let mut fs = F::mount(PartitionDevice::new(bi.interface, part.extent()))?;
+    if !fs.capabilities().shrink {
+        warn!("Filesystem driver can't shrink, refusing to split");
+        return Err(Status::UNSUPPORTED.into());
+    }
+    let blocksize = disk.blocksize() as u64;
+    let min = (fs.min_size()? + blocksize - 1) / blocksize;
+    if num_sectors < min {
+        warn!("Filesystem needs at least {} sectors, refusing to shrink to {}", min, num_sectors);
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+    fs.resize(num_sectors)?;
+
+    // now truncate the entry and put the new one in the tail
+    disk.resize_partition(part_guid, num_sectors)
+        .map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+    disk.create_partition(
+        GPTPartition::from_values(
+            new_type,
+            new_guid,
+            Extent::new(tail_start, part.last_lba()),
+            0,
+            new_name
+        )
+    ).map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+
+    disk.write(unsafe{&mut *bi.interface.get()})?;
+    Ok(new_guid)
+}
+
+
+/// moves the primary partition entry array and/or changes how many entries it
+/// holds, pushing partitions back if the array grows into them
+/// 
+/// note the backup array always sits right before the backup header
+pub fn resize_gpt_table(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    lba_part_entries: u64,
+    num_partitions: u32
+) -> uefi::Result {
+    let usable = match disk.usable_range_for(lba_part_entries, num_partitions) {
+        Some(usable) => usable,
+        None => {
+            warn!("Partition entry array doesn't fit on the disk");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+    };
+
+    // we can only push partitions towards the end of the disk, so anything in
+    // the way of the backup array is a dead end
+    if disk.partitions().iter().any(|p| p.is_used() && p.last_lba() > usable.last_lba()) {
+        warn!("Partitions are in the way of the backup partition entry array");
+        return Err(Status::VOLUME_FULL.into());
+    }
+
+    // push back whatever sits where the primary array wants to go
+    let parts: Vec<GPTPartition> = disk.partitions()
+                                       .iter()
+                                       .filter(|p| p.is_used())
+                                       .copied()
+                                       .collect();
+    let moves = plan_push_back(disk, parts, usable.first_lba())?;
+    if moves.iter().any(|m| m.to.last_lba() > usable.last_lba()) {
+        warn!("Not enough space to push the partitions out of the way");
+        return Err(Status::VOLUME_FULL.into());
+    }
+    for m in moves.iter() {
+        move_gpt_partition_safe(st, img_handle, disk, m.part_guid, m.to.first_lba())?;
+    }
+
+    disk.resize_entry_array(lba_part_entries, num_partitions)
+        .map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    disk.write(unsafe{&mut *bi.interface.get()})
+}
+
+
+/// moves the backup GPT to the end of the disk (after the disk grew) and wipes
+/// the old backup header so nothing mistakes it for the real one
+pub fn fix_gpt_backup(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk
+) -> uefi::Result {
+    if !disk.backup_misplaced() {
+        return Ok(());
+    }
+    let old_backup_lba = disk.header().backup_lba();
+
+    disk.relocate_backup()
+        .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+
+    let bi = match disk.open(st.boot_services(), img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let bi = unsafe{&mut *bi.interface.get()};
+    disk.write(bi)?;
+
+    // only wipe the old header if nothing else lives there now
+    if old_backup_lba < disk.last_block() {
+        let sector = vec![0u8; disk.blocksize() as usize];
+        bi.write_blocks(disk.media_id(), old_backup_lba, &sector)?;
+        bi.flush_blocks()?;
+    }
+
+    info!("Moved the backup GPT from LBA {} to LBA {}", old_backup_lba, disk.last_block());
+    Ok(())
+}
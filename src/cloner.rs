@@ -0,0 +1,456 @@
+//! Copies a whole disk onto another one, e.g. when a drive is being swapped
+//! for a bigger (or smaller) one in the field.
+//!
+//! Three modes are supported:
+//!  - sector for sector: everything up to the end of the source's usable area
+//!    is copied verbatim, then the GPT is rewritten so the backup sits at the
+//!    end of the target
+//!  - used space: only the boot sector and what the filesystems have in use
+//!    are copied, free space is skipped (note partitions whose filesystem
+//!    driver can't say what is allocated are copied whole)
+//!  - proportional: like used space, but every partition is moved and grown so
+//!    it takes up the same share of the target as it did of the source
+//!
+//! Disks without a GPT can only be cloned sector for sector.
+//!
+//! Single partitions can also be cloned, into free space on the same disk or
+//! on another one.
+
+use uefi::prelude::*;
+use uefi::Guid;
+use uefi::proto::media::block::BlockIO;
+use crate::alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::convert::TryInto;
+use core::ops::Range;
+
+use crate::partitions::{
+    Extent,
+    FreeSpaceMap,
+    GPTDisk,
+    GPTPartition,
+    MBR
+};
+use crate::partitions::extents::align_up;
+use crate::block_shifter::{
+    alignment_sectors,
+    copy_between_disks,
+    copy_sectors
+};
+use crate::helpers::{
+    crc32_update,
+    open_handle_block_io,
+    random_guid
+};
+use crate::memory::allocate_copy_buffer;
+use crate::fs::{
+    Filesystem,
+    PartitionDevice
+};
+use crate::fs::exfat::ExFatFs;
+use crate::fs::ext4::ExtFs;
+use crate::fs::fat32::FatFs;
+use crate::fs::ntfs::NtfsFs;
+use crate::fs::probe::{
+    probe,
+    FsKind
+};
+
+/// defines how the data of a disk is laid out on its clone
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum CloneMode {
+    SectorForSector,    // copy every sector, free space included
+    UsedSpace,          // copy only the boot sector and the data in use
+    Proportional        // like UsedSpace, but scale the partitions to the target
+}
+
+/// defines the options for a clone
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct CloneOptions {
+    pub mode:       CloneMode,
+    pub new_guids:  bool // give the clone new disk and partition GUIDs
+}
+
+/// defines a range of sectors to copy from the source to the target
+#[derive(Copy,Clone,Debug,PartialEq)]
+struct CopyRange {
+    src_lba:        u64,
+    dst_lba:        u64,
+    num_sectors:    u64
+}
+
+
+/// scales the partitions of `table` (which already points at the target) so
+/// they take up the same share of the target's usable area as they did of the
+/// source's, never making a partition smaller than it was
+fn scale_partitions(table: &mut GPTDisk, src_last_lba: u64) -> Result<(), ()> {
+    let first = table.header().first_lba() as u128;
+    let src_span = src_last_lba as u128 - first + 1;
+    let dst_span = table.header().last_lba() as u128 - first + 1;
+    let alignment = alignment_sectors(table.blocksize());
+    let mut free = FreeSpaceMap::new(table.header().first_lba(), table.header().last_lba());
+
+    // note the scaling has to be done in 128 bits, LBA * LBA overflows a u64
+    let scale = |lba: u64| -> u64 {
+        (first + (lba as u128 - first) * dst_span / src_span) as u64
+    };
+
+    for part in table.partitions().clone().iter().filter(|p| p.is_used()) {
+        let start = align_up(scale(part.first_lba()), alignment);
+        let end = scale(part.last_lba() + 1);
+        if end <= start || end - start < part.extent().len() {
+            info!("Partition {} would be smaller than its data after scaling", part.name());
+            return Err(());
+        }
+
+        let extent = Extent::new(start, end - 1);
+        if !free.is_free(&extent) {
+            info!("Partition {} doesn't fit on the target after scaling", part.name());
+            return Err(());
+        }
+        free.reserve(extent);
+        table.partition_mut(part.part_guid()).unwrap().set_extent(extent);
+    }
+    Ok(())
+}
+
+/// asks the filesystem on a partition which byte ranges it has in use
+fn fs_used_ranges(dev: PartitionDevice) -> uefi::Result<Vec<Range<u64>>> {
+    match probe(&dev).map(|info| info.kind) {
+        Some(FsKind::Fat12) | Some(FsKind::Fat16) | Some(FsKind::Fat32) => FatFs::mount(dev)?.used_ranges(),
+        Some(FsKind::ExFat) => ExFatFs::mount(dev)?.used_ranges(),
+        Some(FsKind::Ntfs) => NtfsFs::mount(dev)?.used_ranges(),
+        Some(FsKind::Ext2) | Some(FsKind::Ext3) | Some(FsKind::Ext4) => ExtFs::mount(dev)?.used_ranges(),
+        _ => Err(Status::UNSUPPORTED.into())
+    }
+}
+
+/// adds a range to copy, joining it onto the last one if it carries straight on
+fn add_copy(ranges: &mut Vec<CopyRange>, src_lba: u64, dst_lba: u64, num_sectors: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.src_lba + last.num_sectors == src_lba && last.dst_lba + last.num_sectors == dst_lba => {
+            last.num_sectors += num_sectors;
+        },
+        _ => ranges.push(CopyRange { src_lba, dst_lba, num_sectors })
+    }
+}
+
+/// works out which sectors need copying for a GPT clone, given the source
+/// table and the table that will be written to the target
+///
+/// Outside of sector for sector mode only what each filesystem on `src_bi`
+/// has in use is copied
+fn plan_gpt_copy(src_bi: &UnsafeCell<BlockIO>, src: &GPTDisk, dst: &GPTDisk, mode: CloneMode) -> Vec<CopyRange> {
+    let mut ranges: Vec<CopyRange> = Vec::new();
+
+    if mode == CloneMode::SectorForSector {
+        // the backup GPT is written separately, so stop at the last usable LBA
+        let last_lba = core::cmp::min(src.header().last_lba(), dst.header().last_lba());
+        ranges.push(CopyRange { src_lba: 0, dst_lba: 0, num_sectors: last_lba + 1 });
+        return ranges;
+    }
+
+    // keep the boot code, the protective partition gets fixed up afterwards
+    ranges.push(CopyRange { src_lba: 0, dst_lba: 0, num_sectors: 1 });
+
+    // only the original length of each partition holds any data, and of that
+    // only what the filesystem has in use if its driver can tell us
+    let blocksize = src.blocksize() as u64;
+    for part in src.partitions().iter().filter(|p| p.is_used()) {
+        let target = dst.partition(part.part_guid()).unwrap();
+        let len = part.extent().len();
+        let used = match fs_used_ranges(PartitionDevice::new(src_bi, part.extent())) {
+            Ok(used) => used,
+            Err(_) => {
+                info!("Copying all of partition {}, its filesystem can't say what is in use", part.name());
+                vec![0..len * blocksize]
+            }
+        };
+        for range in used {
+            let first = range.start / blocksize;
+            let end = core::cmp::min((range.end + blocksize - 1) / blocksize, len);
+            if first < end {
+                add_copy(&mut ranges, part.first_lba() + first, target.first_lba() + first, end - first);
+            }
+        }
+    }
+    ranges
+}
+
+/// points the protective MBR of the target at the whole disk again
+fn fix_target_pmbr(bi: &mut BlockIO) -> uefi::Result {
+    let media_id = bi.media().media_id();
+    let mut sector = vec![0u8; bi.media().block_size() as usize];
+    bi.read_blocks(media_id, 0, &mut sector)?;
+
+    let mut mbr = MBR::new(sector[..512].try_into().unwrap(), media_id, bi.media().last_block())
+        .map_err(|_| uefi::Error::from(Status::VOLUME_CORRUPTED))?;
+    if mbr.fix_protective().is_err() {
+        warn!("Target has no protective MBR partition, leaving its MBR alone");
+        return Ok(());
+    }
+    mbr.write(bi)
+}
+
+/// clones the disk behind `src_handle` onto the disk behind `dst_handle`
+///
+/// Everything on the target is overwritten. Returns the GPT written to the
+/// target, or None if the source isn't GPT partitioned
+pub fn clone_disk(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    src_handle: Handle,
+    dst_handle: Handle,
+    options: CloneOptions
+) -> uefi::Result<Option<GPTDisk>> {
+    let bs = st.boot_services();
+    let src_proto = match open_handle_block_io(bs, img_handle, src_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let src_bi = unsafe{&*src_proto.interface.get()};
+    // note both are opened exclusively, so this also stops a disk being cloned onto itself
+    let dst_bi = match open_handle_block_io(bs, img_handle, dst_handle) {
+        Some(bi) => bi,
+        None => {
+            warn!("Couldn't open the target disk, is it the source?");
+            return Err(Status::ACCESS_DENIED.into());
+        }
+    };
+    let dst_bi = unsafe{&mut *dst_bi.interface.get()};
+
+    // make sure the target is writable
+    if dst_bi.media().is_read_only() {
+        return Err(Status::WRITE_PROTECTED.into());
+    }
+    let blocksize = src_bi.media().block_size();
+    if dst_bi.media().block_size() != blocksize {
+        warn!("Disks have different block sizes, can't clone");
+        return Err(Status::UNSUPPORTED.into());
+    }
+
+    // work out what the target's table looks like and what has to be copied
+    let (table, ranges) = match GPTDisk::from_block_io(src_handle, src_bi) {
+        Ok(src) => {
+            let mut table = src.clone();
+            table.retarget(dst_handle, dst_bi)
+                 .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+            if options.mode == CloneMode::Proportional {
+                scale_partitions(&mut table, src.header().last_lba())
+                    .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+            }
+            // note the ranges are matched up by GUID, so plan before changing them
+            let ranges = plan_gpt_copy(src_proto.interface, &src, &table, options.mode);
+            if options.new_guids {
+                table.regenerate_guids(|| random_guid(bs))?;
+            }
+            (Some(table), ranges)
+        },
+        Err(_) => {
+            if options.mode != CloneMode::SectorForSector {
+                warn!("Source has no GPT, it can only be cloned sector for sector");
+                return Err(Status::UNSUPPORTED.into());
+            }
+            let last_block = src_bi.media().last_block();
+            if dst_bi.media().last_block() < last_block {
+                warn!("Target is smaller than the source");
+                return Err(Status::VOLUME_FULL.into());
+            }
+            (None, vec![CopyRange { src_lba: 0, dst_lba: 0, num_sectors: last_block + 1 }])
+        }
+    };
+
+    let total: u64 = ranges.iter().map(|r| r.num_sectors).sum();
+    let largest = ranges.iter().map(|r| r.num_sectors).max().unwrap_or(1);
+    let mut buf = allocate_copy_buffer(bs, blocksize, largest)?;
+    info!("Cloning {} sectors", total);
+
+    // used space clones can have a lot of small ranges, so only report every percent
+    let mut done = 0;
+    let mut reported = 0;
+    for range in ranges.iter() {
+        copy_between_disks(
+            src_bi,
+            dst_bi,
+            range.src_lba,
+            range.dst_lba,
+            range.num_sectors,
+            buf.as_mut_slice()
+        )?;
+        done += range.num_sectors;
+        if done * 100 / total != reported {
+            reported = done * 100 / total;
+            info!("Cloned {} of {} sectors", done, total);
+        }
+    }
+
+    // finally lay down the target's own table
+    match table {
+        Some(mut table) => {
+            table.write(dst_bi)?;
+            fix_target_pmbr(dst_bi)?;
+            if options.mode == CloneMode::Proportional {
+                warn!("Partitions were grown, their filesystems need growing to use the space");
+            }
+            Ok(Some(table))
+        },
+        None => {
+            dst_bi.flush_blocks()?;
+            Ok(None)
+        }
+    }
+}
+
+
+/// checksums `num_sectors` sectors starting at `lba`
+fn checksum_sectors(bi: &BlockIO, lba: u64, num_sectors: u64, buf: &mut [u8]) -> uefi::Result<u32> {
+    let blocksize = bi.media().block_size() as u64;
+    let chunk_len = core::cmp::min(buf.len() as u64 / blocksize, num_sectors);
+    if chunk_len == 0 {
+        return Err(Status::BUFFER_TOO_SMALL.into());
+    }
+
+    let mut crc = 0;
+    let mut offset = 0;
+    while offset < num_sectors {
+        let len = core::cmp::min(chunk_len, num_sectors - offset);
+        let data = &mut buf[..(len * blocksize) as usize];
+        bi.read_blocks(bi.media().media_id(), lba + offset, data)?;
+        crc = crc32_update(crc, data);
+        offset += len;
+    }
+    Ok(crc)
+}
+
+/// finds where a clone of `num_sectors` sectors goes on `table`, either at
+/// `dst_lba` or in the first aligned gap big enough to hold it
+fn place_clone(table: &GPTDisk, num_sectors: u64, dst_lba: Option<u64>) -> uefi::Result<Extent> {
+    if !table.partitions().iter().any(|p| !p.is_used()) {
+        warn!("No free slots left in the partition entry array");
+        return Err(Status::VOLUME_FULL.into());
+    }
+
+    let free = table.free_space();
+    match dst_lba {
+        Some(lba) => {
+            let extent = Extent::with_len(lba, num_sectors);
+            if !free.is_free(&extent) {
+                warn!("Selected range overlaps another partition!");
+                return Err(Status::INVALID_PARAMETER.into());
+            }
+            Ok(extent)
+        },
+        None => match free.first_fit(num_sectors, alignment_sectors(table.blocksize())) {
+            Some(extent) => Ok(extent),
+            None => {
+                warn!("No free gap big enough to hold the clone");
+                Err(Status::VOLUME_FULL.into())
+            }
+        }
+    }
+}
+
+/// clones a partition into free space, either on the same disk (`target` is
+/// None) or on another one
+///
+/// The new entry gets the same type, attributes and name. Its GUID is kept if
+/// `keep_guid` is set, note this is only allowed on another disk since GUIDs
+/// must be unique. Returns the GUID of the clone
+pub fn clone_partition(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk: &mut GPTDisk,
+    part_guid: Guid,
+    target: Option<&mut GPTDisk>,
+    dst_lba: Option<u64>,
+    keep_guid: bool
+) -> uefi::Result<Guid> {
+    let part = match disk.partition(part_guid) {
+        Some(part) => *part,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let num_sectors = part.extent().len();
+
+    let bs = st.boot_services();
+    let new_guid = match keep_guid {
+        true => part_guid,
+        false => random_guid(bs)?
+    };
+
+    let src_bi = match disk.open(bs, img_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    let src_bi = unsafe{&mut *src_bi.interface.get()};
+    let mut buf = allocate_copy_buffer(bs, disk.blocksize(), num_sectors)?;
+
+    // copy the data and work out what the clone should look like
+    let src_crc = checksum_sectors(src_bi, part.first_lba(), num_sectors, buf.as_mut_slice())?;
+    let (table, dst_bi, extent) = match target {
+        None => {
+            if keep_guid {
+                warn!("A clone on the same disk needs a new GUID");
+                return Err(Status::INVALID_PARAMETER.into());
+            }
+            let extent = place_clone(disk, num_sectors, dst_lba)?;
+            if src_bi.media().is_read_only() {
+                return Err(Status::WRITE_PROTECTED.into());
+            }
+            copy_sectors(
+                src_bi,
+                disk.media_id(),
+                part.first_lba(),
+                extent.first_lba(),
+                num_sectors,
+                buf.as_mut_slice()
+            )?;
+            (disk, src_bi, extent)
+        },
+        Some(table) => {
+            if table.partition(new_guid).is_some() {
+                warn!("Target disk already has a partition with that GUID");
+                return Err(Status::INVALID_PARAMETER.into());
+            }
+            if table.blocksize() != disk.blocksize() {
+                warn!("Disks have different block sizes, can't clone");
+                return Err(Status::UNSUPPORTED.into());
+            }
+            let extent = place_clone(table, num_sectors, dst_lba)?;
+            let dst_bi = match table.open(bs, img_handle) {
+                Some(bi) => bi,
+                None => return Err(Status::NOT_FOUND.into())
+            };
+            let dst_bi = unsafe{&mut *dst_bi.interface.get()};
+            if dst_bi.media().is_read_only() {
+                return Err(Status::WRITE_PROTECTED.into());
+            }
+            copy_between_disks(
+                src_bi,
+                dst_bi,
+                part.first_lba(),
+                extent.first_lba(),
+                num_sectors,
+                buf.as_mut_slice()
+            )?;
+            (table, dst_bi, extent)
+        }
+    };
+
+    // make sure the whole clone matches before it gets an entry
+    if checksum_sectors(dst_bi, extent.first_lba(), num_sectors, buf.as_mut_slice())? != src_crc {
+        error!("Clone doesn't match the source partition");
+        return Err(Status::CRC_ERROR.into());
+    }
+    table.create_partition(
+        GPTPartition::from_values(
+            part.part_type_guid(),
+            new_guid,
+            extent,
+            part.attr_flags(),
+            &part.name()
+        )
+    ).map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+
+    table.write(dst_bi)?;
+    Ok(new_guid)
+}
@@ -0,0 +1,129 @@
+// Includes a block device that only lets a filesystem driver see its own partition
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use core::cell::UnsafeCell;
+
+use crate::partitions::Extent;
+
+/// defines a window onto the sectors of a single partition
+///
+/// LBAs are relative to the start of the partition and every access is
+/// checked against its end. Devices only borrow the BlockIO protocol, so
+/// several of them can share one disk (e.g. when copying between partitions)
+#[derive(Copy,Clone)]
+pub struct PartitionDevice<'a> {
+    bi:         &'a UnsafeCell<BlockIO>,
+    media_id:   u32,
+    blocksize:  u32,
+    extent:     Extent
+}
+
+
+////////////////////////// PARTITIONDEVICE IMPL //////////////////////////////
+impl<'a> PartitionDevice<'a> {
+    /// creates a device covering `extent` of the disk behind `bi`
+    /// note `bi` is usually the `interface` of an opened `ScopedProtocol`
+    pub fn new(bi: &'a UnsafeCell<BlockIO>, extent: Extent) -> Self {
+        let media = unsafe{&*bi.get()}.media();
+        PartitionDevice {
+            bi,
+            media_id:   media.media_id(),
+            blocksize:  media.block_size(),
+            extent
+        }
+    }
+
+    /// returns the size of a sector in bytes
+    pub fn blocksize(&self) -> u32 {
+        self.blocksize
+    }
+
+    /// returns the number of sectors in the partition
+    pub fn num_sectors(&self) -> u64 {
+        self.extent.len()
+    }
+
+    /// returns the size of the partition in bytes
+    pub fn size(&self) -> u64 {
+        self.extent.len() * self.blocksize as u64
+    }
+
+    /// returns the LBAs of the disk the partition covers
+    pub fn extent(&self) -> Extent {
+        self.extent
+    }
+
+    /// checks to see if the disk can be written to
+    pub fn is_read_only(&self) -> bool {
+        unsafe{&*self.bi.get()}.media().is_read_only()
+    }
+
+    /// turns a partition relative LBA into a disk LBA, checking the access fits
+    fn disk_lba(&self, lba: u64, len: usize) -> uefi::Result<u64> {
+        let sectors = (len as u64 + self.blocksize as u64 - 1) / self.blocksize as u64;
+        match lba.checked_add(sectors) {
+            Some(end) if end <= self.num_sectors() => Ok(self.extent.first_lba() + lba),
+            _ => {
+                error!("Access to LBA {} runs past the end of the partition", lba);
+                Err(Status::INVALID_PARAMETER.into())
+            }
+        }
+    }
+
+    /// reads whole sectors starting at the partition relative `lba`
+    pub fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> uefi::Result {
+        let disk_lba = self.disk_lba(lba, buf.len())?;
+        unsafe{&*self.bi.get()}.read_blocks(self.media_id, disk_lba, buf)
+    }
+
+    /// writes whole sectors starting at the partition relative `lba`
+    pub fn write_blocks(&self, lba: u64, buf: &[u8]) -> uefi::Result {
+        let disk_lba = self.disk_lba(lba, buf.len())?;
+        unsafe{&mut *self.bi.get()}.write_blocks(self.media_id, disk_lba, buf)
+    }
+
+    /// makes sure everything written so far actually hit the disk
+    pub fn flush(&self) -> uefi::Result {
+        unsafe{&mut *self.bi.get()}.flush_blocks()
+    }
+
+    /// reads `buf.len()` bytes starting at byte `offset` of the partition
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> uefi::Result {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let blocksize = self.blocksize as u64;
+        let first = offset / blocksize;
+        let last = (offset + buf.len() as u64 - 1) / blocksize;
+
+        // note the sectors are read whole and only the part asked for is copied out
+        let mut sectors = vec![0u8; ((last - first + 1) * blocksize) as usize];
+        self.read_blocks(first, &mut sectors)?;
+        let start = (offset - first * blocksize) as usize;
+        buf.copy_from_slice(&sectors[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// writes `buf` starting at byte `offset` of the partition, keeping the
+    /// rest of any partially written sector as it was
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> uefi::Result {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let blocksize = self.blocksize as u64;
+        let first = offset / blocksize;
+        let last = (offset + buf.len() as u64 - 1) / blocksize;
+        let start = (offset - first * blocksize) as usize;
+
+        let mut sectors = vec![0u8; ((last - first + 1) * blocksize) as usize];
+        if start != 0 || buf.len() as u64 % blocksize != 0 {
+            // only the first and last sector can be partial
+            let len = blocksize as usize;
+            let tail = sectors.len() - len;
+            self.read_blocks(first, &mut sectors[..len])?;
+            self.read_blocks(last, &mut sectors[tail..])?;
+        }
+        sectors[start..start + buf.len()].copy_from_slice(buf);
+        self.write_blocks(first, &sectors)
+    }
+}
@@ -0,0 +1,686 @@
+// Includes a struct and APIs for handling exFAT partitions
+// note this is read only for now, it is mostly here so SD cards and big USB
+// sticks can be looked at and copied off
+
+// notes on how it works
+// https://learn.microsoft.com/en-us/windows/win32/fileio/exfat-specification
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::ops::Range;
+use uefi::{Guid, Status};
+
+use super::{
+    add_range,
+    DirEntry,
+    Filesystem,
+    PartitionDevice
+};
+use super::ntfs::BASIC_DATA_GUID;
+use super::probe::{
+    probe,
+    FsKind
+};
+use crate::partitions::mbr::MbrPartTypes;
+
+/// the boot region is 11 sectors, followed by a sector full of their checksum
+const BOOT_REGION_SECTORS: u64 = 11;
+
+/// the backup boot region comes right after the main one
+const BACKUP_BOOT_SECTOR: u64 = 12;
+
+/// defines the directory entry types we care about
+const ENTRY_END: u8 = 0x00;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xc0;
+const ENTRY_NAME: u8 = 0xc1;
+
+/// the characters of a name each file name entry holds
+const NAME_ENTRY_CHARS: usize = 15;
+
+/// defines the stream extension flags
+const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+/// the file attribute set on directories
+const ATTR_DIRECTORY: u16 = 0x10;
+
+/// defines the volume flags
+const VOLUME_ACTIVE_FAT: u16 = 0x0001;
+const VOLUME_DIRTY: u16 = 0x0002;
+const VOLUME_MEDIA_FAILURE: u16 = 0x0004;
+
+/// the FAT entry marking a bad cluster, anything above it ends a chain
+const FAT_BAD: u32 = 0xffff_fff7;
+
+/// the most a directory can hold according to the spec
+const MAX_DIR_BYTES: u64 = 256 * 1024 * 1024;
+
+/// the size of the chunks the allocation bitmap is read in when counting
+const BITMAP_CHUNK_BYTES: usize = 64 * 1024;
+
+/// defines a mounted exFAT filesystem
+pub struct ExFatFs<'a> {
+    dev:                PartitionDevice<'a>,
+    label:              Option<String>,
+    serial:             u32,
+    bytes_per_sector:   u64,
+    cluster_size:       u64,
+    total_sectors:      u64,
+    fat_offset:         u64, // in bytes, of the active FAT
+    heap_offset:        u64, // in bytes, of cluster 2
+    cluster_count:      u32,
+    version:            (u8, u8),
+    volume_flags:       u16,
+    root:               ExFatFile,
+    bitmap:             ExFatFile, // the allocation bitmap of the active FAT
+    upcase:             Vec<u16> // what each character upcases to, past the end is unchanged
+}
+
+/// defines an open file (or directory)
+pub struct ExFatFile {
+    first_cluster:  u32,
+    size:           u64,
+    valid:          u64, // anything past this reads as zeros
+    is_dir:         bool,
+    contiguous:     bool, // set if the FAT isn't used, the clusters simply follow each other
+    cursor:         Cell<(u64, u32)> // the last cluster index we read, and its cluster
+}
+
+/// defines a file or directory entry set, already checked and put together
+#[derive(Clone,Debug)]
+struct ExFatDirEntry {
+    name:           String,
+    upcased:        Vec<u16>,
+    attributes:     u16,
+    first_cluster:  u32,
+    size:           u64,
+    valid:          u64,
+    contiguous:     bool
+}
+
+
+/// helper function to read little endian integers out of a buffer
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// works out the checksum of the boot region, which leaves out the fields
+/// that change while the volume is in use
+fn boot_checksum(region: &[u8]) -> u32 {
+    region.iter()
+          .enumerate()
+          .filter(|(i, _)| *i != 106 && *i != 107 && *i != 112)
+          .fold(0u32, |sum, (_, b)| sum.rotate_right(1).wrapping_add(*b as u32))
+}
+
+/// works out the checksum of an entry set, which leaves out the checksum itself
+fn entry_set_checksum(set: &[u8]) -> u16 {
+    set.iter()
+       .enumerate()
+       .filter(|(i, _)| *i != 2 && *i != 3)
+       .fold(0u16, |sum, (_, b)| sum.rotate_right(1).wrapping_add(*b as u16))
+}
+
+/// works out the checksum of the upcase table as it is stored
+fn table_checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, b| sum.rotate_right(1).wrapping_add(*b as u32))
+}
+
+/// expands the upcase table, where 0xffff followed by a count means that many
+/// characters upcase to themselves
+fn expand_upcase(data: &[u8]) -> Vec<u16> {
+    let mut table: Vec<u16> = Vec::new();
+    let mut units = data.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+    while let Some(unit) = units.next() {
+        if unit == 0xffff {
+            if let Some(skip) = units.next() {
+                let from = table.len();
+                table.extend((from..from + skip as usize).map(|c| c as u16));
+                continue;
+            }
+        }
+        table.push(unit);
+        if table.len() > 0x10000 {
+            break;
+        }
+    }
+    table
+}
+
+
+////////////////////////// EXFATFS IMPL //////////////////////////////
+impl<'a> ExFatFs<'a> {
+    /// checks to see if an MBR partition type is one exFAT uses
+    /// note this is only a hint, the probe decides what is really there
+    pub fn matches_mbr_type(part_type: MbrPartTypes) -> bool {
+        // exFAT shares its type with NTFS
+        part_type == MbrPartTypes::NTFS
+    }
+
+    /// checks to see if a GPT partition type is one exFAT uses
+    /// note Basic Data partitions may just as well hold FAT or NTFS
+    pub fn matches_gpt_type(type_guid: Guid) -> bool {
+        type_guid == BASIC_DATA_GUID
+    }
+
+    /// returns the size of a cluster in bytes
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// returns the number of clusters in the cluster heap
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// returns the exFAT revision, e.g. (1, 0)
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    /// checks to see if the volume is marked dirty, i.e. it wasn't ejected
+    /// cleanly and wants to be checked
+    pub fn is_dirty(&self) -> bool {
+        self.volume_flags & VOLUME_DIRTY != 0
+    }
+
+    /// returns the byte offset of a cluster
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.heap_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// checks to see if a cluster number points into the cluster heap
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// returns the cluster after `cluster` in its chain, None at the end
+    fn next_cluster(&self, cluster: u32) -> uefi::Result<Option<u32>> {
+        let mut entry = [0u8; 4];
+        self.dev.read_at(self.fat_offset + cluster as u64 * 4, &mut entry)?;
+        let next = u32::from_le_bytes(entry);
+        if next > FAT_BAD {
+            return Ok(None);
+        }
+        if !self.is_data_cluster(next) {
+            error!("Cluster {} points at invalid cluster {}", cluster, next);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        Ok(Some(next))
+    }
+
+    /// returns the number of clusters in the chain starting at `cluster`
+    fn chain_len(&self, cluster: u32) -> uefi::Result<u64> {
+        let mut len = 0;
+        let mut current = Some(cluster);
+
+        // a looping chain can never be longer than the number of clusters
+        while let Some(cluster) = current {
+            if len > self.cluster_count as u64 {
+                error!("Cluster chain starting at {} loops", cluster);
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            len += 1;
+            current = self.next_cluster(cluster)?;
+        }
+        Ok(len)
+    }
+
+    /// reads from a file or directory, following the FAT unless it is contiguous
+    fn read_data(&self, file: &ExFatFile, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        if offset >= file.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, file.size - offset) as usize;
+        let cluster_bytes = self.cluster_size;
+
+        // anything past the valid data length was never written
+        let valid = match file.valid {
+            valid if valid > offset => cmp::min((valid - offset) as usize, len),
+            _ => 0
+        };
+        buf[valid..len].iter_mut().for_each(|b| *b = 0);
+
+        // carry on from the last cluster we read if we can, so reading a file
+        // front to back doesn't walk the chain from the start every time
+        let (mut index, mut cluster) = match file.cursor.get() {
+            (index, cluster) if index <= offset / cluster_bytes => (index, cluster),
+            _ => (0, file.first_cluster)
+        };
+
+        let mut done = 0;
+        while done < valid {
+            let pos = offset + done as u64;
+            if file.contiguous {
+                index = pos / cluster_bytes;
+                cluster = file.first_cluster + index as u32;
+            }
+            while index < pos / cluster_bytes {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None => {
+                        error!("File is shorter than its directory entry says");
+                        return Err(Status::VOLUME_CORRUPTED.into());
+                    }
+                };
+                index += 1;
+            }
+            if !self.is_data_cluster(cluster) {
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+
+            let within = pos % cluster_bytes;
+            let chunk = cmp::min((cluster_bytes - within) as usize, valid - done);
+            self.dev.read_at(self.cluster_offset(cluster) + within, &mut buf[done..done + chunk])?;
+            done += chunk;
+        }
+
+        file.cursor.set((index, cluster));
+        Ok(len)
+    }
+
+    /// reads the raw entries of a directory
+    fn read_dir_raw(&self, dir: &ExFatFile) -> uefi::Result<Vec<u8>> {
+        if dir.size > MAX_DIR_BYTES {
+            error!("exFAT directory claims to be {} bytes, more than the 256 MiB allowed", dir.size);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let mut data = vec![0u8; dir.size as usize];
+        let len = self.read_data(dir, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+
+    /// returns what a character upcases to
+    fn upcase_char(&self, c: u16) -> u16 {
+        match self.upcase.get(c as usize) {
+            Some(upper) => *upper,
+            None => c
+        }
+    }
+
+    /// upcases a name the way the volume does
+    fn upcase_name(&self, name: &[u16]) -> Vec<u16> {
+        name.iter().map(|c| self.upcase_char(*c)).collect()
+    }
+
+    /// works out the hash of an upcased name
+    fn name_hash(upcased: &[u16]) -> u16 {
+        upcased.iter()
+               .flat_map(|c| c.to_le_bytes())
+               .fold(0u16, |hash, b| hash.rotate_right(1).wrapping_add(b as u16))
+    }
+
+    /// parses the file entry sets of a directory, skipping (and complaining
+    /// about) any that fail their checksums
+    fn parse_dir(&self, data: &[u8]) -> Vec<ExFatDirEntry> {
+        let mut entries = Vec::new();
+        let mut at = 0;
+        while at + 32 <= data.len() {
+            let entry = &data[at..at + 32];
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_FILE => (),
+                _ => {
+                    at += 32;
+                    continue;
+                }
+            }
+
+            // a set is the file entry, a stream extension and the name entries
+            let secondary = entry[1] as usize;
+            let set_len = (secondary + 1) * 32;
+            if secondary < 2 || at + set_len > data.len() {
+                warn!("Skipping truncated exFAT entry set at {}", at);
+                at += 32;
+                continue;
+            }
+            let set = &data[at..at + set_len];
+            at += set_len;
+            if entry_set_checksum(set) != le16(set, 2) {
+                warn!("Skipping exFAT entry set with a bad checksum");
+                continue;
+            }
+            let stream = &set[32..64];
+            if stream[0] != ENTRY_STREAM {
+                warn!("Skipping exFAT entry set without a stream extension");
+                continue;
+            }
+
+            let name_len = stream[3] as usize;
+            let name_entries = (name_len + NAME_ENTRY_CHARS - 1) / NAME_ENTRY_CHARS;
+            let mut name: Vec<u16> = set[64..].chunks_exact(32)
+                                              .take(name_entries)
+                                              .take_while(|e| e[0] == ENTRY_NAME)
+                                              .flat_map(|e| e[2..32].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])))
+                                              .collect();
+            if name_len == 0 || name.len() < name_len {
+                warn!("Skipping exFAT entry set with a short name");
+                continue;
+            }
+            name.truncate(name_len);
+
+            // the hash lets lookups skip names quickly, so a wrong one would
+            // make the file impossible to find on other systems
+            let upcased = self.upcase_name(&name);
+            if Self::name_hash(&upcased) != le16(stream, 4) {
+                warn!("Skipping exFAT entry set with a bad name hash");
+                continue;
+            }
+
+            entries.push(ExFatDirEntry {
+                name:           char::decode_utf16(name.iter().cloned())
+                                     .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                                     .collect(),
+                upcased,
+                attributes:     le16(set, 4),
+                first_cluster:  le32(stream, 20),
+                size:           le64(stream, 24),
+                valid:          le64(stream, 8),
+                contiguous:     stream[1] & STREAM_NO_FAT_CHAIN != 0
+            });
+        }
+        entries
+    }
+
+    /// finds the file or directory at `path`
+    /// note names are matched ignoring case, using the volume's upcase table
+    fn lookup(&self, path: &str) -> uefi::Result<ExFatFile> {
+        let root = &self.root;
+        let mut current = ExFatFile::new(root.first_cluster, root.size, root.valid, true, false);
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if !current.is_dir {
+                return Err(Status::NOT_FOUND.into());
+            }
+            let wanted = self.upcase_name(&part.encode_utf16().collect::<Vec<u16>>());
+            let entry = match self.parse_dir(&self.read_dir_raw(&current)?).into_iter().find(|e| e.upcased == wanted) {
+                Some(entry) => entry,
+                None => return Err(Status::NOT_FOUND.into())
+            };
+            current = ExFatFile::from(&entry);
+        }
+        Ok(current)
+    }
+
+    /// reads the boot region starting at `sector`, returning its first sector
+    /// if the checksum matches
+    fn read_boot_region(dev: &PartitionDevice, sector: u64, bytes_per_sector: u64) -> uefi::Result<Option<Vec<u8>>> {
+        let mut region = vec![0u8; ((BOOT_REGION_SECTORS + 1) * bytes_per_sector) as usize];
+        dev.read_at(sector * bytes_per_sector, &mut region)?;
+        let (boot, sums) = region.split_at((BOOT_REGION_SECTORS * bytes_per_sector) as usize);
+        let sum = boot_checksum(boot);
+        if sums.chunks_exact(4).any(|s| u32::from_le_bytes(s.try_into().unwrap()) != sum) {
+            return Ok(None);
+        }
+        Ok(Some(boot[..bytes_per_sector as usize].to_vec()))
+    }
+}
+
+
+////////////////////////// EXFATFILE IMPL //////////////////////////////
+impl ExFatFile {
+    fn new(first_cluster: u32, size: u64, valid: u64, is_dir: bool, contiguous: bool) -> Self {
+        ExFatFile {
+            first_cluster,
+            size,
+            valid:      cmp::min(valid, size),
+            is_dir,
+            contiguous,
+            cursor:     Cell::new((0, first_cluster))
+        }
+    }
+
+    /// opens the file an entry set describes
+    fn from(entry: &ExFatDirEntry) -> Self {
+        Self::new(
+            entry.first_cluster,
+            entry.size,
+            entry.valid,
+            entry.attributes & ATTR_DIRECTORY != 0,
+            entry.contiguous
+        )
+    }
+}
+
+
+////////////////////////// FILESYSTEM IMPL //////////////////////////////
+impl<'a> Filesystem<'a> for ExFatFs<'a> {
+    type File = ExFatFile;
+
+    fn probe(dev: &PartitionDevice) -> bool {
+        matches!(probe(dev).map(|info| info.kind), Some(FsKind::ExFat))
+    }
+
+    fn mount(dev: PartitionDevice<'a>) -> uefi::Result<Self> {
+        if !Self::probe(&dev) {
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let mut bs = [0u8; 512];
+        dev.read_at(0, &mut bs)?;
+        let sector_shift = bs[108] as u64;
+        if sector_shift < 9 || sector_shift > 12 {
+            error!("exFAT boot sector is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let bytes_per_sector = 1u64 << sector_shift;
+
+        // fall back on the backup boot region if the main one fails its checksum
+        let bs = match Self::read_boot_region(&dev, 0, bytes_per_sector)? {
+            Some(bs) => bs,
+            None => match Self::read_boot_region(&dev, BACKUP_BOOT_SECTOR, bytes_per_sector)? {
+                Some(bs) => {
+                    warn!("exFAT boot region checksum is wrong, using the backup");
+                    bs
+                },
+                None => {
+                    error!("exFAT boot region and its backup both fail their checksums");
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+            }
+        };
+
+        // parse the boot sector
+        let cluster_shift = bs[109] as u64;
+        let total_sectors = le64(&bs, 72);
+        let fat_offset = le32(&bs, 80) as u64;
+        let fat_sectors = le32(&bs, 84) as u64;
+        let heap_offset = le32(&bs, 88) as u64;
+        let cluster_count = le32(&bs, 92);
+        let root_cluster = le32(&bs, 96);
+        let num_fats = bs[110] as u64;
+        let volume_flags = le16(&bs, 106);
+        if bs[108] as u64 != sector_shift || cluster_shift > 25 - sector_shift
+            || num_fats == 0 || num_fats > 2 || bs[105] != 1
+            || heap_offset + ((cluster_count as u64) << cluster_shift) > total_sectors
+            || fat_sectors * bytes_per_sector < (cluster_count as u64 + 2) * 4 {
+            error!("exFAT boot sector is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        if total_sectors * bytes_per_sector > dev.size() {
+            error!("exFAT filesystem is bigger than its partition");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        if volume_flags & VOLUME_DIRTY != 0 {
+            warn!("exFAT volume is marked dirty, it wasn't ejected cleanly");
+        }
+        if volume_flags & VOLUME_MEDIA_FAILURE != 0 {
+            warn!("exFAT volume has reported media failures");
+        }
+
+        // with two FATs (TexFAT) the flags say which one is in use
+        let active_fat = match num_fats == 2 && volume_flags & VOLUME_ACTIVE_FAT != 0 {
+            true => 1,
+            false => 0
+        };
+        let mut fs = ExFatFs {
+            dev,
+            label:              None,
+            serial:             le32(&bs, 100),
+            bytes_per_sector,
+            cluster_size:       bytes_per_sector << cluster_shift,
+            total_sectors,
+            fat_offset:         (fat_offset + active_fat * fat_sectors) * bytes_per_sector,
+            heap_offset:        heap_offset * bytes_per_sector,
+            cluster_count,
+            version:            (bs[105], bs[104]),
+            volume_flags,
+            root:               ExFatFile::new(0, 0, 0, true, false),
+            bitmap:             ExFatFile::new(0, 0, 0, false, true),
+            upcase:             Vec::new()
+        };
+
+        // the root directory is always a FAT chain, with no size recorded
+        if !fs.is_data_cluster(root_cluster) {
+            error!("exFAT root directory is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let root_size = fs.chain_len(root_cluster)? * fs.cluster_size;
+        fs.root = ExFatFile::new(root_cluster, root_size, root_size, true, false);
+
+        // the root holds the label, the allocation bitmap and the upcase table
+        let root = fs.read_dir_raw(&fs.root)?;
+        let mut upcase = None;
+        for entry in root.chunks_exact(32) {
+            // note the metadata files always have a FAT chain, even when contiguous
+            let file = ExFatFile::new(le32(entry, 20), le64(entry, 24), le64(entry, 24), false, false);
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_LABEL => {
+                    let len = cmp::min(entry[1] as usize, 11);
+                    let units: Vec<u16> = entry[2..2 + len * 2].chunks_exact(2)
+                                                               .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                                                               .collect();
+                    fs.label = Some(String::from_utf16_lossy(&units));
+                },
+                // there is a bitmap per FAT, the flags say which one it goes with
+                ENTRY_BITMAP if (entry[1] & 1) as u64 == active_fat => fs.bitmap = file,
+                ENTRY_UPCASE => upcase = Some((le32(entry, 4), file)),
+                _ => ()
+            }
+        }
+
+        if !fs.is_data_cluster(fs.bitmap.first_cluster) || fs.bitmap.size * 8 < cluster_count as u64 {
+            error!("exFAT allocation bitmap is missing or too small");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let (checksum, file) = match upcase {
+            Some(upcase) if fs.is_data_cluster(upcase.1.first_cluster) && upcase.1.size <= 128 * 1024 => upcase,
+            _ => {
+                error!("exFAT upcase table is missing");
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+        };
+        let table = fs.read_dir_raw(&file)?;
+        if table_checksum(&table) != checksum {
+            error!("exFAT upcase table fails its checksum");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        fs.upcase = expand_upcase(&table);
+        Ok(fs)
+    }
+
+    fn name(&self) -> &'static str {
+        "exFAT"
+    }
+
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    fn uuid(&self) -> Option<String> {
+        Some(format!("{:04X}-{:04X}", self.serial >> 16, self.serial & 0xffff))
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector
+    }
+
+    fn used_bytes(&mut self) -> uefi::Result<u64> {
+        // count the clusters marked in use in the allocation bitmap
+        let total = self.cluster_count as u64;
+        let mut buf = vec![0u8; BITMAP_CHUNK_BYTES];
+        let mut used = 0u64;
+        let mut offset = 0;
+        while offset * 8 < total {
+            let len = self.read_data(&self.bitmap, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            for (i, b) in buf[..len].iter().enumerate() {
+                // the last byte may cover clusters past the end
+                let first = (offset + i as u64) * 8;
+                let bits = cmp::min(8, total.saturating_sub(first)) as u32;
+                used += (*b as u32 & ((1u32 << bits) - 1)).count_ones() as u64;
+            }
+            offset += len as u64;
+        }
+        Ok(used * self.cluster_size)
+    }
+
+    fn used_ranges(&mut self) -> uefi::Result<Vec<Range<u64>>> {
+        // the boot regions and the FAT all come before the cluster heap
+        let mut ranges = vec![0..self.heap_offset];
+        let total = self.cluster_count as u64;
+        let mut buf = vec![0u8; BITMAP_CHUNK_BYTES];
+        let mut offset = 0;
+        while offset * 8 < total {
+            let len = self.read_data(&self.bitmap, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            let first = offset * 8;
+            for index in first..cmp::min(total, first + len as u64 * 8) {
+                if buf[((index - first) / 8) as usize] & (1 << (index % 8)) != 0 {
+                    let start = self.cluster_offset(index as u32 + 2);
+                    add_range(&mut ranges, start..start + self.cluster_size);
+                }
+            }
+            offset += len as u64;
+        }
+        Ok(ranges)
+    }
+
+    fn read_dir(&mut self, path: &str) -> uefi::Result<Vec<DirEntry>> {
+        let dir = self.lookup(path)?;
+        if !dir.is_dir {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let entries = self.parse_dir(&self.read_dir_raw(&dir)?);
+        Ok(entries.into_iter()
+                  .map(|e| DirEntry {
+                      is_dir: e.attributes & ATTR_DIRECTORY != 0,
+                      size:   if e.attributes & ATTR_DIRECTORY != 0 { 0 } else { e.size },
+                      name:   e.name
+                  })
+                  .collect())
+    }
+
+    fn open(&mut self, path: &str) -> uefi::Result<ExFatFile> {
+        self.lookup(path)
+    }
+
+    fn read_at(&mut self, file: &ExFatFile, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        if file.is_dir {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        self.read_data(file, offset, buf)
+    }
+
+    fn min_size(&mut self) -> uefi::Result<u64> {
+        // note shrinking isn't supported yet, so this is only a lower bound
+        Ok(self.heap_offset + self.used_bytes()?)
+    }
+}
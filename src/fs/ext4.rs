@@ -1 +1,2434 @@
-// Includes a struct and APIs for handling EXT4 partitions
+// Includes a struct and APIs for handling EXT4 partitions (and ext2/3 too)
+
+// notes on how it works
+// https://www.kernel.org/doc/html/latest/filesystems/ext4/index.html
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp;
+use core::convert::TryInto;
+use core::ops::Range;
+use uefi::Status;
+
+use super::{
+    add_range,
+    DirEntry,
+    Filesystem,
+    FsCapabilities,
+    PartitionDevice
+};
+use crate::helpers::{
+    crc16_update,
+    crc32c_update
+};
+use super::probe::{
+    probe,
+    FsKind
+};
+
+/// the inode of the root directory, and of the inode holding the reserved GDT blocks
+const ROOT_INO: u32 = 2;
+const RESIZE_INO: u32 = 7;
+
+/// how many symlinks we follow while looking up a path before giving up
+const MAX_SYMLINKS: u32 = 40;
+
+/// defines the compat features we care about
+const COMPAT_HAS_JOURNAL: u32 = 0x0004;
+const COMPAT_EXCLUDE_BITMAP: u32 = 0x0100;
+const COMPAT_RESIZE_INODE: u32 = 0x0010;
+const COMPAT_SPARSE_SUPER2: u32 = 0x0200;
+
+/// defines the incompat features we care about
+const INCOMPAT_COMPRESSION: u32 = 0x0001;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+const INCOMPAT_META_BG: u32 = 0x0010;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_MMP: u32 = 0x0100;
+const INCOMPAT_EA_INODE: u32 = 0x0400;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+
+/// defines the ro_compat features we care about
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+const RO_COMPAT_SNAPSHOT: u32 = 0x0080;
+const RO_COMPAT_BIGALLOC: u32 = 0x0200;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+const RO_COMPAT_REPLICA: u32 = 0x0800;
+const RO_COMPAT_ORPHAN_PRESENT: u32 = 0x10000;
+
+/// defines the filesystem state bits
+const STATE_VALID: u16 = 0x0001;
+const STATE_ERROR: u16 = 0x0002;
+
+/// defines the group descriptor flags
+const BG_INODE_UNINIT: u16 = 0x0001;
+const BG_BLOCK_UNINIT: u16 = 0x0002;
+const BG_INODE_ZEROED: u16 = 0x0004;
+
+/// defines the inode flags we care about
+const INODE_INDEX: u32 = 0x0000_1000;
+const INODE_EXTENTS: u32 = 0x0008_0000;
+const INODE_INLINE_DATA: u32 = 0x1000_0000;
+
+/// defines the file types in the top bits of the inode mode
+const MODE_TYPE_MASK: u16 = 0xf000;
+const MODE_FIFO: u16 = 0x1000;
+const MODE_CHAR: u16 = 0x2000;
+const MODE_DIR: u16 = 0x4000;
+const MODE_BLOCK: u16 = 0x6000;
+const MODE_SYMLINK: u16 = 0xa000;
+const MODE_SOCKET: u16 = 0xc000;
+
+/// the size of the chunks file data is copied in when it is moved
+const COPY_CHUNK_BLOCKS: u64 = 256;
+
+/// the magic numbers of an extent tree node and of the in-inode extended attributes
+const EXTENT_MAGIC: u16 = 0xf30a;
+const XATTR_MAGIC: u32 = 0xea02_0000;
+
+/// the magic number of the journal's superblock (stored big endian unlike everything else)
+const JOURNAL_MAGIC: u32 = 0xc03b_3998;
+
+/// defines a mounted ext2/3/4 filesystem
+pub struct ExtFs<'a> {
+    dev:                PartitionDevice<'a>,
+    kind:               FsKind,
+    label:              Option<String>,
+    uuid:               Option<String>,
+    block_size:         u64,
+    blocks_count:       u64,
+    free_blocks:        u64,
+    first_data_block:   u64,
+    blocks_per_group:   u64,
+    inodes_per_group:   u64,
+    inode_size:         u64,
+    desc_size:          u64,
+    first_meta_bg:      u64,
+    compat:             u32,
+    incompat:           u32,
+    ro_compat:          u32,
+    fs_uuid:            [u8; 16],
+    csum_seed:          u32 // what every metadata_csum checksum starts from
+}
+
+/// defines an open file (or directory, or the symlink itself)
+pub struct ExtFile {
+    inode: Inode
+}
+
+/// defines an inode, kept as the raw bytes read off the disk
+#[derive(Clone)]
+struct Inode {
+    raw: Vec<u8>
+}
+
+/// defines a block group descriptor, kept as the raw bytes so any fields we
+/// don't know about survive being written back
+#[derive(Clone)]
+struct GroupDesc {
+    raw: Vec<u8>
+}
+
+/// defines the state of a resize in progress
+///
+/// Bitmaps are loaded as they are needed and only written back at the very
+/// end, along with the group descriptors and the superblock
+struct Resize<'r, 'a> {
+    fs:             &'r ExtFs<'a>,
+    sb:             Vec<u8>,
+    descs:          Vec<GroupDesc>,
+    block_bitmaps:  BTreeMap<u64, Vec<u8>>, // by group
+    inode_bitmaps:  BTreeMap<u64, Vec<u8>>, // by group
+    gdt_blocks:     u64, // not counting meta_bg descriptor blocks
+    reserved_gdt:   u64,
+    limit:          u64, // nothing at or past this block can be allocated
+    kept_groups:    u64, // inodes are only allocated in these groups
+    next_alloc:     u64, // where to start looking for free blocks
+    moves:          BTreeMap<u64, (u64, u64)>, // old start to new start and length
+    planning:       bool // only pick where blocks go, don't write anything
+}
+
+/// defines what a check of the filesystem found
+#[derive(Clone,Debug,Default)]
+pub struct ExtCheck {
+    pub name:           String, // the label, or the UUID without one
+    pub inodes_count:   u64,
+    pub inodes_used:    u64,
+    pub blocks_count:   u64,
+    pub blocks_used:    u64,
+    pub checksums:      bool, // false if there were no checksums to check
+    pub needs_recovery: bool, // the journal is flagged as needing a replay
+    pub journal_dirty:  bool, // the journal still holds transactions
+    pub not_clean:      bool, // not cleanly unmounted, or errors were recorded
+    pub error_count:    u32,
+    pub orphans:        Vec<u32>,
+    pub orphan_file:    bool, // orphans may be recorded in the orphan file
+    pub bad_superblock: bool,
+    pub bad_descs:      Vec<u64>, // by group
+    pub bad_bitmaps:    Vec<u64>, // by group
+    pub bad_inodes:     Vec<u32>,
+    pub bad_extents:    Vec<(u32, u64)> // the inode and the tree block
+}
+
+/// defines a raw directory entry
+#[derive(Clone,Debug)]
+struct ExtDirEntry {
+    name:       String,
+    ino:        u32,
+    file_type:  u8 // 0 if the filesystem doesn't record it
+}
+
+
+/// helper function to read little endian integers out of a buffer
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// helper functions to write little endian integers into a buffer
+fn set16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// helper functions for bitmaps, bit 0 being the lowest bit of the first byte
+fn bit_is_set(bitmap: &[u8], bit: u64) -> bool {
+    bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+}
+
+fn set_bit(bitmap: &mut [u8], bit: u64, value: bool) {
+    match value {
+        true => bitmap[(bit / 8) as usize] |= 1 << (bit % 8),
+        false => bitmap[(bit / 8) as usize] &= !(1 << (bit % 8))
+    }
+}
+
+/// renumbers the inodes the entries of a directory block point at
+/// returns true if anything changed
+fn remap_dir_block(data: &mut [u8], map: &BTreeMap<u32, u32>) -> bool {
+    let mut changed = false;
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let rec_len = le16(data, offset + 4) as usize;
+        if rec_len < 8 || offset + rec_len > data.len() {
+            break;
+        }
+        if let Some(new) = map.get(&le32(data, offset)) {
+            set32(data, offset, *new);
+            changed = true;
+        }
+        offset += rec_len;
+    }
+    changed
+}
+
+/// parses a block (or inline chunk) of linear directory entries
+///
+/// note htree directories need no special handling here, their index blocks
+/// look like a single empty entry so a linear scan steps right over them
+fn parse_dir_block(data: &[u8], entries: &mut Vec<ExtDirEntry>) -> uefi::Result {
+    let mut offset = 0;
+    while offset + 8 <= data.len() {
+        let ino = le32(data, offset);
+        let rec_len = le16(data, offset + 4) as usize;
+        let name_len = data[offset + 6] as usize;
+        if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+            error!("Directory entry at offset {} is damaged", offset);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        // an inode of 0 is an unused entry (or a checksum tail)
+        if ino != 0 && name_len != 0 {
+            let name = &data[offset + 8..offset + 8 + name_len];
+            entries.push(ExtDirEntry {
+                name:       String::from(String::from_utf8_lossy(name)),
+                ino,
+                file_type:  data[offset + 7]
+            });
+        }
+        offset += rec_len;
+    }
+    Ok(())
+}
+
+/// splits a path into its components, last one on top
+fn path_stack(path: &str) -> Vec<String> {
+    path.split('/').filter(|p| !p.is_empty()).rev().map(String::from).collect()
+}
+
+
+////////////////////////// INODE IMPL //////////////////////////////
+impl Inode {
+    /// returns the type and permission bits
+    fn mode(&self) -> u16 {
+        le16(&self.raw, 0)
+    }
+
+    /// returns the size of the file in bytes
+    fn size(&self) -> u64 {
+        (le32(&self.raw, 108) as u64) << 32 | le32(&self.raw, 4) as u64
+    }
+
+    /// returns the inode flags
+    fn flags(&self) -> u32 {
+        le32(&self.raw, 32)
+    }
+
+    /// returns the 60 bytes that hold the block map, extent tree, or inline data
+    fn i_block(&self) -> &[u8] {
+        &self.raw[40..100]
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode() & MODE_TYPE_MASK == MODE_DIR
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode() & MODE_TYPE_MASK == MODE_SYMLINK
+    }
+
+    fn has_inline_data(&self) -> bool {
+        self.flags() & INODE_INLINE_DATA != 0
+    }
+
+    /// checks to see if this is a symlink short enough to live in `i_block`
+    fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.size() < 60 && self.flags() & (INODE_EXTENTS | INODE_INLINE_DATA) == 0
+    }
+
+    /// returns the number of 512 byte sectors (or blocks, with huge_file) in use
+    fn blocks(&self) -> u64 {
+        (le16(&self.raw, 116) as u64) << 32 | le32(&self.raw, 28) as u64
+    }
+
+    fn set_blocks(&mut self, blocks: u64) {
+        set32(&mut self.raw, 28, blocks as u32);
+        set16(&mut self.raw, 116, (blocks >> 32) as u16);
+    }
+
+    /// returns the block holding the extended attributes that didn't fit the inode
+    fn file_acl(&self) -> u64 {
+        (le16(&self.raw, 118) as u64) << 32 | le32(&self.raw, 104) as u64
+    }
+
+    fn set_file_acl(&mut self, block: u64) {
+        set32(&mut self.raw, 104, block as u32);
+        set16(&mut self.raw, 118, (block >> 32) as u16);
+    }
+
+    /// checks to see if the inode has blocks of its own, i.e. isn't a device,
+    /// a fast symlink, or something with inline data
+    fn has_blocks(&self) -> bool {
+        let kind = self.mode() & MODE_TYPE_MASK;
+        ![MODE_FIFO, MODE_CHAR, MODE_BLOCK, MODE_SOCKET].contains(&kind)
+            && !self.is_fast_symlink()
+            && !self.has_inline_data()
+    }
+
+    /// returns the value of an extended attribute kept in the inode itself
+    fn inline_xattr(&self, index: u8, name: &[u8]) -> Option<&[u8]> {
+        self.inline_xattr_range(index, name).map(|range| &self.raw[range])
+    }
+
+    /// returns where in the inode the value of an extended attribute is
+    fn inline_xattr_range(&self, index: u8, name: &[u8]) -> Option<core::ops::Range<usize>> {
+        // the attributes live after the extra inode fields
+        if self.raw.len() <= 128 {
+            return None;
+        }
+        let start = 128 + le16(&self.raw, 128) as usize;
+        if start + 4 > self.raw.len() || le32(&self.raw, start) != XATTR_MAGIC {
+            return None;
+        }
+        let first = start + 4;
+        let mut offset = first;
+        while offset + 16 <= self.raw.len() && le32(&self.raw, offset) != 0 {
+            let name_len = self.raw[offset] as usize;
+            let value_offs = le16(&self.raw, offset + 2) as usize;
+            let value_size = le32(&self.raw, offset + 8) as usize;
+            if offset + 16 + name_len > self.raw.len() {
+                return None;
+            }
+            if self.raw[offset + 1] == index && &self.raw[offset + 16..offset + 16 + name_len] == name {
+                // note values are relative to the first entry
+                let range = first + value_offs..first + value_offs + value_size;
+                return match range.end <= self.raw.len() {
+                    true => Some(range),
+                    false => None
+                };
+            }
+            offset += (16 + name_len + 3) & !3;
+        }
+        None
+    }
+
+    /// returns the whole contents of an inline data inode
+    fn inline_data(&self) -> Vec<u8> {
+        let mut data = self.i_block().to_vec();
+        if let Some(more) = self.inline_xattr(7, b"data") {
+            data.extend_from_slice(more);
+        }
+        data.truncate(self.size() as usize);
+        data
+    }
+}
+
+
+////////////////////////// GROUPDESC IMPL //////////////////////////////
+impl GroupDesc {
+    /// reads a field split into a low half and, with 64 bit descriptors, a high half
+    fn get(&self, lo: usize, hi: usize, width: usize) -> u64 {
+        let read = |offset| match width {
+            2 => le16(&self.raw, offset) as u64,
+            _ => le32(&self.raw, offset) as u64
+        };
+        match self.raw.len() >= 64 {
+            true => read(lo) | read(hi) << (width * 8),
+            false => read(lo)
+        }
+    }
+
+    /// writes a field split into a low half and, with 64 bit descriptors, a high half
+    fn set(&mut self, lo: usize, hi: usize, width: usize, value: u64) {
+        let is_64bit = self.raw.len() >= 64;
+        let mut write = |offset, value: u64| match width {
+            2 => set16(&mut self.raw, offset, value as u16),
+            _ => set32(&mut self.raw, offset, value as u32)
+        };
+        write(lo, value);
+        if is_64bit {
+            write(hi, value >> (width * 8));
+        }
+    }
+
+    fn block_bitmap(&self) -> u64 {
+        self.get(0, 32, 4)
+    }
+
+    fn inode_bitmap(&self) -> u64 {
+        self.get(4, 36, 4)
+    }
+
+    fn inode_table(&self) -> u64 {
+        self.get(8, 40, 4)
+    }
+
+    fn free_blocks(&self) -> u64 {
+        self.get(12, 44, 2)
+    }
+
+    fn free_inodes(&self) -> u64 {
+        self.get(14, 46, 2)
+    }
+
+    fn used_dirs(&self) -> u64 {
+        self.get(16, 48, 2)
+    }
+
+    fn flags(&self) -> u16 {
+        le16(&self.raw, 18)
+    }
+
+    fn itable_unused(&self) -> u64 {
+        self.get(28, 50, 2)
+    }
+
+    fn set_flags(&mut self, flags: u16) {
+        set16(&mut self.raw, 18, flags);
+    }
+}
+
+
+////////////////////////// EXTFS IMPL //////////////////////////////
+impl<'a> ExtFs<'a> {
+    /// returns the size of a block in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// returns the number of block groups
+    pub fn group_count(&self) -> u64 {
+        (self.blocks_count - self.first_data_block + self.blocks_per_group - 1) / self.blocks_per_group
+    }
+
+    /// reads a whole block
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> uefi::Result {
+        if block >= self.blocks_count {
+            error!("Block {} is past the end of the filesystem", block);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        self.dev.read_at(block * self.block_size, buf)
+    }
+
+    /// checks to see if a group holds a backup of the superblock
+    fn has_super(&self, group: u64) -> bool {
+        if group <= 1 || self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        // with sparse_super only powers of 3, 5 and 7 do
+        [3, 5, 7].iter().any(|base| {
+            let mut n = *base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        })
+    }
+
+    /// returns the byte offset of a group's descriptor
+    fn desc_offset(&self, group: u64) -> u64 {
+        let per_block = self.block_size / self.desc_size;
+        let meta_group = group / per_block;
+
+        // with meta_bg the descriptors are spread out, each meta group of
+        // groups keeps its own at the start of its first group
+        if self.incompat & INCOMPAT_META_BG != 0 && meta_group >= self.first_meta_bg {
+            let first = meta_group * per_block;
+            let block = self.first_data_block + first * self.blocks_per_group + self.has_super(first) as u64;
+            return block * self.block_size + (group % per_block) * self.desc_size;
+        }
+        (self.first_data_block + 1) * self.block_size + group * self.desc_size
+    }
+
+    /// reads a group's descriptor
+    fn read_desc(&self, group: u64) -> uefi::Result<GroupDesc> {
+        if group >= self.group_count() {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let mut raw = vec![0u8; self.desc_size as usize];
+        self.dev.read_at(self.desc_offset(group), &mut raw)?;
+        Ok(GroupDesc{raw})
+    }
+
+    /// reads an inode
+    fn read_inode(&self, ino: u32) -> uefi::Result<Inode> {
+        if ino == 0 {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let group = (ino as u64 - 1) / self.inodes_per_group;
+        let index = (ino as u64 - 1) % self.inodes_per_group;
+        let table = self.read_desc(group)?.inode_table();
+
+        let mut raw = vec![0u8; self.inode_size as usize];
+        self.dev.read_at(table * self.block_size + index * self.inode_size, &mut raw)?;
+        Ok(Inode{raw})
+    }
+
+    /// maps a block of a file to where it is on the disk, None being a hole
+    fn map_block(&self, inode: &Inode, block: u64) -> uefi::Result<Option<u64>> {
+        match inode.flags() & INODE_EXTENTS {
+            0 => self.map_indirect(inode, block),
+            _ => self.map_extent(inode, block)
+        }
+    }
+
+    /// walks an extent tree to find a file's block
+    fn map_extent(&self, inode: &Inode, block: u64) -> uefi::Result<Option<u64>> {
+        let mut node = inode.i_block().to_vec();
+        for _ in 0..8 {
+            if le16(&node, 0) != EXTENT_MAGIC {
+                error!("Extent tree node is damaged");
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            let entries = le16(&node, 2) as usize;
+            let depth = le16(&node, 6);
+            if 12 + entries * 12 > node.len() {
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+
+            if depth == 0 {
+                for i in 0..entries {
+                    let e = 12 + i * 12;
+                    let start = le32(&node, e) as u64;
+                    let len = le16(&node, e + 4) as u64;
+
+                    // lengths above 32768 mark extents that were allocated but
+                    // never written, which read back as zeroes
+                    let (len, unwritten) = match len > 32768 {
+                        true => (len - 32768, true),
+                        false => (len, false)
+                    };
+                    if block >= start && block < start + len {
+                        if unwritten {
+                            return Ok(None);
+                        }
+                        let phys = (le16(&node, e + 6) as u64) << 32 | le32(&node, e + 8) as u64;
+                        return Ok(Some(phys + block - start));
+                    }
+                }
+                return Ok(None);
+            }
+
+            // follow the last index that starts at or before the block
+            let mut child = None;
+            for i in 0..entries {
+                let e = 12 + i * 12;
+                if le32(&node, e) as u64 > block {
+                    break;
+                }
+                child = Some((le16(&node, e + 8) as u64) << 32 | le32(&node, e + 4) as u64);
+            }
+            match child {
+                Some(child) => {
+                    node = vec![0u8; self.block_size as usize];
+                    self.read_block(child, &mut node)?;
+                },
+                None => return Ok(None)
+            }
+        }
+        error!("Extent tree is too deep");
+        Err(Status::VOLUME_CORRUPTED.into())
+    }
+
+    /// walks the legacy ext2/3 direct and indirect block maps to find a file's block
+    fn map_indirect(&self, inode: &Inode, block: u64) -> uefi::Result<Option<u64>> {
+        let per_block = self.block_size / 4;
+        let i_block = inode.i_block();
+
+        // work out which tree the block is in and how deep it goes
+        let (mut ptr, mut index, levels) = if block < 12 {
+            (le32(i_block, block as usize * 4), 0, 0)
+        } else if block - 12 < per_block {
+            (le32(i_block, 48), block - 12, 1)
+        } else if block - 12 - per_block < per_block * per_block {
+            (le32(i_block, 52), block - 12 - per_block, 2)
+        } else if block - 12 - per_block - per_block * per_block < per_block * per_block * per_block {
+            (le32(i_block, 56), block - 12 - per_block - per_block * per_block, 3)
+        } else {
+            return Ok(None);
+        };
+
+        for level in (0..levels).rev() {
+            if ptr == 0 {
+                return Ok(None);
+            }
+            let slot = index / per_block.pow(level);
+            index %= per_block.pow(level);
+            let mut raw = [0u8; 4];
+            self.dev.read_at(ptr as u64 * self.block_size + slot * 4, &mut raw)?;
+            ptr = u32::from_le_bytes(raw);
+        }
+        Ok(match ptr {
+            0 => None,
+            ptr => Some(ptr as u64)
+        })
+    }
+
+    /// reads from a file's data at byte `offset`, holes read back as zeroes
+    fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+
+        if inode.has_inline_data() {
+            let data = inode.inline_data();
+            let end = cmp::min(offset as usize + len, data.len());
+            let start = cmp::min(offset as usize, end);
+            buf[..end - start].copy_from_slice(&data[start..end]);
+            buf[end - start..len].iter_mut().for_each(|b| *b = 0);
+            return Ok(len);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % self.block_size;
+            let chunk = cmp::min((self.block_size - within) as usize, len - done);
+            match self.map_block(inode, pos / self.block_size)? {
+                Some(block) => {
+                    if block >= self.blocks_count {
+                        return Err(Status::VOLUME_CORRUPTED.into());
+                    }
+                    self.dev.read_at(block * self.block_size + within, &mut buf[done..done + chunk])?;
+                },
+                None => buf[done..done + chunk].iter_mut().for_each(|b| *b = 0)
+            }
+            done += chunk;
+        }
+        Ok(len)
+    }
+
+    /// reads every entry of a directory
+    fn read_dir_entries(&self, inode: &Inode) -> uefi::Result<Vec<ExtDirEntry>> {
+        if !inode.is_dir() {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let mut entries = Vec::new();
+
+        // inline directories start with their parent's inode instead of "."
+        // and "..", then carry on in the extended attribute
+        if inode.has_inline_data() {
+            let data = inode.inline_data();
+            if data.len() < 4 {
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            entries.push(ExtDirEntry{name: String::from(".."), ino: le32(&data, 0), file_type: 2});
+            let split = cmp::min(60, data.len());
+            parse_dir_block(&data[4..split], &mut entries)?;
+            parse_dir_block(&data[split..], &mut entries)?;
+            return Ok(entries);
+        }
+
+        let mut block = vec![0u8; self.block_size as usize];
+        let mut offset = 0;
+        while offset < inode.size() {
+            self.read_data(inode, offset, &mut block)?;
+            parse_dir_block(&block, &mut entries)?;
+            offset += self.block_size;
+        }
+        Ok(entries)
+    }
+
+    /// returns where a symlink points
+    fn link_target(&self, inode: &Inode) -> uefi::Result<String> {
+        let data = match inode.is_fast_symlink() {
+            true => inode.i_block()[..inode.size() as usize].to_vec(),
+            false => {
+                let mut data = vec![0u8; inode.size() as usize];
+                self.read_data(inode, 0, &mut data)?;
+                data
+            }
+        };
+        Ok(String::from(String::from_utf8_lossy(&data)))
+    }
+
+    /// finds the inode at `path`, following symlinks along the way
+    /// note a symlink at the very end is only followed with `follow_last` set
+    fn lookup(&self, path: &str, follow_last: bool) -> uefi::Result<(u32, Inode)> {
+        let mut parts = path_stack(path);
+        let mut ino = ROOT_INO;
+        let mut inode = self.read_inode(ino)?;
+        let mut links = 0;
+
+        while let Some(part) = parts.pop() {
+            if !inode.is_dir() {
+                return Err(Status::NOT_FOUND.into());
+            }
+            let child_ino = match self.read_dir_entries(&inode)?.into_iter().find(|e| e.name == part) {
+                Some(entry) => entry.ino,
+                None => return Err(Status::NOT_FOUND.into())
+            };
+            let child = self.read_inode(child_ino)?;
+
+            // a symlink's target is looked up from the directory holding it
+            if child.is_symlink() && (follow_last || !parts.is_empty()) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    warn!("Too many levels of symlinks in {}", path);
+                    return Err(Status::NOT_FOUND.into());
+                }
+                let target = self.link_target(&child)?;
+                parts.extend(path_stack(&target));
+                if target.starts_with('/') {
+                    ino = ROOT_INO;
+                    inode = self.read_inode(ino)?;
+                }
+                continue;
+            }
+            ino = child_ino;
+            inode = child;
+        }
+        Ok((ino, inode))
+    }
+
+    /// returns where the symlink at `path` points
+    pub fn read_link(&self, path: &str) -> uefi::Result<String> {
+        let (_, inode) = self.lookup(path, false)?;
+        if !inode.is_symlink() {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        self.link_target(&inode)
+    }
+
+    /// returns the first block of a group
+    fn group_start(&self, group: u64) -> u64 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    /// returns the number of group descriptors that fit in a block
+    fn descs_per_block(&self) -> u64 {
+        self.block_size / self.desc_size
+    }
+
+    /// returns the number of blocks a group's inode table takes up
+    fn inode_table_blocks(&self) -> u64 {
+        (self.inodes_per_group * self.inode_size + self.block_size - 1) / self.block_size
+    }
+
+    fn has_metadata_csum(&self) -> bool {
+        self.ro_compat & RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    /// checks to see if group descriptors carry a checksum, of either flavour
+    fn has_group_csum(&self) -> bool {
+        self.ro_compat & (RO_COMPAT_METADATA_CSUM | RO_COMPAT_GDT_CSUM) != 0
+    }
+
+    /// fails if the filesystem uses features we can't keep consistent while resizing
+    fn check_resizable(&self) -> uefi::Result {
+        if self.dev.is_read_only() {
+            return Err(Status::WRITE_PROTECTED.into());
+        }
+        let compat = self.compat & (COMPAT_EXCLUDE_BITMAP | COMPAT_SPARSE_SUPER2);
+        let incompat = self.incompat & (INCOMPAT_MMP | INCOMPAT_EA_INODE);
+        let ro_compat = self.ro_compat & (RO_COMPAT_SNAPSHOT | RO_COMPAT_BIGALLOC | RO_COMPAT_REPLICA | RO_COMPAT_ORPHAN_PRESENT);
+        if compat != 0 || incompat != 0 || ro_compat != 0 {
+            warn!(
+                "ext filesystem uses features we can't resize (compat {:#x}, incompat {:#x}, ro_compat {:#x})",
+                compat, incompat, ro_compat
+            );
+            return Err(Status::UNSUPPORTED.into());
+        }
+        Ok(())
+    }
+
+    /// returns the seed an inode's own checksums (and those of its blocks) start from
+    fn inode_seed(&self, ino: u32, inode: &Inode) -> u32 {
+        let seed = crc32c_update(self.csum_seed, &ino.to_le_bytes());
+        crc32c_update(seed, &inode.raw[100..104])
+    }
+
+    /// updates the checksum of an inode
+    fn set_inode_checksum(&self, ino: u32, inode: &mut Inode) {
+        if !self.has_metadata_csum() {
+            return;
+        }
+        // the high half only exists if the extra inode fields cover it
+        let has_hi = inode.raw.len() > 128 && le16(&inode.raw, 128) >= 4;
+        set16(&mut inode.raw, 124, 0);
+        if has_hi {
+            set16(&mut inode.raw, 130, 0);
+        }
+        let csum = crc32c_update(self.inode_seed(ino, inode), &inode.raw);
+        set16(&mut inode.raw, 124, csum as u16);
+        if has_hi {
+            set16(&mut inode.raw, 130, (csum >> 16) as u16);
+        }
+    }
+
+    /// updates the checksum of a group descriptor
+    fn set_desc_checksum(&self, group: u64, desc: &mut GroupDesc) {
+        set16(&mut desc.raw, 30, 0);
+        let csum = if self.has_metadata_csum() {
+            let crc = crc32c_update(self.csum_seed, &(group as u32).to_le_bytes());
+            crc32c_update(crc, &desc.raw) as u16
+        } else if self.ro_compat & RO_COMPAT_GDT_CSUM != 0 {
+            // the older flavour skips the checksum field rather than zeroing it
+            let crc = crc16_update(!0, &self.fs_uuid);
+            let crc = crc16_update(crc, &(group as u32).to_le_bytes());
+            let crc = crc16_update(crc, &desc.raw[..30]);
+            crc16_update(crc, &desc.raw[32..])
+        } else {
+            return;
+        };
+        set16(&mut desc.raw, 30, csum);
+    }
+
+    /// updates the bitmap checksums kept in a group descriptor
+    fn set_bitmap_checksums(&self, desc: &mut GroupDesc, blocks: Option<&[u8]>, inodes: Option<&[u8]>) {
+        if !self.has_metadata_csum() {
+            return;
+        }
+        if let Some(bitmap) = blocks {
+            let csum = crc32c_update(self.csum_seed, &bitmap[..(self.blocks_per_group / 8) as usize]);
+            desc.set(24, 56, 2, csum as u64);
+        }
+        if let Some(bitmap) = inodes {
+            let csum = crc32c_update(self.csum_seed, &bitmap[..(self.inodes_per_group / 8) as usize]);
+            desc.set(26, 58, 2, csum as u64);
+        }
+    }
+
+    /// updates the checksum of the superblock
+    fn set_sb_checksum(&self, sb: &mut [u8]) {
+        if self.has_metadata_csum() {
+            let csum = crc32c_update(!0, &sb[..1020]);
+            set32(sb, 1020, csum);
+        }
+    }
+
+    /// updates the checksum in the tail of an extent tree block
+    fn set_extent_checksum(&self, seed: u32, node: &mut [u8]) {
+        let tail = 12 + le16(node, 4) as usize * 12;
+        if self.has_metadata_csum() && tail + 4 <= node.len() {
+            let csum = crc32c_update(seed, &node[..tail]);
+            set32(node, tail, csum);
+        }
+    }
+
+    /// updates the checksum of a directory block, which lives in a fake entry
+    /// at the end of leaf blocks or after the index of htree blocks
+    fn set_dir_checksum(&self, seed: u32, block: &mut [u8], is_index: bool, is_root: bool) {
+        if !self.has_metadata_csum() {
+            return;
+        }
+        let size = block.len();
+        let tail = size - 12;
+        if le32(block, tail) == 0 && le16(block, tail + 4) == 12 && block[tail + 6] == 0 && block[tail + 7] == 0xde {
+            let csum = crc32c_update(seed, &block[..tail]);
+            set32(block, size - 4, csum);
+            return;
+        }
+        if !is_index {
+            return;
+        }
+
+        // the root starts with "." and "..", interior nodes with an empty entry
+        let count_offset = match is_root {
+            true => 24 + block[29] as usize,
+            false if le32(block, 0) == 0 && le16(block, 4) as usize == size => 8,
+            false => return
+        };
+        let limit = le16(block, count_offset) as usize;
+        let count = le16(block, count_offset + 2) as usize;
+        let tail = count_offset + limit * 8;
+        if tail + 8 > size || count > limit {
+            return;
+        }
+        let csum = crc32c_update(seed, &block[..count_offset + count * 8]);
+        let csum = crc32c_update(csum, &block[tail..tail + 4]);
+        let csum = crc32c_update(csum, &[0u8; 4]);
+        set32(block, tail + 4, csum);
+    }
+
+    /// updates the checksum of an extended attribute block, which depends on where it is
+    fn set_xattr_checksum(&self, block_nr: u64, block: &mut [u8]) {
+        if self.has_metadata_csum() {
+            set32(block, 16, 0);
+            let csum = crc32c_update(self.csum_seed, &block_nr.to_le_bytes());
+            let csum = crc32c_update(csum, block);
+            set32(block, 16, csum);
+        }
+    }
+
+    /// checks to see if the superblock's checksum is right
+    fn sb_checksum_ok(&self, sb: &[u8]) -> bool {
+        let mut copy = sb.to_vec();
+        self.set_sb_checksum(&mut copy);
+        copy == sb
+    }
+
+    /// checks to see if the journal still holds transactions to replay
+    fn journal_dirty(&self, sb: &[u8]) -> uefi::Result<bool> {
+        let ino = le32(sb, 224);
+        if self.compat & COMPAT_HAS_JOURNAL == 0 || ino == 0 {
+            return Ok(false);
+        }
+        let inode = self.read_inode(ino)?;
+        let block = match self.map_block(&inode, 0)? {
+            Some(block) => block,
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+        let mut jsb = vec![0u8; 1024];
+        self.dev.read_at(block * self.block_size, &mut jsb)?;
+
+        // the journal is written by jbd2, which is big endian throughout
+        let be32 = |offset: usize| u32::from_be_bytes(jsb[offset..offset + 4].try_into().unwrap());
+        if be32(0) != JOURNAL_MAGIC || !matches!(be32(4), 3 | 4) {
+            error!("ext journal superblock is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        // a journal with nothing to replay starts at 0
+        Ok(be32(28) != 0)
+    }
+
+    /// walks the list of orphaned inodes, which is chained through their dtime
+    fn orphans(&self, sb: &[u8]) -> uefi::Result<Vec<u32>> {
+        let inodes_count = le32(sb, 0);
+        let mut orphans = Vec::new();
+        let mut ino = le32(sb, 232);
+        while ino != 0 {
+            if ino > inodes_count || orphans.len() as u32 >= inodes_count || orphans.contains(&ino) {
+                error!("ext orphan list is damaged");
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            orphans.push(ino);
+            ino = le32(&self.read_inode(ino)?.raw, 20);
+        }
+        Ok(orphans)
+    }
+
+    /// checks the checksums of an extent tree node's children, and theirs
+    fn check_extents(&self, ino: u32, seed: u32, node: &[u8], report: &mut ExtCheck) -> uefi::Result {
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if le16(node, 0) != EXTENT_MAGIC || 12 + entries * 12 > node.len() || depth == 0 || depth > 8 {
+            return Ok(());
+        }
+        let mut block = vec![0u8; self.block_size as usize];
+        for i in 0..entries {
+            let e = 12 + i * 12;
+            let child = (le16(node, e + 8) as u64) << 32 | le32(node, e + 4) as u64;
+            if child >= self.blocks_count {
+                report.bad_extents.push((ino, child));
+                continue;
+            }
+            self.read_block(child, &mut block)?;
+            let mut copy = block.clone();
+            self.set_extent_checksum(seed, &mut copy);
+            if le16(&block, 0) != EXTENT_MAGIC || copy != block {
+                report.bad_extents.push((ino, child));
+                continue;
+            }
+            self.check_extents(ino, seed, &block, report)?;
+        }
+        Ok(())
+    }
+
+    /// checks the checksums of a group's bitmaps and the inodes in use in it
+    fn check_group(&self, group: u64, desc: &GroupDesc, report: &mut ExtCheck) -> uefi::Result {
+        let bs = self.block_size;
+        let ipg = self.inodes_per_group;
+        let mut inode_bitmap = vec![0u8; bs as usize];
+        let mut copy = desc.clone();
+
+        if desc.flags() & BG_BLOCK_UNINIT == 0 {
+            let mut bitmap = vec![0u8; bs as usize];
+            self.read_block(desc.block_bitmap(), &mut bitmap)?;
+            self.set_bitmap_checksums(&mut copy, Some(&bitmap), None);
+        }
+        if desc.flags() & BG_INODE_UNINIT != 0 {
+            if copy.raw != desc.raw {
+                report.bad_bitmaps.push(group);
+            }
+            return Ok(());
+        }
+        self.read_block(desc.inode_bitmap(), &mut inode_bitmap)?;
+        self.set_bitmap_checksums(&mut copy, None, Some(&inode_bitmap));
+        if copy.raw != desc.raw {
+            report.bad_bitmaps.push(group);
+        }
+
+        // inodes past the unused mark were never written
+        let used = match self.has_group_csum() {
+            true => ipg - cmp::min(desc.itable_unused(), ipg),
+            false => ipg
+        };
+        let per_chunk = cmp::max(COPY_CHUNK_BLOCKS * bs / self.inode_size, 1);
+        let mut table = vec![0u8; (cmp::min(used, per_chunk) * self.inode_size) as usize];
+        let mut first = 0;
+        while first < used {
+            let count = cmp::min(used - first, per_chunk);
+            let table = &mut table[..(count * self.inode_size) as usize];
+            self.dev.read_at(desc.inode_table() * bs + first * self.inode_size, table)?;
+
+            for (i, raw) in table.chunks_exact(self.inode_size as usize).enumerate() {
+                let index = first + i as u64;
+                if !bit_is_set(&inode_bitmap, index) {
+                    continue;
+                }
+                let ino = (group * ipg + index + 1) as u32;
+                let inode = Inode{raw: raw.to_vec()};
+                let mut copy = inode.clone();
+                self.set_inode_checksum(ino, &mut copy);
+                if copy.raw != inode.raw {
+                    report.bad_inodes.push(ino);
+                    continue;
+                }
+                if inode.has_blocks() && inode.flags() & INODE_EXTENTS != 0 {
+                    self.check_extents(ino, self.inode_seed(ino, &inode), inode.i_block(), report)?;
+                }
+            }
+            first += count;
+        }
+        Ok(())
+    }
+
+    /// checks the filesystem is safe to change, along the lines of what
+    /// e2fsck looks at before deciding a full check can be skipped
+    ///
+    /// The superblock, group descriptor, bitmap, inode and extent tree
+    /// checksums are all verified when the filesystem has them
+    pub fn check(&self) -> uefi::Result<ExtCheck> {
+        let mut sb = vec![0u8; 1024];
+        self.dev.read_at(1024, &mut sb)?;
+
+        let inodes_count = le32(&sb, 0) as u64;
+        let mut report = ExtCheck {
+            name:           self.label.clone().or_else(|| self.uuid.clone()).unwrap_or_default(),
+            inodes_count,
+            inodes_used:    inodes_count - cmp::min(le32(&sb, 16) as u64, inodes_count),
+            blocks_count:   self.blocks_count,
+            blocks_used:    self.blocks_count - cmp::min(self.free_blocks, self.blocks_count),
+            checksums:      self.has_group_csum(),
+            needs_recovery: le32(&sb, 96) & INCOMPAT_RECOVER != 0,
+            journal_dirty:  self.journal_dirty(&sb)?,
+            not_clean:      le16(&sb, 58) & (STATE_VALID | STATE_ERROR) != STATE_VALID,
+            error_count:    le32(&sb, 404),
+            orphans:        self.orphans(&sb)?,
+            orphan_file:    self.ro_compat & RO_COMPAT_ORPHAN_PRESENT != 0,
+            bad_superblock: !self.sb_checksum_ok(&sb),
+            ..ExtCheck::default()
+        };
+        if !report.checksums {
+            return Ok(report);
+        }
+
+        for group in 0..self.group_count() {
+            let desc = self.read_desc(group)?;
+            let mut copy = desc.clone();
+            self.set_desc_checksum(group, &mut copy);
+            if copy.raw != desc.raw {
+                // nothing it points at can be trusted
+                report.bad_descs.push(group);
+                continue;
+            }
+            if self.has_metadata_csum() {
+                self.check_group(group, &desc, &mut report)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+
+////////////////////////// EXTCHECK IMPL //////////////////////////////
+impl ExtCheck {
+    /// checks to see if nothing was found that would make changing the filesystem unsafe
+    pub fn is_clean(&self) -> bool {
+        !self.needs_recovery && !self.journal_dirty && !self.not_clean && self.error_count == 0
+            && self.orphans.is_empty() && !self.orphan_file && !self.bad_superblock
+            && self.bad_descs.is_empty() && self.bad_bitmaps.is_empty()
+            && self.bad_inodes.is_empty() && self.bad_extents.is_empty()
+    }
+
+    /// prints a summary to the console, like the one line e2fsck gives
+    pub fn print(&self) {
+        info!(
+            "{}: {}, {}/{} files, {}/{} blocks",
+            self.name,
+            if self.is_clean() { "clean" } else { "needs checking" },
+            self.inodes_used,
+            self.inodes_count,
+            self.blocks_used,
+            self.blocks_count
+        );
+        if !self.checksums {
+            info!("  no metadata checksums to verify");
+        }
+        if self.needs_recovery || self.journal_dirty {
+            warn!("  journal needs recovery");
+        }
+        if self.not_clean {
+            warn!("  not cleanly unmounted or marked as having errors");
+        }
+        if self.error_count != 0 {
+            warn!("  {} errors recorded by the kernel", self.error_count);
+        }
+        if !self.orphans.is_empty() {
+            warn!("  {} orphaned inodes waiting to be cleaned up", self.orphans.len());
+        }
+        if self.orphan_file {
+            warn!("  orphan file is in use");
+        }
+        if self.bad_superblock {
+            warn!("  superblock checksum is wrong");
+        }
+        if !self.bad_descs.is_empty() {
+            warn!("  {} group descriptor checksums are wrong (first group {})", self.bad_descs.len(), self.bad_descs[0]);
+        }
+        if !self.bad_bitmaps.is_empty() {
+            warn!("  {} groups have bitmap checksums that are wrong (first group {})", self.bad_bitmaps.len(), self.bad_bitmaps[0]);
+        }
+        if !self.bad_inodes.is_empty() {
+            warn!("  {} inode checksums are wrong (first inode {})", self.bad_inodes.len(), self.bad_inodes[0]);
+        }
+        if let Some((ino, block)) = self.bad_extents.first() {
+            warn!("  {} extent blocks are damaged (first is block {} of inode {})", self.bad_extents.len(), block, ino);
+        }
+    }
+}
+
+
+////////////////////////// RESIZE IMPL //////////////////////////////
+impl<'r, 'a> Resize<'r, 'a> {
+    /// loads the superblock and every group descriptor
+    fn new(fs: &'r ExtFs<'a>) -> uefi::Result<Self> {
+        let mut sb = vec![0u8; 1024];
+        fs.dev.read_at(1024, &mut sb)?;
+
+        let groups = fs.group_count();
+        let mut descs = Vec::with_capacity(groups as usize);
+        for group in 0..groups {
+            descs.push(fs.read_desc(group)?);
+        }
+        let gdt_blocks = match fs.incompat & INCOMPAT_META_BG {
+            0 => (groups + fs.descs_per_block() - 1) / fs.descs_per_block(),
+            _ => fs.first_meta_bg
+        };
+
+        Ok(Resize {
+            fs,
+            reserved_gdt:   le16(&sb, 206) as u64,
+            sb,
+            descs,
+            block_bitmaps:  BTreeMap::new(),
+            inode_bitmaps:  BTreeMap::new(),
+            gdt_blocks,
+            limit:          fs.blocks_count,
+            kept_groups:    groups,
+            next_alloc:     fs.first_data_block,
+            moves:          BTreeMap::new(),
+            planning:       false
+        })
+    }
+
+    /// reads one of the superblock's counts that has a high half with 64bit
+    fn sb_count(&self, lo: usize, hi: usize) -> u64 {
+        match self.fs.incompat & INCOMPAT_64BIT {
+            0 => le32(&self.sb, lo) as u64,
+            _ => le32(&self.sb, lo) as u64 | (le32(&self.sb, hi) as u64) << 32
+        }
+    }
+
+    fn set_sb_count(&mut self, lo: usize, hi: usize, value: u64) {
+        set32(&mut self.sb, lo, value as u32);
+        if self.fs.incompat & INCOMPAT_64BIT != 0 {
+            set32(&mut self.sb, hi, (value >> 32) as u32);
+        }
+    }
+
+    /// returns the number of blocks in the filesystem, as far as the resize has got
+    fn blocks_count(&self) -> u64 {
+        self.sb_count(4, 336)
+    }
+
+    /// changes the number of blocks, scaling the reserved blocks along with it
+    fn set_blocks_count(&mut self, blocks: u64) {
+        let old = self.blocks_count();
+        let reserved = self.sb_count(8, 340) as u128 * blocks as u128 / old as u128;
+        self.set_sb_count(4, 336, blocks);
+        self.set_sb_count(8, 340, reserved as u64);
+    }
+
+    /// returns the number of blocks in a group
+    fn group_size(&self, group: u64) -> u64 {
+        cmp::min(self.fs.blocks_per_group, self.blocks_count() - self.fs.group_start(group))
+    }
+
+    /// returns the number of blocks at the start of a group taken up by the
+    /// superblock and group descriptor copies
+    fn group_overhead(&self, group: u64) -> u64 {
+        let fs = self.fs;
+        let has_super = fs.has_super(group) as u64;
+        let per_block = fs.descs_per_block();
+        if fs.incompat & INCOMPAT_META_BG != 0 && group / per_block >= fs.first_meta_bg {
+            // meta_bg keeps a descriptor block in the first, second and last
+            // group of each meta group
+            match group % per_block {
+                0 | 1 => has_super + 1,
+                index if index == per_block - 1 => has_super + 1,
+                _ => has_super
+            }
+        } else if has_super == 1 {
+            1 + self.gdt_blocks + self.reserved_gdt
+        } else {
+            0
+        }
+    }
+
+    /// returns the bitmap of a group's blocks, loading it if needed
+    fn block_bitmap(&mut self, group: u64) -> uefi::Result<&mut Vec<u8>> {
+        if !self.block_bitmaps.contains_key(&group) {
+            let fs = self.fs;
+            let desc = &self.descs[group as usize];
+            let mut bitmap = vec![0u8; fs.block_size as usize];
+
+            if desc.flags() & BG_BLOCK_UNINIT != 0 {
+                // never written out, so work out what it would hold
+                let start = fs.group_start(group);
+                let table = desc.inode_table();
+                let bitmaps = [desc.block_bitmap(), desc.inode_bitmap()];
+                let metadata = bitmaps.iter().copied().chain(table..table + fs.inode_table_blocks());
+                for block in metadata.filter(|b| *b >= start && *b < start + fs.blocks_per_group) {
+                    set_bit(&mut bitmap, block - start, true);
+                }
+                for bit in 0..self.group_overhead(group) {
+                    set_bit(&mut bitmap, bit, true);
+                }
+                for bit in self.group_size(group)..fs.block_size * 8 {
+                    set_bit(&mut bitmap, bit, true);
+                }
+            } else {
+                fs.dev.read_at(desc.block_bitmap() * fs.block_size, &mut bitmap)?;
+            }
+            self.block_bitmaps.insert(group, bitmap);
+        }
+        Ok(self.block_bitmaps.get_mut(&group).unwrap())
+    }
+
+    /// returns the bitmap of a group's inodes, loading it if needed
+    fn inode_bitmap(&mut self, group: u64) -> uefi::Result<&mut Vec<u8>> {
+        if !self.inode_bitmaps.contains_key(&group) {
+            let bitmap = self.read_inode_bitmap(group)?;
+            self.inode_bitmaps.insert(group, bitmap);
+        }
+        Ok(self.inode_bitmaps.get_mut(&group).unwrap())
+    }
+
+    /// reads the bitmap of a group's inodes without caching it
+    fn read_inode_bitmap(&self, group: u64) -> uefi::Result<Vec<u8>> {
+        let fs = self.fs;
+        let desc = &self.descs[group as usize];
+        let mut bitmap = vec![0u8; fs.block_size as usize];
+        if desc.flags() & BG_INODE_UNINIT != 0 {
+            for bit in fs.inodes_per_group..fs.block_size * 8 {
+                set_bit(&mut bitmap, bit, true);
+            }
+        } else {
+            fs.dev.read_at(desc.inode_bitmap() * fs.block_size, &mut bitmap)?;
+        }
+        Ok(bitmap)
+    }
+
+    /// marks a block as used or free
+    fn set_block(&mut self, block: u64, used: bool) -> uefi::Result {
+        let offset = block - self.fs.first_data_block;
+        let bpg = self.fs.blocks_per_group;
+        set_bit(self.block_bitmap(offset / bpg)?, offset % bpg, used);
+        Ok(())
+    }
+
+    /// looks for `len` free blocks in a row between `from` and the limit
+    fn find_free_run(&mut self, from: u64, len: u64) -> uefi::Result<Option<u64>> {
+        let fs = self.fs;
+        let mut run_start = from;
+        let mut run_len = 0;
+        let mut block = from;
+        while block < self.limit {
+            let group = (block - fs.first_data_block) / fs.blocks_per_group;
+            let start = fs.group_start(group);
+            let end = cmp::min(self.limit, start + fs.blocks_per_group);
+
+            // a full group can be skipped without reading its bitmap
+            if !self.block_bitmaps.contains_key(&group) && self.descs[group as usize].free_blocks() == 0 {
+                run_len = 0;
+                run_start = end;
+                block = end;
+                continue;
+            }
+            let bitmap = self.block_bitmap(group)?;
+            for b in block..end {
+                if bit_is_set(bitmap, b - start) {
+                    run_len = 0;
+                    run_start = b + 1;
+                    continue;
+                }
+                run_len += 1;
+                if run_len == len {
+                    return Ok(Some(run_start));
+                }
+            }
+            block = end;
+        }
+        Ok(None)
+    }
+
+    /// finds `len` free blocks in a row below the limit and marks them used
+    fn alloc_blocks(&mut self, len: u64) -> uefi::Result<u64> {
+        let start = match self.find_free_run(self.next_alloc, len)? {
+            Some(start) => start,
+            None => match self.find_free_run(self.fs.first_data_block, len)? {
+                Some(start) => start,
+                None => {
+                    warn!("No run of {} free blocks left to move data into", len);
+                    return Err(Status::VOLUME_FULL.into());
+                }
+            }
+        };
+        for block in start..start + len {
+            self.set_block(block, true)?;
+        }
+        self.next_alloc = start + len;
+        Ok(start)
+    }
+
+    /// returns where a run of blocks past the limit goes, picking a place for
+    /// it while planning
+    fn new_home(&mut self, start: u64, len: u64) -> uefi::Result<u64> {
+        if let Some((new, _)) = self.moves.get(&start) {
+            return Ok(*new);
+        }
+        if !self.planning {
+            error!("Block {} wasn't planned to move", start);
+            return Err(Status::ABORTED.into());
+        }
+        let new = self.alloc_blocks(len)?;
+        self.moves.insert(start, (new, len));
+        Ok(new)
+    }
+
+    /// finds a free inode in the kept groups and marks it used
+    fn alloc_inode(&mut self, is_dir: bool) -> uefi::Result<u32> {
+        let ipg = self.fs.inodes_per_group;
+        let first_ino = match le32(&self.sb, 76) {
+            0 => 11,
+            _ => le32(&self.sb, 84) as u64
+        };
+        for group in 0..self.kept_groups {
+            if !self.inode_bitmaps.contains_key(&group) && self.descs[group as usize].free_inodes() == 0 {
+                continue;
+            }
+            let bitmap = self.inode_bitmap(group)?;
+            let index = match (0..ipg).find(|i| !bit_is_set(bitmap, *i) && group * ipg + i + 1 >= first_ino) {
+                Some(index) => index,
+                None => continue
+            };
+            set_bit(bitmap, index, true);
+
+            let desc = &mut self.descs[group as usize];
+            if is_dir {
+                let dirs = desc.used_dirs() + 1;
+                desc.set(16, 48, 2, dirs);
+            }
+            // inodes past the unused mark were never set up, so move the mark
+            if index >= ipg - cmp::min(desc.itable_unused(), ipg) {
+                desc.set(28, 50, 2, ipg - index - 1);
+            }
+            return Ok((group * ipg + index + 1) as u32);
+        }
+        warn!("No free inodes left in the groups being kept");
+        Err(Status::VOLUME_FULL.into())
+    }
+
+    /// returns every inode in use in `groups`
+    fn used_inodes(&self, groups: core::ops::Range<u64>) -> uefi::Result<Vec<u32>> {
+        let ipg = self.fs.inodes_per_group;
+        let mut inodes = Vec::new();
+        for group in groups {
+            let bitmap = match self.inode_bitmaps.get(&group) {
+                Some(bitmap) => bitmap.clone(),
+                None if self.descs[group as usize].free_inodes() >= ipg => continue,
+                None => self.read_inode_bitmap(group)?
+            };
+            inodes.extend((0..ipg).filter(|i| bit_is_set(&bitmap, *i)).map(|i| (group * ipg + i + 1) as u32));
+        }
+        Ok(inodes)
+    }
+
+    /// returns the byte offset of an inode
+    fn inode_offset(&self, ino: u32) -> u64 {
+        let fs = self.fs;
+        let group = (ino as u64 - 1) / fs.inodes_per_group;
+        let index = (ino as u64 - 1) % fs.inodes_per_group;
+        self.descs[group as usize].inode_table() * fs.block_size + index * fs.inode_size
+    }
+
+    fn read_inode(&self, ino: u32) -> uefi::Result<Inode> {
+        let mut raw = vec![0u8; self.fs.inode_size as usize];
+        self.fs.dev.read_at(self.inode_offset(ino), &mut raw)?;
+        Ok(Inode{raw})
+    }
+
+    fn write_inode(&self, ino: u32, inode: &mut Inode) -> uefi::Result {
+        self.fs.set_inode_checksum(ino, inode);
+        self.fs.dev.write_at(self.inode_offset(ino), &inode.raw)
+    }
+
+    /// reads a whole block
+    fn read_block(&self, block: u64) -> uefi::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.fs.block_size as usize];
+        self.fs.dev.read_at(block * self.fs.block_size, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> uefi::Result {
+        self.fs.dev.write_at(block * self.fs.block_size, buf)
+    }
+
+    /// copies `len` blocks from `from` to `to`, the two must not overlap
+    fn copy_blocks(&self, from: u64, to: u64, len: u64) -> uefi::Result {
+        let bs = self.fs.block_size;
+        let mut buf = vec![0u8; (cmp::min(len, COPY_CHUNK_BLOCKS) * bs) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, COPY_CHUNK_BLOCKS);
+            let buf = &mut buf[..(chunk * bs) as usize];
+            self.fs.dev.read_at((from + done) * bs, buf)?;
+            self.fs.dev.write_at((to + done) * bs, buf)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// returns the resize inode and the block listing the reserved GDT blocks,
+    /// if the filesystem has one
+    fn resize_inode(&self) -> uefi::Result<Option<(Inode, u64)>> {
+        if self.fs.compat & COMPAT_RESIZE_INODE == 0 {
+            return Ok(None);
+        }
+        let inode = self.read_inode(RESIZE_INO)?;
+        let dind = le32(inode.i_block(), 13 * 4) as u64;
+        Ok(match dind {
+            0 => None,
+            dind => Some((inode, dind))
+        })
+    }
+
+    /// returns the groups other than the first holding superblock backups
+    fn backup_groups(&self, groups: u64) -> Vec<u64> {
+        (1..groups).filter(|g| self.fs.has_super(*g)).collect()
+    }
+
+    /// changes the block count of the resize inode by `count` blocks
+    fn adjust_resize_blocks(&self, inode: &mut Inode, count: i64) {
+        let sectors = count * (self.fs.block_size / 512) as i64;
+        inode.set_blocks((inode.blocks() as i64 + sectors) as u64);
+    }
+
+    /// turns the first reserved GDT block into a real one (numbered `index`),
+    /// its backups become the GDT backups
+    fn take_reserved_gdt(&mut self, index: u64) -> uefi::Result {
+        let (mut inode, dind) = match self.resize_inode()? {
+            Some(found) => found,
+            None => return Err(Status::UNSUPPORTED.into())
+        };
+        let per_block = (self.fs.block_size / 4) as usize;
+        let primary = self.fs.first_data_block + 1 + index;
+        let slot = (index as usize % per_block) * 4;
+
+        let mut list = self.read_block(dind)?;
+        if le32(&list, slot) as u64 != primary {
+            error!("Resize inode doesn't list reserved GDT block {}", primary);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        set32(&mut list, slot, 0);
+        self.write_block(dind, &list)?;
+
+        let backups = self.read_block(primary)?;
+        let count = backups.chunks_exact(4).filter(|b| le32(b, 0) != 0).count();
+        self.adjust_resize_blocks(&mut inode, -(1 + count as i64));
+        self.write_inode(RESIZE_INO, &mut inode)?;
+
+        self.reserved_gdt -= 1;
+        self.gdt_blocks += 1;
+        Ok(())
+    }
+
+    /// turns a GDT block that is no longer needed (numbered `index`) back into
+    /// a reserved one, listing the backups in the groups we keep
+    fn give_back_gdt(&mut self, index: u64) -> uefi::Result {
+        let (mut inode, dind) = match self.resize_inode()? {
+            Some(found) => found,
+            None => return Err(Status::UNSUPPORTED.into())
+        };
+        let fs = self.fs;
+        let per_block = (fs.block_size / 4) as usize;
+        let primary = fs.first_data_block + 1 + index;
+
+        let mut backups = vec![0u8; fs.block_size as usize];
+        let groups = self.backup_groups(self.kept_groups);
+        for (i, group) in groups.iter().take(per_block).enumerate() {
+            set32(&mut backups, i * 4, (fs.group_start(*group) + 1 + index) as u32);
+        }
+        self.write_block(primary, &backups)?;
+
+        let mut list = self.read_block(dind)?;
+        set32(&mut list, (index as usize % per_block) * 4, primary as u32);
+        self.write_block(dind, &list)?;
+
+        self.adjust_resize_blocks(&mut inode, 1 + cmp::min(groups.len(), per_block) as i64);
+        self.write_inode(RESIZE_INO, &mut inode)?;
+
+        self.reserved_gdt += 1;
+        self.gdt_blocks -= 1;
+        Ok(())
+    }
+
+    /// lists the reserved GDT backups in a new group with a superblock backup
+    fn add_reserved_backups(&mut self, group: u64) -> uefi::Result {
+        let (mut inode, dind) = match self.resize_inode()? {
+            Some(found) => found,
+            None => return Ok(())
+        };
+        let fs = self.fs;
+        let per_block = (fs.block_size / 4) as usize;
+
+        // each reserved block lists its backups in group order
+        let index = self.backup_groups(group).len();
+        if index >= per_block {
+            return Ok(());
+        }
+        let list = self.read_block(dind)?;
+        for i in 0..self.reserved_gdt {
+            let primary = le32(&list, ((self.gdt_blocks + i) as usize % per_block) * 4) as u64;
+            if primary == 0 {
+                continue;
+            }
+            let mut backups = self.read_block(primary)?;
+            set32(&mut backups, index * 4, (fs.group_start(group) + 1 + self.gdt_blocks + i) as u32);
+            self.write_block(primary, &backups)?;
+        }
+        self.adjust_resize_blocks(&mut inode, self.reserved_gdt as i64);
+        self.write_inode(RESIZE_INO, &mut inode)
+    }
+
+    /// drops the reserved GDT backups that lie past the limit
+    fn trim_reserved_backups(&mut self) -> uefi::Result {
+        let (mut inode, dind) = match self.resize_inode()? {
+            Some(found) => found,
+            None => return Ok(())
+        };
+        let per_block = (self.fs.block_size / 4) as usize;
+        let list = self.read_block(dind)?;
+        let mut removed = 0;
+        for i in 0..self.reserved_gdt {
+            let primary = le32(&list, ((self.gdt_blocks + i) as usize % per_block) * 4) as u64;
+            if primary == 0 {
+                continue;
+            }
+            let mut backups = self.read_block(primary)?;
+            let mut changed = false;
+            for slot in 0..per_block {
+                if le32(&backups, slot * 4) as u64 >= self.limit {
+                    set32(&mut backups, slot * 4, 0);
+                    removed += 1;
+                    changed = true;
+                }
+            }
+            if changed {
+                self.write_block(primary, &backups)?;
+            }
+        }
+        if removed != 0 {
+            self.adjust_resize_blocks(&mut inode, -removed);
+            self.write_inode(RESIZE_INO, &mut inode)?;
+        }
+        Ok(())
+    }
+
+    /// sets up the bitmaps, inode table and descriptor of a new group
+    /// note the group's bitmaps and table go in the group itself, which is
+    /// fine with flex_bg too
+    fn add_group(&mut self, group: u64) -> uefi::Result {
+        let fs = self.fs;
+        let bs = fs.block_size;
+        let start = fs.group_start(group);
+        let overhead = self.group_overhead(group);
+        let table_blocks = fs.inode_table_blocks();
+        let block_bitmap = start + overhead;
+
+        let mut blocks = vec![0u8; bs as usize];
+        for bit in (0..overhead + 2 + table_blocks).chain(self.group_size(group)..bs * 8) {
+            set_bit(&mut blocks, bit, true);
+        }
+        let mut inodes = vec![0u8; bs as usize];
+        for bit in fs.inodes_per_group..bs * 8 {
+            set_bit(&mut inodes, bit, true);
+        }
+
+        // the inode table has to start out zeroed
+        let zeroes = vec![0u8; (COPY_CHUNK_BLOCKS * bs) as usize];
+        let mut done = 0;
+        while done < table_blocks {
+            let chunk = cmp::min(table_blocks - done, COPY_CHUNK_BLOCKS);
+            self.write_block(block_bitmap + 2 + done, &zeroes[..(chunk * bs) as usize])?;
+            done += chunk;
+        }
+
+        let mut desc = GroupDesc{raw: vec![0u8; fs.desc_size as usize]};
+        desc.set(0, 32, 4, block_bitmap);
+        desc.set(4, 36, 4, block_bitmap + 1);
+        desc.set(8, 40, 4, block_bitmap + 2);
+        if fs.has_group_csum() {
+            desc.set_flags(BG_INODE_ZEROED);
+            desc.set(28, 50, 2, fs.inodes_per_group);
+        }
+        self.descs.push(desc);
+        self.block_bitmaps.insert(group, blocks);
+        self.inode_bitmaps.insert(group, inodes);
+
+        if fs.has_super(group) && (fs.incompat & INCOMPAT_META_BG == 0) {
+            self.add_reserved_backups(group)?;
+        }
+        Ok(())
+    }
+
+    /// works out how many blocks and groups a resize to `blocks` ends up with,
+    /// dropping a last group too small to hold its own metadata
+    fn layout(&self, blocks: u64) -> uefi::Result<(u64, u64)> {
+        let fs = self.fs;
+        if blocks <= fs.first_data_block {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let groups = (blocks - fs.first_data_block + fs.blocks_per_group - 1) / fs.blocks_per_group;
+        let last_start = fs.group_start(groups - 1);
+        let needed = self.group_overhead(groups - 1) + 2 + fs.inode_table_blocks() + 50;
+        if blocks - last_start >= needed {
+            return Ok((blocks, groups));
+        }
+        if groups == 1 {
+            warn!("{} blocks is too small for an ext filesystem", blocks);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        Ok((last_start, groups - 1))
+    }
+
+    /// grows the filesystem to `blocks` blocks in `groups` groups
+    fn grow(&mut self, blocks: u64, groups: u64) -> uefi::Result {
+        let fs = self.fs;
+        let old_groups = self.descs.len() as u64;
+        if groups * fs.inodes_per_group > u32::MAX as u64 {
+            warn!("ext filesystem would have too many inodes, refusing to grow it that far");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if fs.incompat & INCOMPAT_64BIT == 0 && blocks > u32::MAX as u64 {
+            warn!("ext filesystem without the 64bit feature can't go past 2^32 blocks");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        // new descriptors need room in the GDT, taken from the reserved blocks
+        let needed = match fs.incompat & INCOMPAT_META_BG {
+            0 => (groups + fs.descs_per_block() - 1) / fs.descs_per_block(),
+            _ => self.gdt_blocks
+        };
+        if needed > self.gdt_blocks && (needed - self.gdt_blocks > self.reserved_gdt || self.resize_inode()?.is_none()) {
+            warn!("No reserved GDT blocks left, the filesystem would have to be converted to meta_bg first");
+            return Err(Status::UNSUPPORTED.into());
+        }
+        info!("Growing ext filesystem from {} to {} blocks", self.blocks_count(), blocks);
+
+        // the old last group fills up first
+        let last = old_groups - 1;
+        let old_size = self.group_size(last);
+        self.block_bitmap(last)?;
+        self.set_blocks_count(blocks);
+        let new_size = self.group_size(last);
+        let bitmap = self.block_bitmap(last)?;
+        for bit in old_size..new_size {
+            set_bit(bitmap, bit, false);
+        }
+
+        for index in self.gdt_blocks..needed {
+            self.take_reserved_gdt(index)?;
+        }
+        for group in old_groups..groups {
+            self.add_group(group)?;
+        }
+        self.limit = blocks;
+        self.kept_groups = groups;
+        self.commit()
+    }
+
+    /// picks a new inode in the kept groups for every inode in use in the
+    /// groups being removed
+    ///
+    /// Returns the old and new inode numbers
+    fn plan_inodes(&mut self, old_groups: u64) -> uefi::Result<BTreeMap<u32, u32>> {
+        let mut map = BTreeMap::new();
+        for ino in self.used_inodes(self.kept_groups..old_groups)? {
+            let inode = self.read_inode(ino)?;
+            let new = self.alloc_inode(inode.is_dir())?;
+            map.insert(ino, new);
+        }
+        Ok(map)
+    }
+
+    /// moves every data and tree block of an extent tree node that lies past
+    /// the limit, returns true if the node itself changed
+    ///
+    /// With `reseed` set every tree block is written back with a fresh checksum,
+    /// as needed when the inode has a new number
+    fn move_extents(&mut self, seed: u32, node: &mut [u8], reseed: bool) -> uefi::Result<bool> {
+        if le16(node, 0) != EXTENT_MAGIC {
+            error!("Extent tree node is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        if 12 + entries * 12 > node.len() || depth > 8 {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        let mut changed = false;
+        for i in 0..entries {
+            let e = 12 + i * 12;
+            if depth == 0 {
+                let len = le16(node, e + 4) as u64;
+                let (len, unwritten) = match len > 32768 {
+                    true => (len - 32768, true),
+                    false => (len, false)
+                };
+                let start = (le16(node, e + 6) as u64) << 32 | le32(node, e + 8) as u64;
+                if start + len <= self.limit {
+                    continue;
+                }
+
+                // the whole extent moves, so it stays in one piece
+                let new = self.new_home(start, len)?;
+                if !unwritten && !self.planning {
+                    self.copy_blocks(start, new, len)?;
+                }
+                set16(node, e + 6, (new >> 32) as u16);
+                set32(node, e + 8, new as u32);
+                changed = true;
+                continue;
+            }
+
+            let mut child = (le16(node, e + 8) as u64) << 32 | le32(node, e + 4) as u64;
+            let mut block = self.read_block(child)?;
+            let mut dirty = self.move_extents(seed, &mut block, reseed)? || reseed;
+            if child >= self.limit {
+                child = self.new_home(child, 1)?;
+                set32(node, e + 4, child as u32);
+                set16(node, e + 8, (child >> 32) as u16);
+                changed = true;
+                dirty = true;
+            }
+            if dirty && !self.planning {
+                self.fs.set_extent_checksum(seed, &mut block);
+                self.write_block(child, &block)?;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// moves a block of a block mapped file, and at `level` > 0 everything
+    /// below the indirect block too
+    ///
+    /// Returns the block's new number (or the old one if it stayed)
+    fn move_mapped(&mut self, ptr: u32, level: u32) -> uefi::Result<u32> {
+        if ptr == 0 {
+            return Ok(0);
+        }
+        let mut ptr = ptr as u64;
+        if level == 0 {
+            if ptr >= self.limit {
+                let new = self.new_home(ptr, 1)?;
+                if !self.planning {
+                    self.copy_blocks(ptr, new, 1)?;
+                }
+                ptr = new;
+            }
+            return Ok(ptr as u32);
+        }
+
+        let mut block = self.read_block(ptr)?;
+        let mut changed = false;
+        for slot in 0..(self.fs.block_size / 4) as usize {
+            let old = le32(&block, slot * 4);
+            let new = self.move_mapped(old, level - 1)?;
+            if new != old {
+                set32(&mut block, slot * 4, new);
+                changed = true;
+            }
+        }
+        if ptr >= self.limit {
+            ptr = self.new_home(ptr, 1)?;
+            changed = true;
+        }
+        if changed && !self.planning {
+            self.write_block(ptr, &block)?;
+        }
+        Ok(ptr as u32)
+    }
+
+    /// moves every block of the kept inodes that lies past the limit, reading
+    /// each inode from its old number and writing it to its new one
+    ///
+    /// While planning this only picks where everything goes
+    fn move_blocks(&mut self, inodes: &[(u32, u32)]) -> uefi::Result {
+        let journal_ino = le32(&self.sb, 224);
+        let mut xattrs = BTreeSet::new();
+
+        for (old, ino) in inodes.iter().copied() {
+            // the resize inode's blocks are dealt with on their own
+            if ino == RESIZE_INO {
+                continue;
+            }
+            let mut inode = self.read_inode(old)?;
+            let mut changed = old != ino;
+
+            if inode.has_blocks() && inode.flags() & INODE_EXTENTS != 0 {
+                let seed = self.fs.inode_seed(ino, &inode);
+                let mut root = inode.i_block().to_vec();
+                if self.move_extents(seed, &mut root, old != ino)? {
+                    inode.raw[40..100].copy_from_slice(&root);
+                    changed = true;
+                }
+            } else if inode.has_blocks() {
+                // 12 direct blocks, then single, double and triple indirect
+                for slot in 0..15usize {
+                    let level = slot.saturating_sub(11) as u32;
+                    let old = le32(inode.i_block(), slot * 4);
+                    let new = self.move_mapped(old, level)?;
+                    if new != old {
+                        set32(&mut inode.raw, 40 + slot * 4, new);
+                        changed = true;
+                    }
+                }
+            }
+
+            // extended attribute blocks can be shared, so only copy each once
+            let acl = inode.file_acl();
+            if acl != 0 && acl >= self.limit {
+                let new = self.new_home(acl, 1)?;
+                if !self.planning && xattrs.insert(acl) {
+                    let mut block = self.read_block(acl)?;
+                    self.fs.set_xattr_checksum(new, &mut block);
+                    self.write_block(new, &block)?;
+                }
+                inode.set_file_acl(new);
+                changed = true;
+            }
+
+            if changed && !self.planning {
+                self.write_inode(ino, &mut inode)?;
+                // the superblock keeps a copy of the journal's block map
+                if old == journal_ino {
+                    self.sb[268..328].copy_from_slice(inode.i_block());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// renumbers the inodes directory entries point at, and redoes the
+    /// checksums of directories that were moved themselves
+    fn remap_dirs(&mut self, map: &BTreeMap<u32, u32>, moved: &BTreeSet<u32>) -> uefi::Result {
+        let fs = self.fs;
+        let bs = fs.block_size;
+        for ino in self.used_inodes(0..self.kept_groups)? {
+            let mut inode = self.read_inode(ino)?;
+            if !inode.is_dir() {
+                continue;
+            }
+            let reseed = moved.contains(&ino);
+
+            // inline directories start with their parent instead of ".."
+            if inode.has_inline_data() {
+                let mut changed = false;
+                if let Some(new) = map.get(&le32(inode.i_block(), 0)) {
+                    set32(&mut inode.raw, 40, *new);
+                    changed = true;
+                }
+                changed |= remap_dir_block(&mut inode.raw[44..100], map);
+                if let Some(range) = inode.inline_xattr_range(7, b"data") {
+                    changed |= remap_dir_block(&mut inode.raw[range], map);
+                }
+                if changed || reseed {
+                    self.write_inode(ino, &mut inode)?;
+                }
+                continue;
+            }
+
+            let seed = fs.inode_seed(ino, &inode);
+            let is_index = inode.flags() & INODE_INDEX != 0;
+            for index in 0..(inode.size() + bs - 1) / bs {
+                let block = match fs.map_block(&inode, index)? {
+                    Some(block) => block,
+                    None => continue
+                };
+                let mut data = self.read_block(block)?;
+                if remap_dir_block(&mut data, map) || reseed {
+                    fs.set_dir_checksum(seed, &mut data, is_index, is_index && index == 0);
+                    self.write_block(block, &data)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// shrinks the filesystem to `blocks` blocks in `groups` groups
+    fn shrink(&mut self, blocks: u64, groups: u64) -> uefi::Result {
+        let fs = self.fs;
+        let old_groups = self.descs.len() as u64;
+
+        // the groups we keep must have their own metadata below the new end
+        for desc in self.descs.iter().take(groups as usize) {
+            let table_end = desc.inode_table() + fs.inode_table_blocks();
+            if desc.block_bitmap() >= blocks || desc.inode_bitmap() >= blocks || table_end > blocks {
+                warn!("Group metadata lives past the new end of the filesystem, refusing to shrink");
+                return Err(Status::UNSUPPORTED.into());
+            }
+        }
+
+        // rule out the plainly hopeless before planning anything
+        let ipg = fs.inodes_per_group;
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        let mut needed_blocks = 0;
+        let mut needed_inodes = 0;
+        for (group, desc) in self.descs.iter().enumerate() {
+            let group = group as u64;
+            if group < groups {
+                free_blocks += desc.free_blocks();
+                free_inodes += desc.free_inodes();
+            } else {
+                let overhead = self.group_overhead(group) + 2 + fs.inode_table_blocks();
+                needed_blocks += (self.group_size(group) - desc.free_blocks()).saturating_sub(overhead);
+                needed_inodes += ipg - cmp::min(desc.free_inodes(), ipg);
+            }
+        }
+        if needed_blocks > free_blocks || needed_inodes > free_inodes {
+            warn!("Not enough free space left to shrink the filesystem to {} blocks", blocks);
+            return Err(Status::VOLUME_FULL.into());
+        }
+        info!("Shrinking ext filesystem from {} to {} blocks", self.blocks_count(), blocks);
+
+        self.limit = blocks;
+        self.kept_groups = groups;
+        let (map, inodes) = self.plan_evacuation(old_groups)?;
+
+        // from here on the filesystem changes on disk, so a failure is left
+        // for e2fsck to sort out
+        let result = self.evacuate(&map, &inodes).and_then(|_| self.cut_off(blocks, groups, old_groups));
+        if let Err(e) = result {
+            error!("Shrinking failed part way, run e2fsck on the filesystem");
+            self.mark_error()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// drops the groups past the new end once nothing is left in them
+    fn cut_off(&mut self, blocks: u64, groups: u64, old_groups: u64) -> uefi::Result {
+        let fs = self.fs;
+
+        // the removed groups' bitmaps and inode tables may live in kept groups
+        let table_blocks = fs.inode_table_blocks();
+        for group in groups..old_groups {
+            let desc = self.descs[group as usize].clone();
+            let table = desc.inode_table();
+            let bitmaps = [desc.block_bitmap(), desc.inode_bitmap()];
+            let metadata = bitmaps.iter().copied().chain(table..table + table_blocks);
+            for block in metadata.filter(|b| *b < blocks).collect::<Vec<u64>>() {
+                self.set_block(block, false)?;
+            }
+        }
+
+        // the GDT may need fewer blocks now
+        self.trim_reserved_backups()?;
+        if fs.incompat & INCOMPAT_META_BG == 0 {
+            let needed = (groups + fs.descs_per_block() - 1) / fs.descs_per_block();
+            let per_block = fs.block_size / 4;
+            let has_resize_inode = self.resize_inode()?.is_some();
+            while self.gdt_blocks > needed {
+                let index = self.gdt_blocks - 1;
+                if has_resize_inode && self.reserved_gdt < per_block {
+                    self.give_back_gdt(index)?;
+                    continue;
+                }
+                for group in [0].iter().copied().chain(self.backup_groups(groups)) {
+                    self.set_block(fs.group_start(group) + 1 + index, false)?;
+                }
+                self.gdt_blocks -= 1;
+            }
+        }
+
+        self.descs.truncate(groups as usize);
+        self.block_bitmaps.retain(|group, _| *group < groups);
+        self.inode_bitmaps.retain(|group, _| *group < groups);
+        self.set_blocks_count(blocks);
+        self.commit()
+    }
+
+    /// picks a place for every inode and block in the part being cut off
+    /// without writing anything, so running out of room changes nothing
+    ///
+    /// Returns the old and new inode numbers, and every inode kept along with
+    /// the number it is read from
+    fn plan_evacuation(&mut self, old_groups: u64) -> uefi::Result<(BTreeMap<u32, u32>, Vec<(u32, u32)>)> {
+        let map = self.plan_inodes(old_groups)?;
+        let moved: BTreeSet<u32> = map.values().copied().collect();
+        let mut inodes: Vec<(u32, u32)> = self.used_inodes(0..self.kept_groups)?.into_iter()
+            .filter(|ino| !moved.contains(ino))
+            .map(|ino| (ino, ino))
+            .collect();
+        inodes.extend(map.iter().map(|(old, new)| (*old, *new)));
+
+        self.planning = true;
+        let planned = self.move_blocks(&inodes);
+        self.planning = false;
+        planned?;
+        Ok((map, inodes))
+    }
+
+    /// moves every inode and block out of the part being cut off as planned
+    fn evacuate(&mut self, map: &BTreeMap<u32, u32>, inodes: &[(u32, u32)]) -> uefi::Result {
+        self.move_blocks(inodes)?;
+        if !map.is_empty() {
+            info!("Moved {} inodes", map.len());
+            let moved: BTreeSet<u32> = map.values().copied().collect();
+            self.remap_dirs(map, &moved)?;
+
+            // the superblock points at a few inodes too
+            for offset in [224, 232, 576, 580, 616, 620].iter() {
+                if let Some(new) = map.get(&le32(&self.sb, *offset)) {
+                    set32(&mut self.sb, *offset, *new);
+                }
+            }
+        }
+
+        // only now is nothing left pointing at the old places of extents
+        // that started below the limit
+        let moves = core::mem::take(&mut self.moves);
+        for (old, (_, len)) in moves.iter() {
+            for block in *old..cmp::min(old + len, self.limit) {
+                self.set_block(block, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// flags the filesystem as having errors, so it gets checked before it
+    /// is used again
+    fn mark_error(&mut self) -> uefi::Result {
+        let fs = self.fs;
+        fs.dev.read_at(1024, &mut self.sb)?;
+        let state = le16(&self.sb, 58) | STATE_ERROR;
+        set16(&mut self.sb, 58, state);
+        self.write_sb()?;
+        fs.dev.flush()
+    }
+
+    /// writes the bitmaps, group descriptors and superblock back
+    fn commit(&mut self) -> uefi::Result {
+        let fs = self.fs;
+        let bs = fs.block_size;
+        let groups = self.descs.len() as u64;
+
+        // the tail of the last group is always marked as used
+        let last = groups - 1;
+        let size = self.group_size(last);
+        let bitmap = self.block_bitmap(last)?;
+        for bit in size..bs * 8 {
+            set_bit(bitmap, bit, true);
+        }
+
+        let block_bitmaps = core::mem::take(&mut self.block_bitmaps);
+        for (group, bitmap) in block_bitmaps.iter() {
+            let free = (0..self.group_size(*group)).filter(|bit| !bit_is_set(bitmap, *bit)).count();
+            let desc = &mut self.descs[*group as usize];
+            desc.set(12, 44, 2, free as u64);
+            desc.set_flags(desc.flags() & !BG_BLOCK_UNINIT);
+            fs.set_bitmap_checksums(desc, Some(bitmap), None);
+            fs.dev.write_at(desc.block_bitmap() * bs, bitmap)?;
+        }
+        let inode_bitmaps = core::mem::take(&mut self.inode_bitmaps);
+        for (group, bitmap) in inode_bitmaps.iter() {
+            let free = (0..fs.inodes_per_group).filter(|bit| !bit_is_set(bitmap, *bit)).count();
+            let desc = &mut self.descs[*group as usize];
+            desc.set(14, 46, 2, free as u64);
+            desc.set_flags(desc.flags() & !BG_INODE_UNINIT);
+            fs.set_bitmap_checksums(desc, None, Some(bitmap));
+            fs.dev.write_at(desc.inode_bitmap() * bs, bitmap)?;
+        }
+
+        // the superblock's totals are the sum of the groups'
+        let mut free_blocks = 0;
+        let mut free_inodes = 0;
+        for (group, desc) in self.descs.iter_mut().enumerate() {
+            free_blocks += desc.free_blocks();
+            free_inodes += desc.free_inodes();
+            fs.set_desc_checksum(group as u64, desc);
+        }
+        self.set_sb_count(12, 344, free_blocks);
+        set32(&mut self.sb, 0, (groups * fs.inodes_per_group) as u32);
+        set32(&mut self.sb, 16, free_inodes as u32);
+        set16(&mut self.sb, 206, self.reserved_gdt as u16);
+        // note the kernel works the overhead out again if this is 0
+        set32(&mut self.sb, 584, 0);
+
+        self.write_descs()?;
+        self.write_sb()?;
+        fs.dev.flush()
+    }
+
+    /// writes the group descriptors to the GDT and all its backups
+    fn write_descs(&self) -> uefi::Result {
+        let fs = self.fs;
+        let per_block = fs.descs_per_block() as usize;
+        let desc_size = fs.desc_size as usize;
+        let groups = self.descs.len() as u64;
+        let backups = self.backup_groups(groups);
+
+        for (index, descs) in self.descs.chunks(per_block).enumerate() {
+            let mut block = vec![0u8; fs.block_size as usize];
+            for (i, desc) in descs.iter().enumerate() {
+                block[i * desc_size..(i + 1) * desc_size].copy_from_slice(&desc.raw);
+            }
+
+            let index = index as u64;
+            if fs.incompat & INCOMPAT_META_BG != 0 && index >= fs.first_meta_bg {
+                let first = index * per_block as u64;
+                for group in [first, first + 1, first + per_block as u64 - 1].iter() {
+                    if *group < groups {
+                        self.write_block(fs.group_start(*group) + fs.has_super(*group) as u64, &block)?;
+                    }
+                }
+            } else {
+                for group in [0].iter().chain(backups.iter()) {
+                    self.write_block(fs.group_start(*group) + 1 + index, &block)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// writes the superblock and all its backups
+    fn write_sb(&self) -> uefi::Result {
+        let fs = self.fs;
+        for group in [0].iter().copied().chain(self.backup_groups(self.descs.len() as u64)) {
+            let mut sb = self.sb.clone();
+            set16(&mut sb, 90, group as u16);
+            fs.set_sb_checksum(&mut sb);
+            let offset = match group {
+                0 => 1024,
+                group => fs.group_start(group) * fs.block_size
+            };
+            fs.dev.write_at(offset, &sb)?;
+        }
+        Ok(())
+    }
+}
+
+
+////////////////////////// FILESYSTEM IMPL //////////////////////////////
+impl<'a> Filesystem<'a> for ExtFs<'a> {
+    type File = ExtFile;
+
+    fn probe(dev: &PartitionDevice) -> bool {
+        matches!(
+            probe(dev).map(|info| info.kind),
+            Some(FsKind::Ext2) | Some(FsKind::Ext3) | Some(FsKind::Ext4)
+        )
+    }
+
+    fn mount(dev: PartitionDevice<'a>) -> uefi::Result<Self> {
+        let info = match probe(&dev) {
+            Some(info) if matches!(info.kind, FsKind::Ext2 | FsKind::Ext3 | FsKind::Ext4) => info,
+            _ => return Err(Status::UNSUPPORTED.into())
+        };
+        let mut sb = [0u8; 1024];
+        dev.read_at(1024, &mut sb)?;
+
+        // refuse anything we would read wrong
+        let incompat = le32(&sb, 96);
+        let unsupported = INCOMPAT_COMPRESSION | INCOMPAT_JOURNAL_DEV | INCOMPAT_DIRDATA | INCOMPAT_ENCRYPT;
+        if incompat & unsupported != 0 {
+            warn!("ext filesystem uses features we can't read ({:#x})", incompat & unsupported);
+            return Err(Status::UNSUPPORTED.into());
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            warn!("ext journal needs recovery, recent changes may be missing");
+        }
+        if le32(&sb, 100) & RO_COMPAT_METADATA_CSUM != 0 && le32(&sb, 1020) != crc32c_update(!0, &sb[..1020]) {
+            warn!("ext superblock checksum is wrong");
+        }
+
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let block_size = 1024u64 << log_block_size;
+
+        // 64 bit filesystems split their counts in two
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let hi = |offset: usize| match is_64bit {
+            true => (le32(&sb, offset) as u64) << 32,
+            false => 0
+        };
+        let blocks_count = le32(&sb, 4) as u64 | hi(336);
+        let free_blocks = le32(&sb, 12) as u64 | hi(344);
+
+        // revision 0 has fixed size inodes
+        let inode_size = match le32(&sb, 76) {
+            0 => 128,
+            _ => le16(&sb, 88) as u64
+        };
+        let desc_size = match (is_64bit, le16(&sb, 254)) {
+            (true, size) if size >= 64 => size as u64,
+            _ => 32
+        };
+
+        // each group's bitmaps are a single block
+        let blocks_per_group = le32(&sb, 32) as u64;
+        let inodes_per_group = le32(&sb, 40) as u64;
+        let first_data_block = le32(&sb, 20) as u64;
+        if blocks_per_group == 0 || blocks_per_group > block_size * 8
+            || inodes_per_group == 0 || inodes_per_group > block_size * 8
+            || first_data_block >= blocks_count || inode_size < 128
+            || inode_size > block_size || desc_size > block_size {
+            error!("ext superblock is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        if blocks_count.checked_mul(block_size).map_or(true, |size| size > dev.size()) {
+            error!("ext filesystem is bigger than its partition");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        let fs = ExtFs {
+            dev,
+            kind:               info.kind,
+            label:              info.label,
+            uuid:               info.uuid,
+            block_size,
+            blocks_count,
+            free_blocks,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            first_meta_bg:      le32(&sb, 260) as u64,
+            compat:             le32(&sb, 92),
+            incompat,
+            ro_compat:          le32(&sb, 100),
+            fs_uuid:            sb[104..120].try_into().unwrap(),
+            csum_seed:          match incompat & INCOMPAT_CSUM_SEED {
+                0 => crc32c_update(!0, &sb[104..120]),
+                _ => le32(&sb, 624)
+            }
+        };
+
+        // make sure the root directory is actually there
+        if !fs.read_inode(ROOT_INO)?.is_dir() {
+            error!("ext root directory is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        Ok(fs)
+    }
+
+    fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    fn uuid(&self) -> Option<String> {
+        self.uuid.clone()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.blocks_count * self.block_size
+    }
+
+    fn used_bytes(&mut self) -> uefi::Result<u64> {
+        // note the free count in the superblock is only updated on unmount
+        // by modern kernels, but it is the best we can do without the bitmaps
+        Ok((self.blocks_count - cmp::min(self.free_blocks, self.blocks_count)) * self.block_size)
+    }
+
+    fn used_ranges(&mut self) -> uefi::Result<Vec<Range<u64>>> {
+        // with 1 KiB blocks the boot block comes before the first group
+        let bs = self.block_size;
+        let mut ranges = vec![0..self.first_data_block * bs];
+
+        // the resize code already knows how to make up bitmaps never written
+        let mut resize = Resize::new(self)?;
+        for group in 0..self.group_count() {
+            let start = self.group_start(group);
+            let size = resize.group_size(group);
+            let bitmap = resize.block_bitmap(group)?;
+            for bit in (0..size).filter(|bit| bit_is_set(bitmap, *bit)) {
+                add_range(&mut ranges, (start + bit) * bs..(start + bit + 1) * bs);
+            }
+        }
+        Ok(ranges)
+    }
+
+    fn read_dir(&mut self, path: &str) -> uefi::Result<Vec<DirEntry>> {
+        let (_, inode) = self.lookup(path, true)?;
+        let mut entries = Vec::new();
+        for entry in self.read_dir_entries(&inode)? {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+
+            // the type is in the entry itself unless the filetype feature is off
+            let child = self.read_inode(entry.ino)?;
+            let is_dir = match entry.file_type {
+                0 => child.is_dir(),
+                file_type => file_type == 2
+            };
+            entries.push(DirEntry {
+                name:   entry.name,
+                is_dir,
+                size:   if is_dir { 0 } else { child.size() }
+            });
+        }
+        Ok(entries)
+    }
+
+    /// note a symlink at the end of the path isn't followed, opening it reads
+    /// back where it points
+    fn open(&mut self, path: &str) -> uefi::Result<ExtFile> {
+        let (_, inode) = self.lookup(path, false)?;
+        Ok(ExtFile{inode})
+    }
+
+    fn read_at(&mut self, file: &ExtFile, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        if file.inode.is_dir() {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if file.inode.is_fast_symlink() {
+            let target = &file.inode.i_block()[..file.inode.size() as usize];
+            let start = cmp::min(offset as usize, target.len());
+            let len = cmp::min(buf.len(), target.len() - start);
+            buf[..len].copy_from_slice(&target[start..start + len]);
+            return Ok(len);
+        }
+        self.read_data(&file.inode, offset, buf)
+    }
+
+    fn min_size(&mut self) -> uefi::Result<u64> {
+        self.used_bytes()
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        let resizable = self.check_resizable().is_ok();
+        FsCapabilities {
+            grow:   resizable,
+            shrink: resizable,
+            ..FsCapabilities::default()
+        }
+    }
+
+    /// note like resize2fs this needs a clean filesystem, and isn't safe
+    /// against losing power part way
+    fn resize(&mut self, num_sectors: u64) -> uefi::Result {
+        self.check_resizable()?;
+        let report = self.check()?;
+        if !report.is_clean() {
+            report.print();
+            warn!("ext filesystem isn't clean, mount it in Linux or run e2fsck on it first");
+            return Err(Status::ACCESS_DENIED.into());
+        }
+
+        // note the device's sectors needn't be the size of a block
+        let blocks = num_sectors * self.dev.blocksize() as u64 / self.block_size;
+        if blocks * self.block_size > self.dev.size() {
+            warn!("Partition has to be grown before the filesystem");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        let (blocks_count, free_blocks) = {
+            let mut resize = Resize::new(self)?;
+            let (blocks, groups) = resize.layout(blocks)?;
+            match blocks.cmp(&self.blocks_count) {
+                cmp::Ordering::Equal => return Ok(()),
+                cmp::Ordering::Greater => resize.grow(blocks, groups)?,
+                cmp::Ordering::Less => resize.shrink(blocks, groups)?
+            }
+            (resize.blocks_count(), resize.sb_count(12, 344))
+        };
+        self.blocks_count = blocks_count;
+        self.free_blocks = free_blocks;
+        Ok(())
+    }
+}
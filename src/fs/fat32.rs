@@ -1,5 +1,1811 @@
-// Includes structs and APIs for handling FAT32 partitions
-// note, this could simply be a wrapper around the UEFI SimpleFileSystem protocol
+// Includes structs and APIs for handling FAT32 partitions (and FAT12/16 too)
+// note, this could simply be a wrapper around the UEFI SimpleFileSystem protocol,
+// but the firmware only mounts the partitions it cares about, so we read it ourselves
 
 // notes on how it works
-// https://wiki.osdev.org/FAT
\ No newline at end of file
+// https://wiki.osdev.org/FAT
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::cmp;
+use core::convert::TryInto;
+use core::ops::Range;
+use uefi::Status;
+
+use super::{
+    add_range,
+    DirEntry,
+    Filesystem,
+    FsCapabilities,
+    PartitionDevice
+};
+use super::probe::{
+    probe,
+    FsKind
+};
+
+/// the size of the window of the FAT we keep cached
+const FAT_CACHE_BYTES: u64 = 4096;
+
+/// the size of the chunks whole FATs are rewritten in when resizing
+const FAT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// the size of the chunks data is moved in when changing the cluster size
+const MOVE_CHUNK_BYTES: u64 = 1024 * 1024;
+
+/// the biggest cluster size we will pick, anything past 32 KiB upsets some
+/// older drivers
+const MAX_CLUSTER_BYTES: u64 = 32 * 1024;
+
+/// defines the directory entry attributes we care about
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LFN: u8 = 0x0f;
+
+/// the FSInfo signatures
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+
+/// the characters that can't appear in an 8.3 name
+const SHORT_NAME_INVALID: &[u8] = b"\"*+,./:;<=>?[\\]|";
+
+/// defines the FAT variants, which differ in how big a FAT entry is
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32
+}
+
+/// defines a mounted FAT filesystem
+pub struct FatFs<'a> {
+    dev:                    PartitionDevice<'a>,
+    fat_type:               FatType,
+    bytes_per_sector:       u64,
+    sectors_per_cluster:    u64,
+    reserved_sectors:       u64,
+    num_fats:               u64,
+    fat_sectors:            u64, // size of a single FAT
+    root_entries:           u64, // FAT12/16 only, FAT32 keeps its root in a cluster chain
+    root_cluster:           u32,
+    total_sectors:          u64,
+    cluster_count:          u32,
+    serial:                 u32,
+    label:                  Option<String>,
+    active_fat:             Option<u64>, // set if FAT32 mirroring is turned off
+    fsinfo_sector:          Option<u64>, // FAT32 only, and only if it is valid
+    free_count:             Option<u32>, // as kept in FSInfo, None if unknown
+    next_free:              u32, // where to start looking for a free cluster
+    fat_cache:              (u64, Vec<u8>) // byte offset into the FAT, and its contents
+}
+
+/// defines an open file (or directory)
+pub struct FatFile {
+    entry_offset:   u64, // byte offset of the file's directory entry
+    first_cluster:  u32,
+    size:           u64,
+    is_dir:         bool,
+    cursor:         Cell<(u64, u32)> // the last cluster index we read, and its cluster
+}
+
+/// defines a raw directory entry, with its long file name already put together
+#[derive(Clone,Debug)]
+struct FatDirEntry {
+    name:           String,
+    short_name:     [u8; 11],
+    attr:           u8,
+    first_cluster:  u32,
+    size:           u64,
+    slots:          Vec<u64> // byte offsets of the LFN entries and then the entry itself
+}
+
+/// defines the cluster chain of a file or directory, when changing the cluster size
+#[derive(Clone,Debug)]
+struct Chain {
+    clusters:   Vec<u32>,
+    keep:       u64, // bytes worth keeping, the rest of a file's last cluster is slack
+    is_dir:     bool
+}
+
+
+/// helper function to read little endian integers out of a buffer
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// turns an 8.3 name into a string, honouring the lower case flags NT uses
+fn short_name(entry: &[u8]) -> String {
+    let mut name = String::new();
+    let case = entry[12];
+    for (i, c) in entry[0..11].iter().enumerate() {
+        // note 0x05 stands in for a leading 0xe5, which would mean "deleted"
+        let mut c = if i == 0 && *c == 0x05 { 0xe5 } else { *c };
+        if c == b' ' {
+            continue;
+        }
+        if i == 8 {
+            name.push('.');
+        }
+        if (i < 8 && case & 0x08 != 0) || (i >= 8 && case & 0x10 != 0) {
+            c = c.to_ascii_lowercase();
+        }
+        name.push(c as char);
+    }
+    // an extension without a base means the loop above never added the dot
+    if entry[0..8].iter().all(|c| *c == b' ') && entry[8] != b' ' {
+        name.insert(0, '.');
+    }
+    name
+}
+
+/// calculates the checksum of an 8.3 name that the LFN entries carry
+fn lfn_checksum(name: &[u8]) -> u8 {
+    name.iter().fold(0u8, |sum, c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+/// parses a directory's raw contents into entries, putting long names together
+/// note `offsets` holds the byte offset on the device of every 32 byte slot
+fn parse_dir(data: &[u8], offsets: &[u64]) -> Vec<FatDirEntry> {
+    let mut entries = Vec::new();
+    let mut lfn: Vec<u16> = Vec::new();
+    let mut lfn_checksum_seen: Option<u8> = None;
+    let mut slots: Vec<u64> = Vec::new();
+
+    for (raw, offset) in data.chunks_exact(32).zip(offsets.iter()) {
+        match raw[0] {
+            0x00 => break,
+            0xe5 => {
+                lfn.clear();
+                slots.clear();
+                continue;
+            },
+            _ => ()
+        }
+        slots.push(*offset);
+
+        // long name entries come last part first, each holding 13 UTF-16 chars
+        if raw[11] == ATTR_LFN {
+            let mut part: Vec<u16> = Vec::new();
+            for range in [1..11, 14..26, 28..32].iter() {
+                for c in raw[range.clone()].chunks_exact(2) {
+                    part.push(u16::from_le_bytes([c[0], c[1]]));
+                }
+            }
+            if raw[0] & 0x40 != 0 {
+                lfn.clear();
+                slots.clear();
+                slots.push(*offset);
+            }
+            part.extend_from_slice(&lfn);
+            lfn = part;
+            lfn_checksum_seen = Some(raw[13]);
+            continue;
+        }
+
+        // only use the long name if it actually belongs to this entry
+        let name = match lfn_checksum_seen {
+            Some(sum) if !lfn.is_empty() && sum == lfn_checksum(&raw[0..11]) => {
+                let chars = lfn.iter().cloned().take_while(|c| *c != 0 && *c != 0xffff);
+                core::char::decode_utf16(chars)
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect()
+            },
+            _ => {
+                // an orphaned long name isn't part of this entry
+                slots.clear();
+                slots.push(*offset);
+                short_name(raw)
+            }
+        };
+        lfn.clear();
+        lfn_checksum_seen = None;
+
+        entries.push(FatDirEntry {
+            name,
+            short_name:     raw[0..11].try_into().unwrap(),
+            attr:           raw[11],
+            first_cluster:  (le16(raw, 20) as u32) << 16 | le16(raw, 26) as u32,
+            size:           le32(raw, 28) as u64,
+            slots:          core::mem::take(&mut slots)
+        });
+    }
+    entries
+}
+
+/// checks to see if a name is a valid 8.3 name as it is, so needs no long name
+fn is_short_name(name: &str) -> bool {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, "")
+    };
+    !base.is_empty() && base.len() <= 8 && ext.len() <= 3
+        && name.bytes().all(|c| c.is_ascii_graphic() && !c.is_ascii_lowercase())
+        && !base.bytes().chain(ext.bytes()).any(|c| SHORT_NAME_INVALID.contains(&c))
+}
+
+/// turns a name into its 8.3 form, with a `~n` tail if `tail` is not zero
+fn make_short_name(name: &str, tail: u32) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, "")
+    };
+
+    // anything that can't go in a short name becomes an underscore
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| match c.is_ascii() && !SHORT_NAME_INVALID.contains(&(c as u8)) && c > ' ' {
+                true => c.to_ascii_uppercase() as u8,
+                false => b'_'
+            })
+            .collect()
+    };
+    let mut base = clean(base);
+    let ext = clean(ext);
+
+    if tail != 0 {
+        let tail = format!("~{}", tail);
+        base.truncate(8 - tail.len());
+        base.extend_from_slice(tail.as_bytes());
+    }
+    for (i, c) in base.iter().take(8).enumerate() {
+        short[i] = *c;
+    }
+    for (i, c) in ext.iter().take(3).enumerate() {
+        short[8 + i] = *c;
+    }
+    // a leading 0xe5 would look deleted
+    if short[0] == 0xe5 {
+        short[0] = 0x05;
+    }
+    short
+}
+
+/// builds the directory slots for a new entry, long name parts first
+fn build_entry(name: &str, short: [u8; 11], attr: u8, first_cluster: u32, size: u32) -> Vec<[u8; 32]> {
+    let mut slots: Vec<[u8; 32]> = Vec::new();
+
+    if !is_short_name(name) {
+        // the name is NUL terminated (if it fits) then padded with 0xffff
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        let count = (chars.len() + 12) / 13;
+        if chars.len() % 13 != 0 {
+            chars.push(0);
+        }
+        chars.resize(count * 13, 0xffff);
+
+        let checksum = lfn_checksum(&short);
+        for seq in (1..=count).rev() {
+            let mut slot = [0u8; 32];
+            slot[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+            slot[11] = ATTR_LFN;
+            slot[13] = checksum;
+            let part = &chars[(seq - 1) * 13..seq * 13];
+            let at = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+            for (c, offset) in part.iter().zip(at.iter()) {
+                slot[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            slots.push(slot);
+        }
+    }
+
+    slots.push(short_entry(short, attr, first_cluster, size));
+    slots
+}
+
+/// builds a plain 8.3 directory entry
+fn short_entry(short: [u8; 11], attr: u8, first_cluster: u32, size: u32) -> [u8; 32] {
+    // note we have no clock here, so the timestamps are left at 1980-01-01
+    let mut slot = [0u8; 32];
+    slot[0..11].copy_from_slice(&short);
+    slot[11] = attr;
+    slot[16..18].copy_from_slice(&0x0021u16.to_le_bytes());
+    slot[18..20].copy_from_slice(&0x0021u16.to_le_bytes());
+    slot[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    slot[24..26].copy_from_slice(&0x0021u16.to_le_bytes());
+    slot[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    slot[28..32].copy_from_slice(&size.to_le_bytes());
+    slot
+}
+
+/// splits a path into its parent directory and final name
+fn split_path(path: &str) -> uefi::Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path)
+    };
+    if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > 255 {
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+    Ok((parent, name))
+}
+
+
+////////////////////////// FATFS IMPL //////////////////////////////
+impl<'a> FatFs<'a> {
+    /// returns which FAT variant this is
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// returns the size of a cluster in bytes
+    pub fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    /// returns the number of data clusters
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    /// returns the byte offset of the fixed FAT12/16 root directory
+    fn root_dir_offset(&self) -> u64 {
+        (self.reserved_sectors + self.num_fats * self.fat_sectors) * self.bytes_per_sector
+    }
+
+    /// returns the number of sectors taken up by the fixed FAT12/16 root directory
+    fn root_dir_sectors(&self) -> u64 {
+        (self.root_entries * 32 + self.bytes_per_sector - 1) / self.bytes_per_sector
+    }
+
+    /// returns the byte offset of the first data cluster (cluster 2)
+    fn data_offset(&self) -> u64 {
+        self.root_dir_offset() + self.root_dir_sectors() * self.bytes_per_sector
+    }
+
+    /// returns the byte offset of a data cluster
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset() + (cluster as u64 - 2) * self.cluster_bytes()
+    }
+
+    /// checks to see if a cluster number points at a data cluster
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    /// returns the byte offset of the `index`th copy of the FAT
+    fn fat_offset(&self, index: u64) -> u64 {
+        (self.reserved_sectors + index * self.fat_sectors) * self.bytes_per_sector
+    }
+
+    /// reads a few bytes of the (active) FAT, going through the cache
+    fn read_fat(&mut self, offset: u64, len: usize) -> uefi::Result<[u8; 4]> {
+        let (start, ref data) = self.fat_cache;
+        if offset < start || offset + len as u64 > start + data.len() as u64 {
+            // note the window overlaps the next one slightly, so FAT12 entries
+            // straddling a boundary are still in one piece
+            let start = offset - offset % FAT_CACHE_BYTES;
+            let fat_bytes = self.fat_sectors * self.bytes_per_sector;
+            let window = cmp::min(FAT_CACHE_BYTES + 4, fat_bytes - start);
+            let mut data = vec![0u8; window as usize];
+            self.dev.read_at(self.fat_offset(self.active_fat.unwrap_or(0)) + start, &mut data)?;
+            self.fat_cache = (start, data);
+        }
+
+        let (start, ref data) = self.fat_cache;
+        let mut entry = [0u8; 4];
+        let at = (offset - start) as usize;
+        entry[..len].copy_from_slice(&data[at..at + len]);
+        Ok(entry)
+    }
+
+    /// returns the FAT entry of `cluster`, i.e. the next cluster in its chain
+    pub fn fat_entry(&mut self, cluster: u32) -> uefi::Result<u32> {
+        let cluster = cluster as u64;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let raw = self.read_fat(cluster + cluster / 2, 2)?;
+                let value = u16::from_le_bytes([raw[0], raw[1]]) as u32;
+                Ok(if cluster & 1 == 1 { value >> 4 } else { value & 0xfff })
+            },
+            FatType::Fat16 => {
+                let raw = self.read_fat(cluster * 2, 2)?;
+                Ok(u16::from_le_bytes([raw[0], raw[1]]) as u32)
+            },
+            FatType::Fat32 => {
+                // note the top 4 bits are reserved
+                let raw = self.read_fat(cluster * 4, 4)?;
+                Ok(u32::from_le_bytes(raw) & 0x0fff_ffff)
+            }
+        }
+    }
+
+    /// sets the FAT entry of `cluster` in every copy of the FAT
+    pub fn set_fat_entry(&mut self, cluster: u32, value: u32) -> uefi::Result {
+        let cluster = cluster as u64;
+        let (offset, bytes, len) = match self.fat_type {
+            FatType::Fat12 => {
+                // FAT12 entries share a byte with their neighbour
+                let offset = cluster + cluster / 2;
+                let raw = self.read_fat(offset, 2)?;
+                let old = u16::from_le_bytes([raw[0], raw[1]]);
+                let new = match cluster & 1 {
+                    1 => (old & 0x000f) | ((value as u16) << 4),
+                    _ => (old & 0xf000) | (value as u16 & 0x0fff)
+                };
+                (offset, (new as u32).to_le_bytes(), 2)
+            },
+            FatType::Fat16 => (cluster * 2, value.to_le_bytes(), 2),
+            FatType::Fat32 => {
+                // keep the reserved top 4 bits as they were
+                let raw = u32::from_le_bytes(self.read_fat(cluster * 4, 4)?);
+                (cluster * 4, ((raw & 0xf000_0000) | (value & 0x0fff_ffff)).to_le_bytes(), 4)
+            }
+        };
+
+        let copies = match self.active_fat {
+            Some(active) => active..active + 1,
+            None => 0..self.num_fats
+        };
+        for index in copies {
+            self.dev.write_at(self.fat_offset(index) + offset, &bytes[..len])?;
+        }
+
+        // keep the cache in step with the disk
+        let (start, ref mut data) = self.fat_cache;
+        if offset >= start && offset + len as u64 <= start + data.len() as u64 {
+            let at = (offset - start) as usize;
+            data[at..at + len].copy_from_slice(&bytes[..len]);
+        }
+        Ok(())
+    }
+
+    /// returns the end of chain marker we write
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff
+        }
+    }
+
+    /// checks to see if a FAT entry marks the end of a chain
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12 => entry >= 0xff8,
+            FatType::Fat16 => entry >= 0xfff8,
+            FatType::Fat32 => entry >= 0x0fff_fff8
+        }
+    }
+
+    /// returns the cluster after `cluster` in its chain, None at the end
+    fn next_cluster(&mut self, cluster: u32) -> uefi::Result<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+        if self.is_end_of_chain(next) {
+            return Ok(None);
+        }
+        if !self.is_data_cluster(next) {
+            error!("Cluster {} points at invalid cluster {}", cluster, next);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        Ok(Some(next))
+    }
+
+    /// returns every cluster in the chain starting at `cluster`
+    fn chain(&mut self, cluster: u32) -> uefi::Result<Vec<u32>> {
+        if !self.is_data_cluster(cluster) {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let mut clusters: Vec<u32> = Vec::new();
+        let mut current = Some(cluster);
+
+        // a looping chain can never be longer than the number of clusters
+        while let Some(cluster) = current {
+            if clusters.len() > self.cluster_count as usize {
+                error!("Cluster chain starting at {} loops", clusters[0]);
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            clusters.push(cluster);
+            current = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// reads the raw contents of the directory starting at `cluster` (0 meaning
+    /// the root), along with the byte offset of every 32 byte slot in it
+    fn read_dir_raw(&mut self, cluster: u32) -> uefi::Result<(Vec<u8>, Vec<u64>)> {
+        let cluster = match (cluster, self.fat_type) {
+            (0, FatType::Fat32) => self.root_cluster,
+            (0, _) => {
+                // the FAT12/16 root is a fixed region right before the data area
+                let mut data = vec![0u8; (self.root_entries * 32) as usize];
+                self.dev.read_at(self.root_dir_offset(), &mut data)?;
+                let offsets = (0..self.root_entries).map(|i| self.root_dir_offset() + i * 32).collect();
+                return Ok((data, offsets));
+            },
+            (cluster, _) => cluster
+        };
+
+        let cluster_bytes = self.cluster_bytes();
+        let clusters = self.chain(cluster)?;
+        let mut data = vec![0u8; clusters.len() * cluster_bytes as usize];
+        let mut offsets: Vec<u64> = Vec::new();
+        for (i, cluster) in clusters.iter().enumerate() {
+            let base = self.cluster_offset(*cluster);
+            let at = i * cluster_bytes as usize;
+            self.dev.read_at(base, &mut data[at..at + cluster_bytes as usize])?;
+            offsets.extend((0..cluster_bytes / 32).map(|slot| base + slot * 32));
+        }
+        Ok((data, offsets))
+    }
+
+    /// reads and parses the directory starting at `cluster`, 0 meaning the root
+    fn read_dir_entries(&mut self, cluster: u32) -> uefi::Result<Vec<FatDirEntry>> {
+        let (data, offsets) = self.read_dir_raw(cluster)?;
+        Ok(parse_dir(&data, &offsets))
+    }
+
+    /// finds the entry at `path`, None meaning the root directory
+    fn lookup(&mut self, path: &str) -> uefi::Result<Option<FatDirEntry>> {
+        let mut current: Option<FatDirEntry> = None;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            let dir_cluster = match &current {
+                None => 0,
+                Some(entry) if entry.attr & ATTR_DIRECTORY != 0 => entry.first_cluster,
+                Some(_) => return Err(Status::NOT_FOUND.into())
+            };
+
+            // FAT names are case insensitive
+            let wanted = part.to_uppercase();
+            current = self.read_dir_entries(dir_cluster)?
+                          .into_iter()
+                          .filter(|e| e.attr & ATTR_VOLUME_ID == 0)
+                          .find(|e| e.name.to_uppercase() == wanted);
+            if current.is_none() {
+                return Err(Status::NOT_FOUND.into());
+            }
+        }
+        Ok(current)
+    }
+
+    /// returns the first cluster of the directory at `path`, 0 meaning the root
+    fn dir_cluster(&mut self, path: &str) -> uefi::Result<u32> {
+        match self.lookup(path)? {
+            None => Ok(0),
+            Some(entry) if entry.attr & ATTR_DIRECTORY != 0 => Ok(entry.first_cluster),
+            Some(_) => Err(Status::INVALID_PARAMETER.into())
+        }
+    }
+
+    /// checks to see if we are able to write to the filesystem
+    /// note only FAT32 is written to, FAT12/16 are read only for now
+    fn is_writable(&self) -> bool {
+        self.fat_type == FatType::Fat32 && !self.dev.is_read_only()
+    }
+
+    /// fails unless we are able to write to the filesystem
+    fn check_writable(&self) -> uefi::Result {
+        if self.dev.is_read_only() {
+            return Err(Status::WRITE_PROTECTED.into());
+        }
+        if !self.is_writable() {
+            return Err(Status::UNSUPPORTED.into());
+        }
+        Ok(())
+    }
+
+    /// finds a free cluster, marks it as the end of a chain and links it after `prev`
+    fn alloc_cluster(&mut self, prev: Option<u32>) -> uefi::Result<u32> {
+        let count = self.cluster_count;
+        let start = match self.is_data_cluster(self.next_free) {
+            true => self.next_free,
+            false => 2
+        };
+
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat_entry(cluster, self.end_of_chain())?;
+            if let Some(prev) = prev {
+                self.set_fat_entry(prev, cluster)?;
+            }
+            self.next_free = cluster + 1;
+            self.free_count = self.free_count.map(|free| free.saturating_sub(1));
+            return Ok(cluster);
+        }
+        warn!("FAT filesystem is full");
+        Err(Status::VOLUME_FULL.into())
+    }
+
+    /// frees every cluster in the chain starting at `cluster`
+    fn free_chain(&mut self, cluster: u32) -> uefi::Result {
+        for cluster in self.chain(cluster)? {
+            self.set_fat_entry(cluster, 0)?;
+            self.free_count = self.free_count.map(|free| free + 1);
+        }
+        Ok(())
+    }
+
+    /// fills a cluster with zeroes, which new directories need
+    fn zero_cluster(&mut self, cluster: u32) -> uefi::Result {
+        let zeroes = vec![0u8; self.cluster_bytes() as usize];
+        self.dev.write_at(self.cluster_offset(cluster), &zeroes)
+    }
+
+    /// writes the free cluster count and hint back to FSInfo, then flushes
+    fn sync(&mut self) -> uefi::Result {
+        if let Some(sector) = self.fsinfo_sector {
+            let mut info = [0u8; 8];
+            info[0..4].copy_from_slice(&self.free_count.unwrap_or(0xffff_ffff).to_le_bytes());
+            info[4..8].copy_from_slice(&self.next_free.to_le_bytes());
+            self.dev.write_at(sector * self.bytes_per_sector + 488, &info)?;
+        }
+        self.dev.flush()
+    }
+
+    /// writes the directory slots of a new entry into the directory starting
+    /// at `dir_cluster`, growing it if needed
+    /// 
+    /// Returns the byte offset of the entry itself (the last slot)
+    fn add_entry(&mut self, dir_cluster: u32, slots: &[[u8; 32]]) -> uefi::Result<u64> {
+        let (data, mut offsets) = self.read_dir_raw(dir_cluster)?;
+
+        // find a run of free slots big enough, anything after the end marker is free
+        let mut run = 0;
+        let mut found = None;
+        for (i, raw) in data.chunks_exact(32).enumerate() {
+            if raw[0] != 0x00 && raw[0] != 0xe5 {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == slots.len() {
+                found = Some(i + 1 - run);
+                break;
+            }
+        }
+
+        let start = match found {
+            Some(start) => start,
+            None => {
+                // the FAT12/16 root is a fixed size, everything else can grow
+                if dir_cluster == 0 && self.fat_type != FatType::Fat32 {
+                    warn!("Root directory is full");
+                    return Err(Status::VOLUME_FULL.into());
+                }
+                let first = match dir_cluster {
+                    0 => self.root_cluster,
+                    cluster => cluster
+                };
+
+                // note the free run at the end carries on into the new clusters
+                let start = offsets.len() - run;
+                let mut last = *self.chain(first)?.last().unwrap();
+                while offsets.len() - start < slots.len() {
+                    last = self.alloc_cluster(Some(last))?;
+                    self.zero_cluster(last)?;
+                    let base = self.cluster_offset(last);
+                    offsets.extend((0..self.cluster_bytes() / 32).map(|slot| base + slot * 32));
+                }
+                start
+            }
+        };
+
+        for (slot, offset) in slots.iter().zip(offsets[start..].iter()) {
+            self.dev.write_at(*offset, slot)?;
+        }
+        Ok(offsets[start + slots.len() - 1])
+    }
+
+    /// creates a new directory entry at `path`
+    /// 
+    /// Returns the byte offset of the entry
+    fn create_entry(&mut self, path: &str, attr: u8, first_cluster: u32) -> uefi::Result<u64> {
+        let (parent, name) = split_path(path)?;
+        let dir_cluster = self.dir_cluster(parent)?;
+        let entries = self.read_dir_entries(dir_cluster)?;
+
+        let wanted = name.to_uppercase();
+        if entries.iter().any(|e| e.attr & ATTR_VOLUME_ID == 0 && e.name.to_uppercase() == wanted) {
+            return Err(Status::ACCESS_DENIED.into());
+        }
+
+        // pick a short name nothing else in the directory uses
+        let short = match is_short_name(name) {
+            true => make_short_name(name, 0),
+            false => match (1..1_000_000).map(|tail| make_short_name(name, tail))
+                                         .find(|s| !entries.iter().any(|e| e.short_name == *s)) {
+                Some(short) => short,
+                None => return Err(Status::VOLUME_FULL.into())
+            }
+        };
+        if entries.iter().any(|e| e.short_name == short) {
+            return Err(Status::ACCESS_DENIED.into());
+        }
+
+        self.add_entry(dir_cluster, &build_entry(name, short, attr, first_cluster, 0))
+    }
+
+    /// writes the first cluster and size of an open file back to its entry
+    fn update_entry(&mut self, file: &FatFile) -> uefi::Result {
+        let mut raw = [0u8; 32];
+        self.dev.read_at(file.entry_offset, &mut raw)?;
+        raw[20..22].copy_from_slice(&((file.first_cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(file.first_cluster as u16).to_le_bytes());
+        raw[28..32].copy_from_slice(&(file.size as u32).to_le_bytes());
+        self.dev.write_at(file.entry_offset, &raw)
+    }
+
+    /// returns the size of a FAT entry in bytes, FAT12 can't be resized
+    fn entry_bytes(&self) -> u64 {
+        match self.fat_type {
+            FatType::Fat32 => 4,
+            _ => 2
+        }
+    }
+
+    /// returns the fewest and most clusters the FAT type allows, outside of
+    /// that it would be taken for another FAT type
+    fn cluster_limits(&self) -> (u64, u64) {
+        match self.fat_type {
+            FatType::Fat12 => (1, 4084),
+            FatType::Fat16 => (4085, 65524),
+            FatType::Fat32 => (65525, 0x0fff_fff5)
+        }
+    }
+
+    /// returns the number of data clusters `total_sectors` would hold with
+    /// FATs of `fat_sectors` each
+    fn clusters_for(&self, total_sectors: u64, fat_sectors: u64) -> u64 {
+        let meta = self.reserved_sectors + self.num_fats * fat_sectors + self.root_dir_sectors();
+        total_sectors.saturating_sub(meta) / self.sectors_per_cluster
+    }
+
+    /// checks to see if we are able to resize the filesystem
+    fn is_resizable(&self) -> bool {
+        self.fat_type != FatType::Fat12 && !self.dev.is_read_only()
+    }
+
+    /// checks to see if a FAT entry marks a bad cluster
+    fn is_bad(&self, entry: u32) -> bool {
+        entry == self.end_of_chain() - 8
+    }
+
+    /// zeroes the entries of clusters `clusters` in every copy of the FAT
+    fn clear_fat(&mut self, clusters: Range<u32>) -> uefi::Result {
+        let width = self.entry_bytes();
+        let start = clusters.start as u64 * width;
+        let end = clusters.end as u64 * width;
+        let zeroes = vec![0u8; FAT_CHUNK_BYTES];
+        for index in 0..self.num_fats {
+            let mut offset = start;
+            while offset < end {
+                let len = cmp::min(FAT_CHUNK_BYTES as u64, end - offset) as usize;
+                self.dev.write_at(self.fat_offset(index) + offset, &zeroes[..len])?;
+                offset += len as u64;
+            }
+        }
+        self.fat_cache = (0, Vec::new());
+        Ok(())
+    }
+
+    /// points the directory entry at byte `offset` at a new first cluster
+    fn set_entry_cluster(&mut self, offset: u64, cluster: u32) -> uefi::Result {
+        let mut raw = [0u8; 32];
+        self.dev.read_at(offset, &mut raw)?;
+        raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        self.dev.write_at(offset, &raw)
+    }
+
+    /// renumbers the first cluster of every entry in the directory tree under
+    /// `dir_cluster`, the FAT must already use the new numbers
+    fn remap_tree(&mut self, dir_cluster: u32, remap: &dyn Fn(u32) -> Option<u32>) -> uefi::Result {
+        for entry in self.read_dir_entries(dir_cluster)? {
+            let mut cluster = entry.first_cluster;
+            if let Some(new) = remap(cluster) {
+                self.set_entry_cluster(*entry.slots.last().unwrap(), new)?;
+                cluster = new;
+            }
+
+            // note "." and ".." point at directories we visit anyway
+            let is_dir = entry.attr & (ATTR_DIRECTORY | ATTR_VOLUME_ID) == ATTR_DIRECTORY;
+            if is_dir && cluster != 0 && entry.name != "." && entry.name != ".." {
+                self.remap_tree(cluster, remap)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// moves every used cluster in `doomed` into free clusters below `limit`,
+    /// fixing up the FAT chains and directory entries that point at them
+    fn move_clusters(&mut self, doomed: Range<u32>, limit: u32) -> uefi::Result {
+        let mut map: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut free = 2;
+        for cluster in doomed.clone() {
+            let entry = self.fat_entry(cluster)?;
+            if entry == 0 {
+                continue;
+            }
+            if self.is_bad(entry) {
+                // there is nothing worth keeping in a bad cluster
+                continue;
+            }
+            loop {
+                if free >= limit {
+                    warn!("Not enough free clusters left to move the data into");
+                    return Err(Status::VOLUME_FULL.into());
+                }
+                if !doomed.contains(&free) && self.fat_entry(free)? == 0 {
+                    break;
+                }
+                free += 1;
+            }
+            map.insert(cluster, free);
+            free += 1;
+        }
+        if map.is_empty() {
+            return Ok(());
+        }
+        info!("Moving {} clusters", map.len());
+
+        // copy the data first, the old clusters stay valid until the FAT changes
+        let mut buf = vec![0u8; self.cluster_bytes() as usize];
+        for (old, new) in map.iter() {
+            self.dev.read_at(self.cluster_offset(*old), &mut buf)?;
+            self.dev.write_at(self.cluster_offset(*new), &buf)?;
+        }
+
+        // then the chains, both the moved clusters and whatever led into them
+        for (old, new) in map.iter() {
+            let entry = self.fat_entry(*old)?;
+            self.set_fat_entry(*new, *map.get(&entry).unwrap_or(&entry))?;
+        }
+        for cluster in 2..self.cluster_count + 2 {
+            if map.contains_key(&cluster) {
+                continue;
+            }
+            let entry = self.fat_entry(cluster)?;
+            if let Some(new) = map.get(&entry) {
+                self.set_fat_entry(cluster, *new)?;
+            }
+        }
+        for old in map.keys() {
+            self.set_fat_entry(*old, 0)?;
+        }
+
+        // and finally the directory entries
+        if let Some(new) = map.get(&self.root_cluster) {
+            self.root_cluster = *new;
+        }
+        self.remap_tree(0, &|cluster| map.get(&cluster).copied())
+    }
+
+    /// grows every FAT by `shift` clusters worth of sectors in total, which
+    /// takes the first `shift` clusters away from the data area
+    /// 
+    /// Rather than move all the data up, every cluster is renumbered, as
+    /// cluster n + `shift` now sits where cluster n would be
+    fn grow_fats(&mut self, shift: u32, fat_sectors: u64, cluster_count: u32) -> uefi::Result {
+        if self.active_fat.is_some() {
+            warn!("FAT mirroring is turned off, refusing to grow the FATs");
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let old_count = self.cluster_count;
+        self.move_clusters(2..2 + shift, old_count + 2)?;
+
+        // the FAT12/16 root directory gets pushed along by the FATs
+        let mut root = vec![0u8; (self.root_dir_sectors() * self.bytes_per_sector) as usize];
+        self.dev.read_at(self.root_dir_offset(), &mut root)?;
+
+        // rewrite the first FAT in place, note we always read ahead of what
+        // we write so nothing is overwritten before it is read
+        let width = self.entry_bytes();
+        let entries = fat_sectors * self.bytes_per_sector / width;
+        let base = self.fat_offset(0);
+        let mut chunk: Vec<u8> = Vec::with_capacity(FAT_CHUNK_BYTES);
+        let mut written = 0;
+        for i in 0..entries {
+            let value = match i as u32 {
+                // the media byte and clean shutdown flags are kept as they are
+                i if i < 2 => u32::from_le_bytes(self.read_fat(i as u64 * width, width as usize)?),
+                i if i < cluster_count + 2 && i + shift < old_count + 2 => {
+                    match self.fat_entry(i + shift)? {
+                        entry if entry >= 2 && entry < old_count + 2 => entry - shift,
+                        entry => entry
+                    }
+                },
+                _ => 0
+            };
+            chunk.extend_from_slice(&value.to_le_bytes()[..width as usize]);
+            if chunk.len() >= FAT_CHUNK_BYTES {
+                self.dev.write_at(base + written, &chunk)?;
+                written += chunk.len() as u64;
+                chunk.clear();
+            }
+        }
+        self.dev.write_at(base + written, &chunk)?;
+
+        // from here on the new layout is in use
+        self.fat_sectors = fat_sectors;
+        self.cluster_count = cluster_count;
+        self.fat_cache = (0, Vec::new());
+        if self.fat_type == FatType::Fat32 {
+            self.root_cluster -= shift;
+        }
+
+        // mirror the new FAT into the other copies
+        let fat_bytes = fat_sectors * self.bytes_per_sector;
+        let mut buf = vec![0u8; FAT_CHUNK_BYTES];
+        let mut offset = 0;
+        while offset < fat_bytes {
+            let len = cmp::min(FAT_CHUNK_BYTES as u64, fat_bytes - offset) as usize;
+            self.dev.read_at(self.fat_offset(0) + offset, &mut buf[..len])?;
+            for index in 1..self.num_fats {
+                self.dev.write_at(self.fat_offset(index) + offset, &buf[..len])?;
+            }
+            offset += len as u64;
+        }
+
+        self.dev.write_at(self.root_dir_offset(), &root)?;
+        self.remap_tree(0, &|cluster| match cluster >= 2 + shift {
+            true => Some(cluster - shift),
+            false => None
+        })
+    }
+
+    /// works out the size of each FAT and the number of clusters `total_sectors`
+    /// would have with clusters of `spc` sectors
+    fn layout_for(&self, total_sectors: u64, spc: u64) -> (u64, u64) {
+        let bps = self.bytes_per_sector;
+        let width = self.entry_bytes();
+        let data = total_sectors.saturating_sub(self.reserved_sectors + self.root_dir_sectors());
+
+        // start too small and grow the FATs until they cover every cluster,
+        // which takes a couple of rounds at most
+        let mut fat_sectors = 1;
+        loop {
+            let clusters = data.saturating_sub(self.num_fats * fat_sectors) / spc;
+            let needed = ((clusters + 2) * width + bps - 1) / bps;
+            if needed <= fat_sectors {
+                return (fat_sectors, clusters);
+            }
+            fat_sectors = needed;
+        }
+    }
+
+    /// picks the cluster size (in sectors) closest to the current one that
+    /// keeps `total_sectors` within the cluster limits of the FAT type
+    fn fitting_cluster_size(&self, total_sectors: u64) -> Option<u64> {
+        let (min_clusters, max_clusters) = self.cluster_limits();
+        let mut sizes: Vec<u64> = (0..8).map(|shift| 1u64 << shift)
+                                        .filter(|spc| spc * self.bytes_per_sector <= MAX_CLUSTER_BYTES)
+                                        .collect();
+        let current = self.sectors_per_cluster.trailing_zeros() as i32;
+        sizes.sort_by_key(|spc| (spc.trailing_zeros() as i32 - current).abs());
+        sizes.into_iter().find(|spc| {
+            let (_, clusters) = self.layout_for(total_sectors, *spc);
+            clusters >= min_clusters && clusters <= max_clusters
+        })
+    }
+
+    /// collects the cluster chain of every file and directory under `dir_cluster`
+    fn collect_chains(&mut self, dir_cluster: u32, chains: &mut Vec<(u32, Chain)>) -> uefi::Result {
+        let cluster_bytes = self.cluster_bytes();
+        for entry in self.read_dir_entries(dir_cluster)? {
+            if entry.attr & ATTR_VOLUME_ID != 0 || entry.name == "." || entry.name == ".." || entry.first_cluster == 0 {
+                continue;
+            }
+            let clusters = self.chain(entry.first_cluster)?;
+            let is_dir = entry.attr & ATTR_DIRECTORY != 0;
+            let keep = match is_dir {
+                true => clusters.len() as u64 * cluster_bytes,
+                false => entry.size
+            };
+            if keep > clusters.len() as u64 * cluster_bytes {
+                error!("{} is shorter than its directory entry says", entry.name);
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            chains.push((entry.first_cluster, Chain { clusters, keep, is_dir }));
+            if is_dir {
+                self.collect_chains(entry.first_cluster, chains)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// copies a cluster's worth of data from one cluster to another
+    fn copy_cluster(&mut self, from: u32, to: u32, buf: &mut [u8]) -> uefi::Result {
+        self.dev.read_at(self.cluster_offset(from), buf)?;
+        self.dev.write_at(self.cluster_offset(to), buf)
+    }
+
+    /// moves every chain to the front of the data area, one after the other,
+    /// returning where each now starts
+    ///
+    /// Each cluster's new home is a permutation of the old ones, so it is done
+    /// by following its paths (which end in a free cluster) from the far end,
+    /// and its cycles with a spare cluster's worth of memory
+    fn pack_chains(&mut self, chains: &[(u32, Chain)]) -> uefi::Result<Vec<u32>> {
+        let slots = self.cluster_count as usize + 2;
+        let mut dest = vec![0u32; slots];
+        let mut starts: Vec<u32> = Vec::new();
+        let mut next = 2;
+        for (_, chain) in chains.iter() {
+            starts.push(next);
+            for cluster in chain.clusters.iter() {
+                dest[*cluster as usize] = next;
+                next += 1;
+            }
+        }
+
+        let mut is_target = vec![false; slots];
+        for cluster in 2..slots {
+            if dest[cluster] != 0 {
+                is_target[dest[cluster] as usize] = true;
+            }
+        }
+        let moving = (2..slots).filter(|c| dest[*c] != 0 && dest[*c] as usize != *c).count();
+        if moving == 0 {
+            return Ok(starts);
+        }
+        info!("Moving {} clusters to the front of the volume", moving);
+
+        let mut buf = vec![0u8; self.cluster_bytes() as usize];
+        let mut done = vec![false; slots];
+        for start in 2..slots {
+            if dest[start] == 0 || dest[start] as usize == start || is_target[start] {
+                continue;
+            }
+            // a path, whose last cluster moves into one nothing is kept in
+            let mut path = vec![start];
+            let mut current = start;
+            while dest[dest[current] as usize] != 0 {
+                current = dest[current] as usize;
+                path.push(current);
+            }
+            for cluster in path.iter().rev() {
+                self.copy_cluster(*cluster as u32, dest[*cluster], &mut buf)?;
+                done[*cluster] = true;
+            }
+        }
+
+        let mut spare = vec![0u8; buf.len()];
+        for start in 2..slots {
+            if dest[start] == 0 || dest[start] as usize == start || done[start] {
+                continue;
+            }
+            let mut cycle = vec![start];
+            let mut current = dest[start] as usize;
+            while current != start {
+                cycle.push(current);
+                current = dest[current] as usize;
+            }
+
+            // the last one goes where the first one was, so it has to be kept
+            // aside while everything else shuffles along
+            let last = *cycle.last().unwrap() as u32;
+            self.dev.read_at(self.cluster_offset(last), &mut spare)?;
+            for i in (0..cycle.len() - 1).rev() {
+                self.copy_cluster(cycle[i] as u32, cycle[i + 1] as u32, &mut buf)?;
+            }
+            self.dev.write_at(self.cluster_offset(start as u32), &spare)?;
+            cycle.iter().for_each(|c| done[*c] = true);
+        }
+        self.dev.flush()?;
+        Ok(starts)
+    }
+
+    /// moves `len` bytes from `from` to `to`, the two may overlap
+    fn move_bytes(&mut self, from: u64, to: u64, len: u64, buf: &mut [u8]) -> uefi::Result {
+        let chunks = (len + MOVE_CHUNK_BYTES - 1) / MOVE_CHUNK_BYTES;
+        for i in 0..chunks {
+            // go from whichever end keeps us from overwriting what is still to be read
+            let chunk = match to < from {
+                true => i,
+                false => chunks - 1 - i
+            };
+            let offset = chunk * MOVE_CHUNK_BYTES;
+            let size = cmp::min(MOVE_CHUNK_BYTES, len - offset) as usize;
+            self.dev.read_at(from + offset, &mut buf[..size])?;
+            self.dev.write_at(to + offset, &buf[..size])?;
+        }
+        Ok(())
+    }
+
+    /// changes the cluster size to `spc` sectors, laying the data area out
+    /// again for a volume of `total_sectors`
+    ///
+    /// Every chain is first packed into the front of the data area, then each
+    /// one is moved (in order, so nothing is overwritten before it is read)
+    /// to start on a cluster of the new size. Everything is worked out before
+    /// anything is written, but there is no going back once the moves start
+    fn recluster(&mut self, total_sectors: u64, spc: u64) -> uefi::Result {
+        if self.active_fat.is_some() {
+            warn!("FAT mirroring is turned off, refusing to change the cluster size");
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let bps = self.bytes_per_sector;
+        let old_bytes = self.cluster_bytes();
+        let new_bytes = spc * bps;
+
+        // every chain in the tree, FAT32's root directory first
+        let mut chains: Vec<(u32, Chain)> = Vec::new();
+        if self.fat_type == FatType::Fat32 {
+            let clusters = self.chain(self.root_cluster)?;
+            let keep = clusters.len() as u64 * old_bytes;
+            chains.push((self.root_cluster, Chain { clusters, keep, is_dir: true }));
+        }
+        self.collect_chains(0, &mut chains)?;
+
+        // refuse cross linked chains and bad clusters, and drop lost ones
+        let mut owned = vec![false; self.cluster_count as usize + 2];
+        for (_, chain) in chains.iter() {
+            for cluster in chain.clusters.iter() {
+                if owned[*cluster as usize] {
+                    error!("Cluster {} is cross linked, run a filesystem check first", cluster);
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+                owned[*cluster as usize] = true;
+            }
+        }
+        let mut lost = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            let entry = self.fat_entry(cluster)?;
+            if self.is_bad(entry) {
+                warn!("Volume has bad clusters, refusing to change the cluster size");
+                return Err(Status::UNSUPPORTED.into());
+            }
+            if entry != 0 && !owned[cluster as usize] {
+                lost += 1;
+            }
+        }
+        if lost != 0 {
+            warn!("Dropping {} clusters no file or directory uses", lost);
+        }
+
+        // make sure the new layout works before touching anything
+        let (fat_sectors, cluster_count) = self.layout_for(total_sectors, spc);
+        let (min_clusters, max_clusters) = self.cluster_limits();
+        if cluster_count < min_clusters || cluster_count > max_clusters {
+            warn!("{} clusters of {} bytes won't fit {}", cluster_count, new_bytes, self.name());
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let lengths: Vec<u64> = chains.iter().map(|(_, c)| (c.keep + new_bytes - 1) / new_bytes).collect();
+        if lengths.iter().sum::<u64>() > cluster_count {
+            warn!("Data won't fit in {} clusters of {} bytes", cluster_count, new_bytes);
+            return Err(Status::VOLUME_FULL.into());
+        }
+        info!(
+            "Changing the cluster size of {} from {} to {} bytes",
+            self.name(),
+            old_bytes,
+            new_bytes
+        );
+
+        // the first two FAT entries and the FAT12/16 root have to survive the
+        // FATs moving about
+        let width = self.entry_bytes();
+        let reserved_entries = [
+            u32::from_le_bytes(self.read_fat(0, width as usize)?),
+            u32::from_le_bytes(self.read_fat(width, width as usize)?)
+        ];
+        let mut root = vec![0u8; (self.root_dir_sectors() * bps) as usize];
+        self.dev.read_at(self.root_dir_offset(), &mut root)?;
+
+        let packed = self.pack_chains(&chains)?;
+
+        // then move each chain to its place in the new layout, the ones going
+        // down first from the front, then the ones going up from the back
+        let old_data = self.data_offset();
+        let new_data = (self.reserved_sectors + self.num_fats * fat_sectors + self.root_dir_sectors()) * bps;
+        let mut new_starts: Vec<u32> = Vec::new();
+        let mut next = 2;
+        for len in lengths.iter() {
+            new_starts.push(next);
+            next += *len as u32;
+        }
+        let places: Vec<(u64, u64, u64)> = chains.iter()
+            .enumerate()
+            .filter(|(i, _)| lengths[*i] != 0)
+            .map(|(i, (_, chain))| (
+                old_data + (packed[i] as u64 - 2) * old_bytes,
+                new_data + (new_starts[i] as u64 - 2) * new_bytes,
+                (chain.keep + bps - 1) / bps * bps
+            ))
+            .collect();
+        let mut buf = vec![0u8; MOVE_CHUNK_BYTES as usize];
+        for (from, to, len) in places.iter().filter(|(from, to, _)| to < from) {
+            self.move_bytes(*from, *to, *len, &mut buf)?;
+        }
+        for (from, to, len) in places.iter().rev().filter(|(from, to, _)| to > from) {
+            self.move_bytes(*from, *to, *len, &mut buf)?;
+        }
+
+        // directories have to end in zeroes rather than whatever was there
+        let zeroes = vec![0u8; new_bytes as usize];
+        for (i, (_, chain)) in chains.iter().enumerate().filter(|(_, (_, c))| c.is_dir) {
+            let end = new_data + (new_starts[i] as u64 - 2) * new_bytes + chain.keep;
+            let slack = lengths[i] * new_bytes - chain.keep;
+            if slack != 0 {
+                self.dev.write_at(end, &zeroes[..slack as usize])?;
+            }
+        }
+
+        // from here on the new layout is in use
+        self.sectors_per_cluster = spc;
+        self.fat_sectors = fat_sectors;
+        self.cluster_count = cluster_count as u32;
+        self.fat_cache = (0, Vec::new());
+        if self.fat_type == FatType::Fat32 {
+            self.root_cluster = new_starts[0];
+        }
+
+        // write out the FATs, every chain is now in one piece
+        let eoc = self.end_of_chain();
+        let entries = fat_sectors * bps / width;
+        let mut chunk: Vec<u8> = Vec::with_capacity(FAT_CHUNK_BYTES);
+        let mut written = 0;
+        let mut current = 0;
+        for i in 0..entries {
+            while current < lengths.len() && new_starts[current] as u64 + lengths[current] <= i {
+                current += 1;
+            }
+            let value = match i {
+                i if i < 2 => reserved_entries[i as usize],
+                i if current < lengths.len() && i >= new_starts[current] as u64 => {
+                    match i + 1 == new_starts[current] as u64 + lengths[current] {
+                        true => eoc,
+                        false => i as u32 + 1
+                    }
+                },
+                _ => 0
+            };
+            chunk.extend_from_slice(&value.to_le_bytes()[..width as usize]);
+            if chunk.len() >= FAT_CHUNK_BYTES || i + 1 == entries {
+                for index in 0..self.num_fats {
+                    self.dev.write_at(self.fat_offset(index) + written, &chunk)?;
+                }
+                written += chunk.len() as u64;
+                chunk.clear();
+            }
+        }
+
+        // and finally the directory entries
+        self.dev.write_at(self.root_dir_offset(), &root)?;
+        let map: BTreeMap<u32, u32> = chains.iter()
+            .enumerate()
+            .map(|(i, (first, _))| (*first, if lengths[i] == 0 { 0 } else { new_starts[i] }))
+            .collect();
+        self.remap_tree(0, &|cluster| map.get(&cluster).copied())
+    }
+
+    /// resizes the filesystem to `num_sectors` device sectors, changing the
+    /// cluster size to `cluster_bytes` along the way
+    ///
+    /// With None the cluster size is only changed if the FAT type can't hold
+    /// the new number of clusters otherwise
+    fn resize_to(&mut self, num_sectors: u64, cluster_bytes: Option<u64>) -> uefi::Result {
+        if !self.is_resizable() {
+            return Err(Status::UNSUPPORTED.into());
+        }
+        // note the device's sectors needn't be the size of the filesystem's
+        let total_sectors = num_sectors * self.dev.blocksize() as u64 / self.bytes_per_sector;
+        if total_sectors * self.bytes_per_sector > self.dev.size() {
+            warn!("Partition has to be grown before the filesystem");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
+        let (min_clusters, max_clusters) = self.cluster_limits();
+        let spc = match cluster_bytes {
+            Some(bytes) => {
+                if !bytes.is_power_of_two() || bytes < self.bytes_per_sector || bytes > MAX_CLUSTER_BYTES {
+                    warn!("{} bytes isn't a cluster size we can use", bytes);
+                    return Err(Status::INVALID_PARAMETER.into());
+                }
+                bytes / self.bytes_per_sector
+            },
+            None => match self.layout_for(total_sectors, self.sectors_per_cluster) {
+                (_, clusters) if clusters >= min_clusters && clusters <= max_clusters => self.sectors_per_cluster,
+                (_, clusters) => match self.fitting_cluster_size(total_sectors) {
+                    Some(spc) => spc,
+                    None => {
+                        warn!("{} clusters won't fit {} at any cluster size", clusters, self.name());
+                        return Err(Status::INVALID_PARAMETER.into());
+                    }
+                }
+            }
+        };
+
+        if spc != self.sectors_per_cluster {
+            self.recluster(total_sectors, spc)?;
+        } else {
+            self.resize_clusters(total_sectors)?;
+        }
+        self.total_sectors = total_sectors;
+        self.write_bpb()?;
+
+        // the old free count is no good anymore
+        let free = self.cluster_count as u64 - self.used_bytes()? / self.cluster_bytes();
+        self.free_count = Some(free as u32);
+        self.next_free = 2;
+        self.sync()
+    }
+
+    /// changes the number of clusters to fit `total_sectors`, keeping the
+    /// cluster size
+    fn resize_clusters(&mut self, total_sectors: u64) -> uefi::Result {
+        // grow the FATs until they have room for every cluster, the data area
+        // has to lose whole clusters to them or every cluster would be misaligned
+        let spc = self.sectors_per_cluster;
+        let width = self.entry_bytes();
+        let mut shift = 0;
+        let mut fat_sectors = self.fat_sectors;
+        while self.clusters_for(total_sectors, fat_sectors) + 2 > fat_sectors * self.bytes_per_sector / width {
+            shift += 1;
+            while shift * spc % self.num_fats != 0 {
+                shift += 1;
+            }
+            fat_sectors = self.fat_sectors + shift * spc / self.num_fats;
+        }
+
+        let cluster_count = self.clusters_for(total_sectors, fat_sectors);
+        let (min_clusters, max_clusters) = self.cluster_limits();
+        if cluster_count < min_clusters || cluster_count > max_clusters {
+            warn!("{} clusters won't fit {}, it needs a different cluster size", cluster_count, self.name());
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let cluster_count = cluster_count as u32;
+        let old_count = self.cluster_count;
+        info!("Resizing {} from {} to {} clusters", self.name(), old_count, cluster_count);
+
+        if shift != 0 {
+            self.grow_fats(shift as u32, fat_sectors, cluster_count)
+        } else if cluster_count < old_count {
+            // empty the tail before cutting it off
+            self.move_clusters(cluster_count + 2..old_count + 2, cluster_count + 2)?;
+            self.clear_fat(cluster_count + 2..old_count + 2)?;
+            self.cluster_count = cluster_count;
+            Ok(())
+        } else {
+            // the FAT may have junk past the old end
+            self.clear_fat(old_count + 2..cluster_count + 2)?;
+            self.cluster_count = cluster_count;
+            Ok(())
+        }
+    }
+
+    /// writes the current layout back to the boot sector (and its backup)
+    fn write_bpb(&mut self) -> uefi::Result {
+        let mut bs = vec![0u8; self.bytes_per_sector as usize];
+        self.dev.read_at(0, &mut bs)?;
+
+        match (self.fat_type, self.total_sectors) {
+            (FatType::Fat32, _) | (_, 0x10000..=u64::MAX) => {
+                bs[19..21].copy_from_slice(&0u16.to_le_bytes());
+                bs[32..36].copy_from_slice(&(self.total_sectors as u32).to_le_bytes());
+            },
+            _ => {
+                bs[19..21].copy_from_slice(&(self.total_sectors as u16).to_le_bytes());
+                bs[32..36].copy_from_slice(&0u32.to_le_bytes());
+            }
+        }
+        bs[13] = self.sectors_per_cluster as u8;
+        bs[28..32].copy_from_slice(&(self.dev.extent().first_lba() as u32).to_le_bytes());
+
+        if self.fat_type == FatType::Fat32 {
+            bs[36..40].copy_from_slice(&(self.fat_sectors as u32).to_le_bytes());
+            bs[44..48].copy_from_slice(&self.root_cluster.to_le_bytes());
+            self.dev.write_at(0, &bs)?;
+
+            // FAT32 keeps a copy of the boot sector a few sectors in
+            match le16(&bs, 50) as u64 {
+                0 | 0xffff => Ok(()),
+                backup => self.dev.write_at(backup * self.bytes_per_sector, &bs)
+            }
+        } else {
+            bs[22..24].copy_from_slice(&(self.fat_sectors as u16).to_le_bytes());
+            self.dev.write_at(0, &bs)
+        }
+    }
+}
+
+
+////////////////////////// FILESYSTEM IMPL //////////////////////////////
+impl<'a> Filesystem<'a> for FatFs<'a> {
+    type File = FatFile;
+
+    fn probe(dev: &PartitionDevice) -> bool {
+        matches!(
+            probe(dev).map(|info| info.kind),
+            Some(FsKind::Fat12) | Some(FsKind::Fat16) | Some(FsKind::Fat32)
+        )
+    }
+
+    fn mount(dev: PartitionDevice<'a>) -> uefi::Result<Self> {
+        if !Self::probe(&dev) {
+            return Err(Status::UNSUPPORTED.into());
+        }
+        let mut bs = [0u8; 512];
+        dev.read_at(0, &mut bs)?;
+
+        // parse the BIOS parameter block, the probe already checked it is sane
+        let bytes_per_sector = le16(&bs, 11) as u64;
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved_sectors = le16(&bs, 14) as u64;
+        let num_fats = bs[16] as u64;
+        let root_entries = le16(&bs, 17) as u64;
+        let total_sectors = match le16(&bs, 19) {
+            0 => le32(&bs, 32) as u64,
+            total => total as u64
+        };
+        let fat_sectors = match le16(&bs, 22) {
+            0 => le32(&bs, 36) as u64,
+            size => size as u64
+        };
+        if total_sectors * bytes_per_sector > dev.size() {
+            error!("FAT filesystem is bigger than its partition");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        // the FAT type is decided purely by the number of clusters
+        let root_dir_sectors = (root_entries * 32 + bytes_per_sector - 1) / bytes_per_sector;
+        let meta = reserved_sectors + num_fats * fat_sectors + root_dir_sectors;
+        if meta >= total_sectors {
+            error!("FAT filesystem has no room left for data");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let cluster_count = ((total_sectors - meta) / sectors_per_cluster) as u32;
+        let fat_type = match cluster_count {
+            c if c < 4085 => FatType::Fat12,
+            c if c < 65525 => FatType::Fat16,
+            _ => FatType::Fat32
+        };
+
+        // every cluster needs an entry in the FAT, 1.5, 2 or 4 bytes wide
+        let entries = cluster_count as u64 + 2;
+        let fat_needed = match fat_type {
+            FatType::Fat12 => (entries * 3 + 1) / 2,
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4
+        };
+        if fat_sectors * bytes_per_sector < fat_needed {
+            error!("FAT is too small for the {} clusters of the filesystem", cluster_count);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let ext = match fat_type {
+            FatType::Fat32 => 64,
+            _ => 36
+        };
+
+        let mut fs = FatFs {
+            dev,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_sectors,
+            root_entries,
+            root_cluster:   le32(&bs, 44),
+            total_sectors,
+            cluster_count,
+            serial:         le32(&bs, ext + 3),
+            label:          None,
+            active_fat:     None,
+            fsinfo_sector:  None,
+            free_count:     None,
+            next_free:      2,
+            fat_cache:      (0, Vec::new())
+        };
+
+        // FAT32 can turn off mirroring, and keeps a free cluster count in FSInfo
+        if fat_type == FatType::Fat32 {
+            let ext_flags = le16(&bs, 40);
+            if ext_flags & 0x80 != 0 {
+                fs.active_fat = Some((ext_flags & 0x0f) as u64);
+            }
+
+            let sector = le16(&bs, 48) as u64;
+            let mut info = [0u8; 512];
+            fs.dev.read_at(sector * bytes_per_sector, &mut info)?;
+            if sector != 0 && le32(&info, 0) == FSINFO_LEAD_SIG && le32(&info, 484) == FSINFO_STRUCT_SIG {
+                fs.fsinfo_sector = Some(sector);
+                fs.free_count = match le32(&info, 488) {
+                    free if free <= cluster_count => Some(free),
+                    _ => None
+                };
+                fs.next_free = le32(&info, 492);
+            }
+        }
+
+        // the real label is the volume entry in the root directory, the copy
+        // in the boot sector is often stale
+        fs.label = fs.read_dir_entries(0)?
+                     .into_iter()
+                     .find(|e| e.attr & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == ATTR_VOLUME_ID)
+                     .map(|e| String::from(String::from_utf8_lossy(&e.short_name).trim_end()));
+        Ok(fs)
+    }
+
+    fn name(&self) -> &'static str {
+        match self.fat_type {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32"
+        }
+    }
+
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    fn uuid(&self) -> Option<String> {
+        Some(format!("{:04X}-{:04X}", self.serial >> 16, self.serial & 0xffff))
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector
+    }
+
+    fn used_bytes(&mut self) -> uefi::Result<u64> {
+        // note the FAT32 FSInfo free count is only a hint, so count for real
+        let mut used = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? != 0 {
+                used += 1;
+            }
+        }
+        Ok(used * self.cluster_bytes())
+    }
+
+    fn used_ranges(&mut self) -> uefi::Result<Vec<Range<u64>>> {
+        // everything before the data area is metadata
+        let mut ranges = vec![0..self.data_offset()];
+        let cluster_bytes = self.cluster_bytes();
+        for cluster in 2..self.cluster_count + 2 {
+            if self.fat_entry(cluster)? != 0 {
+                let start = self.cluster_offset(cluster);
+                add_range(&mut ranges, start..start + cluster_bytes);
+            }
+        }
+        Ok(ranges)
+    }
+
+    fn read_dir(&mut self, path: &str) -> uefi::Result<Vec<DirEntry>> {
+        let cluster = match self.lookup(path)? {
+            None => 0,
+            Some(entry) if entry.attr & ATTR_DIRECTORY != 0 => entry.first_cluster,
+            Some(_) => return Err(Status::INVALID_PARAMETER.into())
+        };
+
+        let entries = self.read_dir_entries(cluster)?;
+        Ok(entries.into_iter()
+                  .filter(|e| e.attr & ATTR_VOLUME_ID == 0 && e.name != "." && e.name != "..")
+                  .map(|e| DirEntry {
+                      is_dir: e.attr & ATTR_DIRECTORY != 0,
+                      size:   if e.attr & ATTR_DIRECTORY != 0 { 0 } else { e.size },
+                      name:   e.name
+                  })
+                  .collect())
+    }
+
+    fn open(&mut self, path: &str) -> uefi::Result<FatFile> {
+        let entry = match self.lookup(path)? {
+            Some(entry) => entry,
+            None => return Err(Status::INVALID_PARAMETER.into())
+        };
+        Ok(FatFile {
+            entry_offset:   *entry.slots.last().unwrap(),
+            first_cluster:  entry.first_cluster,
+            size:           entry.size,
+            is_dir:         entry.attr & ATTR_DIRECTORY != 0,
+            cursor:         Cell::new((0, entry.first_cluster))
+        })
+    }
+
+    fn read_at(&mut self, file: &FatFile, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        if file.is_dir {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if offset >= file.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, file.size - offset) as usize;
+        let cluster_bytes = self.cluster_bytes();
+
+        // carry on from the last cluster we read if we can, so reading a file
+        // front to back doesn't walk the chain from the start every time
+        let (mut index, mut cluster) = match file.cursor.get() {
+            (index, cluster) if index <= offset / cluster_bytes => (index, cluster),
+            _ => (0, file.first_cluster)
+        };
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            while index < pos / cluster_bytes {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None => {
+                        error!("File is shorter than its directory entry says");
+                        return Err(Status::VOLUME_CORRUPTED.into());
+                    }
+                };
+                index += 1;
+            }
+            if !self.is_data_cluster(cluster) {
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+
+            let within = pos % cluster_bytes;
+            let chunk = cmp::min((cluster_bytes - within) as usize, len - done);
+            self.dev.read_at(self.cluster_offset(cluster) + within, &mut buf[done..done + chunk])?;
+            done += chunk;
+        }
+
+        file.cursor.set((index, cluster));
+        Ok(len)
+    }
+
+    fn min_size(&mut self) -> uefi::Result<u64> {
+        // everything up to the data area, plus the clusters in use if they
+        // were all packed at the start, but never so few clusters that it
+        // would look like another FAT type
+        let meta = self.data_offset();
+        let (min_clusters, _) = self.cluster_limits();
+        Ok(meta + cmp::max(self.used_bytes()?, min_clusters * self.cluster_bytes()))
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities {
+            write:      self.is_writable(),
+            grow:       self.is_resizable(),
+            shrink:     self.is_resizable(),
+            relocate:   !self.dev.is_read_only(),
+            recluster:  self.is_resizable()
+        }
+    }
+
+    fn create_dir(&mut self, path: &str) -> uefi::Result {
+        self.check_writable()?;
+        let (parent, _) = split_path(path)?;
+        let parent_cluster = self.dir_cluster(parent)?;
+
+        // every directory but the root starts with "." and ".."
+        let cluster = self.alloc_cluster(None)?;
+        self.zero_cluster(cluster)?;
+        let dot = short_entry(*b".          ", ATTR_DIRECTORY, cluster, 0);
+        let dotdot = short_entry(*b"..         ", ATTR_DIRECTORY, parent_cluster, 0);
+        self.dev.write_at(self.cluster_offset(cluster), &dot)?;
+        self.dev.write_at(self.cluster_offset(cluster) + 32, &dotdot)?;
+
+        if let Err(e) = self.create_entry(path, ATTR_DIRECTORY, cluster) {
+            self.free_chain(cluster)?;
+            return Err(e);
+        }
+        self.sync()
+    }
+
+    fn create_file(&mut self, path: &str) -> uefi::Result<FatFile> {
+        self.check_writable()?;
+
+        // an existing file is truncated, so it can be overwritten
+        let entry_offset = match self.lookup(path) {
+            Ok(Some(entry)) if entry.attr & ATTR_DIRECTORY == 0 => {
+                let file = FatFile {
+                    entry_offset:   *entry.slots.last().unwrap(),
+                    first_cluster:  0,
+                    size:           0,
+                    is_dir:         false,
+                    cursor:         Cell::new((0, 0))
+                };
+                self.update_entry(&file)?;
+                if entry.first_cluster != 0 {
+                    self.free_chain(entry.first_cluster)?;
+                }
+                file.entry_offset
+            },
+            Ok(_) => return Err(Status::ACCESS_DENIED.into()),
+            Err(e) if e.status() == Status::NOT_FOUND => self.create_entry(path, ATTR_ARCHIVE, 0)?,
+            Err(e) => return Err(e)
+        };
+        self.sync()?;
+
+        Ok(FatFile {
+            entry_offset,
+            first_cluster:  0,
+            size:           0,
+            is_dir:         false,
+            cursor:         Cell::new((0, 0))
+        })
+    }
+
+    fn write_at(&mut self, file: &mut FatFile, offset: u64, buf: &[u8]) -> uefi::Result {
+        self.check_writable()?;
+        if file.is_dir || offset > file.size {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let end = offset + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            warn!("FAT files can't be bigger than 4 GiB");
+            return Err(Status::VOLUME_FULL.into());
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let cluster_bytes = self.cluster_bytes();
+
+        // carry on from the last cluster we touched, like reads do
+        let (mut index, mut cluster) = match file.cursor.get() {
+            (index, cluster) if cluster != 0 && index <= offset / cluster_bytes => (index, cluster),
+            _ => (0, file.first_cluster)
+        };
+        if cluster == 0 {
+            cluster = self.alloc_cluster(None)?;
+            file.first_cluster = cluster;
+        }
+
+        // walk (and grow) the chain as we go
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            while index < pos / cluster_bytes {
+                cluster = match self.next_cluster(cluster)? {
+                    Some(next) => next,
+                    None => self.alloc_cluster(Some(cluster))?
+                };
+                index += 1;
+            }
+
+            let within = pos % cluster_bytes;
+            let chunk = cmp::min((cluster_bytes - within) as usize, buf.len() - done);
+            self.dev.write_at(self.cluster_offset(cluster) + within, &buf[done..done + chunk])?;
+            done += chunk;
+        }
+        file.cursor.set((index, cluster));
+
+        file.size = cmp::max(file.size, end);
+        self.update_entry(file)?;
+        self.sync()
+    }
+
+    fn remove(&mut self, path: &str) -> uefi::Result {
+        self.check_writable()?;
+        let entry = match self.lookup(path)? {
+            Some(entry) => entry,
+            None => return Err(Status::ACCESS_DENIED.into())
+        };
+
+        // only empty directories can go
+        if entry.attr & ATTR_DIRECTORY != 0 && !self.read_dir(path)?.is_empty() {
+            warn!("Directory {} isn't empty", path);
+            return Err(Status::ACCESS_DENIED.into());
+        }
+        if entry.first_cluster != 0 {
+            self.free_chain(entry.first_cluster)?;
+        }
+        for slot in entry.slots.iter() {
+            self.dev.write_at(*slot, &[0xe5])?;
+        }
+        self.sync()
+    }
+
+    fn resize(&mut self, num_sectors: u64) -> uefi::Result {
+        self.resize_to(num_sectors, None)
+    }
+
+    fn resize_with_cluster_size(&mut self, num_sectors: u64, cluster_bytes: Option<u64>) -> uefi::Result {
+        self.resize_to(num_sectors, cluster_bytes)
+    }
+
+    fn relocate(&mut self) -> uefi::Result {
+        // the only thing FAT records about where it lives is the hidden sector
+        // count, i.e. the LBA the partition starts at
+        if self.dev.is_read_only() {
+            return Err(Status::WRITE_PROTECTED.into());
+        }
+        self.write_bpb()?;
+        self.dev.flush()
+    }
+}
@@ -1,4 +1,6 @@
 use alloc::{string::String, vec::Vec};
+use core::cmp;
+use core::ops::Range;
 
 /* 
 NOTE THIS IS POTENTIALLY GONNA BE GOING UP INTO THE AIR
@@ -6,21 +8,218 @@ AND EXPLODING WITH VARYING DEGREES OF "WE DONT NEED THIS"
 AT SOME UNDISCLOSED POINT IN THE FUTURE.  
 */
 // re-export our file system modules
+pub mod device;
+pub mod exfat;
 pub mod ext4;
 pub mod fat32;
+pub mod ntfs;
+pub mod probe;
 
+pub use device::PartitionDevice;
 
-/// define raw file structure
-pub struct RawFile{
-    path: String,
-    size: usize,
-    data: Vec<u8>
+
+/// define directory entry structure
+#[derive(Clone,Debug,PartialEq)]
+pub struct DirEntry {
+    pub name:   String,
+    pub is_dir: bool,
+    pub size:   u64 // in bytes, 0 for directories
+}
+
+/// defines what a filesystem driver can do besides reading
+#[derive(Copy,Clone,Debug,Default,PartialEq)]
+pub struct FsCapabilities {
+    pub write:      bool, // create_dir, create_file, write_at and remove
+    pub grow:       bool, // resize to more sectors
+    pub shrink:     bool, // resize to fewer sectors
+    pub relocate:   bool, // fix up the filesystem after its partition has moved
+    pub recluster:  bool  // change the cluster size while resizing
 }
 
 /// define our Filesystem traits
-pub trait Filesystem {
-    /// creates a new instance of the filesystem
-    fn new(media_id: u32, lba_start: u64, lba_end: u64) -> Self;
+///
+/// A driver is mounted on a device covering just its partition. Paths are `/`
+/// separated and absolute, e.g. `/EFI/BOOT/BOOTX64.EFI`
+pub trait Filesystem<'a>: Sized {
+    /// the handle of an open file
+    type File;
+
+    /// checks to see if the device holds this filesystem, without mounting it
+    fn probe(dev: &PartitionDevice) -> bool;
+
+    /// mounts the filesystem on the device, failing if it isn't there or is damaged
+    fn mount(dev: PartitionDevice<'a>) -> uefi::Result<Self>;
+
+    /// returns the name of the filesystem, e.g. "FAT32"
+    fn name(&self) -> &'static str;
+
+    /// returns the volume label, if the filesystem has one
+    fn label(&self) -> Option<String>;
+
+    /// returns the volume UUID or serial number, formatted like blkid does
+    fn uuid(&self) -> Option<String>;
+
+    /// returns the size of the filesystem in bytes
+    fn total_bytes(&self) -> u64;
+
+    /// returns the number of bytes in use
+    fn used_bytes(&mut self) -> uefi::Result<u64>;
+
+    /// returns the number of bytes still free
+    fn free_bytes(&mut self) -> uefi::Result<u64> {
+        Ok(self.total_bytes().saturating_sub(self.used_bytes()?))
+    }
+
+    /// returns the byte ranges of the device in use, metadata included, in
+    /// order and without overlaps
+    fn used_ranges(&mut self) -> uefi::Result<Vec<Range<u64>>> {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+
+    /// lists the entries of the directory at `path`
+    fn read_dir(&mut self, path: &str) -> uefi::Result<Vec<DirEntry>>;
+
+    /// opens the file at `path` for reading
+    fn open(&mut self, path: &str) -> uefi::Result<Self::File>;
+
+    /// reads from an open file at byte `offset`, returns the number of bytes read
+    /// which is only less than `buf.len()` at the end of the file
+    fn read_at(&mut self, file: &Self::File, offset: u64, buf: &mut [u8]) -> uefi::Result<usize>;
+
+    /// returns the smallest size in bytes the filesystem could be shrunk to
+    fn min_size(&mut self) -> uefi::Result<u64>;
+
+    /// returns what the driver can do besides reading
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities::default()
+    }
+
+    /// creates a directory at `path`
+    fn create_dir(&mut self, _path: &str) -> uefi::Result {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+
+    /// creates an empty file at `path` and opens it
+    fn create_file(&mut self, _path: &str) -> uefi::Result<Self::File> {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
 
-    fn get_file(&self, path: String) -> RawFile;
-}
\ No newline at end of file
+    /// writes to an open file at byte `offset`, growing it as needed
+    fn write_at(&mut self, _file: &mut Self::File, _offset: u64, _buf: &[u8]) -> uefi::Result {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+
+    /// removes the file or (empty) directory at `path`
+    fn remove(&mut self, _path: &str) -> uefi::Result {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+
+    /// resizes the filesystem to `num_sectors` sectors
+    /// note the device has to cover the new size already when growing
+    fn resize(&mut self, _num_sectors: u64) -> uefi::Result {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+
+    /// resizes the filesystem like `resize`, also changing its cluster size to
+    /// `cluster_bytes` if that is set
+    fn resize_with_cluster_size(&mut self, num_sectors: u64, cluster_bytes: Option<u64>) -> uefi::Result {
+        match cluster_bytes {
+            None => self.resize(num_sectors),
+            Some(_) => Err(uefi::Status::UNSUPPORTED.into())
+        }
+    }
+
+    /// fixes up anything that records where on the disk the filesystem lives,
+    /// after its data has been moved to the device it is now mounted on
+    fn relocate(&mut self) -> uefi::Result {
+        Err(uefi::Status::UNSUPPORTED.into())
+    }
+}
+
+
+/// adds a byte range to the end of an ordered list of them, joining it to
+/// the last one if the two touch
+pub fn add_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.start >= range.end {
+        return;
+    }
+    match ranges.last_mut() {
+        Some(last) if last.end >= range.start => last.end = cmp::max(last.end, range.end),
+        _ => ranges.push(range)
+    }
+}
+
+/// joins a directory path and an entry name
+pub fn join_path(dir: &str, name: &str) -> String {
+    let mut path = String::from(dir.trim_end_matches('/'));
+    path.push('/');
+    path.push_str(name);
+    path
+}
+
+/// returns the first entry under `path` on `src` that `dst` already has, so a
+/// copy can be refused before it starts
+///
+/// Note only the top level needs checking as nothing is merged into existing
+/// directories, and names are compared ignoring case like FAT and NTFS do
+pub fn find_collision<'a, 'b, S: Filesystem<'a>, D: Filesystem<'b>>(
+    src: &mut S,
+    dst: &mut D,
+    path: &str
+) -> uefi::Result<Option<String>> {
+    let existing = dst.read_dir(path)?;
+    for entry in src.read_dir(path)? {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        if existing.iter().any(|e| e.name.eq_ignore_ascii_case(&entry.name)) {
+            return Ok(Some(join_path(path, &entry.name)));
+        }
+    }
+    Ok(None)
+}
+
+/// copies every file and directory under `path` from one filesystem to another
+pub fn copy_tree<'a, 'b, S: Filesystem<'a>, D: Filesystem<'b>>(
+    src: &mut S,
+    dst: &mut D,
+    path: &str
+) -> uefi::Result {
+    let mut buf = vec![0u8; 64 * 1024];
+    copy_dir(src, dst, path, &mut buf)
+}
+
+/// recursive half of `copy_tree`, sharing one copy buffer
+fn copy_dir<'a, 'b, S: Filesystem<'a>, D: Filesystem<'b>>(
+    src: &mut S,
+    dst: &mut D,
+    path: &str,
+    buf: &mut [u8]
+) -> uefi::Result {
+    for entry in src.read_dir(path)? {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let child = join_path(path, &entry.name);
+
+        if entry.is_dir {
+            dst.create_dir(&child)?;
+            copy_dir(src, dst, &child, buf)?;
+            continue;
+        }
+
+        // stream the file across, it may well be bigger than memory
+        let file = src.open(&child)?;
+        let mut out = dst.create_file(&child)?;
+        let mut offset = 0;
+        loop {
+            let len = src.read_at(&file, offset, buf)?;
+            if len == 0 {
+                break;
+            }
+            dst.write_at(&mut out, offset, &buf[..len])?;
+            offset += len as u64;
+        }
+    }
+    Ok(())
+}
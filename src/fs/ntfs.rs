@@ -0,0 +1,1554 @@
+// Includes a struct and APIs for handling NTFS partitions
+// note files are read only, Windows keeps far too much state in the MFT for us
+// to safely change them behind its back, but the volume can be resized
+
+// notes on how it works
+// https://flatcap.github.io/linux-ntfs/ntfs/index.html
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp;
+use core::convert::TryInto;
+use core::ops::Range;
+use uefi::{Guid, Status};
+
+use super::{
+    add_range,
+    DirEntry,
+    Filesystem,
+    FsCapabilities,
+    PartitionDevice
+};
+use super::probe::{
+    probe,
+    FsKind
+};
+use crate::partitions::mbr::MbrPartTypes;
+
+/// the GPT type of Microsoft Basic Data partitions, which NTFS shares with FAT and exFAT
+pub const BASIC_DATA_GUID: Guid = Guid::from_values(0xebd0a0a2, 0xb9e5, 0x4433, 0x87c0, 0x68b6_b726_99c7);
+
+/// the MFT records of the metadata files we use
+const MFT_RECORD: u64 = 0;
+const MFT_MIRROR_RECORD: u64 = 1;
+const VOLUME_RECORD: u64 = 3;
+const ROOT_RECORD: u64 = 5;
+const BITMAP_RECORD: u64 = 6;
+const BADCLUS_RECORD: u64 = 8;
+
+/// records below this are reserved for metadata files, which we hide from listings
+const FIRST_USER_RECORD: u64 = 24;
+
+/// defines the attribute types we care about
+const ATTR_ATTRIBUTE_LIST: u32 = 0x20;
+const ATTR_VOLUME_INFORMATION: u32 = 0x70;
+const ATTR_DATA: u32 = 0x80;
+const ATTR_INDEX_ROOT: u32 = 0x90;
+const ATTR_INDEX_ALLOCATION: u32 = 0xa0;
+const ATTR_BITMAP: u32 = 0xb0;
+const ATTR_END: u32 = 0xffff_ffff;
+
+/// defines the attribute flags
+const ATTR_FLAG_COMPRESSED: u16 = 0x0001;
+const ATTR_FLAG_ENCRYPTED: u16 = 0x4000;
+
+/// defines the MFT record flags
+const RECORD_IN_USE: u16 = 0x0001;
+const RECORD_IS_DIR: u16 = 0x0002;
+
+/// defines the index entry flags
+const INDEX_ENTRY_LAST: u16 = 0x0002;
+
+/// the file attribute NTFS sets in file names of directories
+const FILE_ATTR_DIRECTORY: u32 = 0x1000_0000;
+
+/// the namespace of the 8.3 names Windows adds next to long ones
+const NAMESPACE_DOS: u8 = 2;
+
+/// the volume flag set while Windows has the volume mounted (or crashed with it)
+const VOLUME_DIRTY: u16 = 0x0001;
+
+/// the stride of the update sequence fixups, whatever the sector size
+const FIXUP_STRIDE: usize = 512;
+
+/// compressed data is stored in chunks that each decompress to 4 KiB
+const COMPRESSION_CHUNK: usize = 4096;
+
+/// the most of an attribute value that is read in one go, a damaged record
+/// could otherwise ask for any size
+const MAX_ATTR_VALUE: u64 = 256 * 1024 * 1024;
+
+/// the size of the chunks $Bitmap is read in when counting
+const BITMAP_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// the size of the chunks clusters are copied in when they are moved
+const COPY_CHUNK_BYTES: u64 = 1024 * 1024;
+
+/// the name of directory indexes
+const I30: &str = "$I30";
+
+/// the name of the stream in $BadClus listing the bad clusters
+const BAD_STREAM: &str = "$Bad";
+
+/// defines a mounted NTFS filesystem
+pub struct NtfsFs<'a> {
+    dev:                PartitionDevice<'a>,
+    label:              Option<String>,
+    uuid:               Option<String>,
+    bytes_per_sector:   u64,
+    cluster_size:       u64,
+    record_size:        u64,
+    total_sectors:      u64,
+    mft_runs:           Vec<Run>, // where the MFT itself is
+    version:            (u8, u8),
+    volume_flags:       u16
+}
+
+/// defines an open file (or directory)
+pub struct NtfsFile {
+    is_dir:     bool,
+    data:       Option<Attribute>, // the unnamed data stream, None for directories
+    unit_cache: RefCell<Option<(u64, Vec<u8>)>> // the last compression unit we decompressed
+}
+
+/// defines a run of clusters of a non-resident attribute
+#[derive(Copy,Clone,Debug,PartialEq)]
+struct Run {
+    vcn:    u64, // the first cluster within the attribute
+    lcn:    Option<u64>, // where it is on the disk, None if sparse
+    len:    u64
+}
+
+/// defines an attribute of an MFT record
+#[derive(Clone,Debug)]
+struct Attribute {
+    attr_type:  u32,
+    name:       String,
+    flags:      u16,
+    offset:     usize, // within the record it was read from
+    value:      AttrValue
+}
+
+/// defines where an attribute's value lives
+#[derive(Clone,Debug)]
+enum AttrValue {
+    Resident(Vec<u8>),
+    NonResident {
+        runs:               Vec<Run>,
+        size:               u64,
+        initialized:        u64, // anything past this reads as zeros
+        compression_unit:   u8 // log2 of clusters per unit, 0 if not compressed
+    }
+}
+
+/// defines an entry of a directory index
+#[derive(Clone,Debug)]
+struct IndexEntry {
+    record:     u64,
+    name:       String,
+    namespace:  u8,
+    is_dir:     bool
+}
+
+/// defines the state of a resize being worked out
+///
+/// Nothing is written until the whole resize has been planned, so running out
+/// of room part way leaves the volume untouched
+struct Resize<'r, 'a> {
+    fs:         &'r NtfsFs<'a>,
+    bitmap:     Vec<u8>, // the cluster bitmap, sized for the new volume
+    clusters:   u64, // the number of clusters in the new volume
+    next_alloc: u64, // where to start looking for free clusters
+    records:    BTreeMap<u64, Vec<u8>>, // changed MFT records, with fixups undone
+    moves:      Vec<(u64, u64, u64)> // old and new first cluster, and the length
+}
+
+
+/// helper function to read and write little endian integers in a buffer
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn set16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn bit_is_set(bitmap: &[u8], bit: u64) -> bool {
+    bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+}
+
+fn set_bit(bitmap: &mut [u8], bit: u64, value: bool) {
+    let byte = &mut bitmap[(bit / 8) as usize];
+    match value {
+        true => *byte |= 1 << (bit % 8),
+        false => *byte &= !(1 << (bit % 8))
+    }
+}
+
+/// returns part of a buffer, failing if the on-disk structure points outside of it
+fn slice(buf: &[u8], range: Range<usize>) -> uefi::Result<&[u8]> {
+    match buf.get(range) {
+        Some(part) => Ok(part),
+        None => Err(Status::VOLUME_CORRUPTED.into())
+    }
+}
+
+/// turns a UTF-16 LE name into a string
+fn utf16_name(bytes: &[u8]) -> String {
+    let chars = bytes.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+    core::char::decode_utf16(chars)
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// decodes the cluster and record sizes from the boot sector, None if either
+/// doesn't fit in 64 bits
+///
+/// Big clusters are stored as log2 of the sectors per cluster, and records
+/// either as a number of clusters or, if negative, log2 of the number of bytes
+fn boot_sizes(bs: &[u8], bytes_per_sector: u64) -> Option<(u64, u64)> {
+    let cluster_size = match bs[13] {
+        s if s > 0x80 => 1u64.checked_shl(256 - s as u32)?.checked_mul(bytes_per_sector)?,
+        s => s as u64 * bytes_per_sector
+    };
+    let record_size = match bs[0x40] as i8 {
+        s if s < 0 => 1u64.checked_shl(-(s as i32) as u32)?,
+        s => cluster_size.checked_mul(s as u64)?
+    };
+    Some((cluster_size, record_size))
+}
+
+/// undoes the update sequence, which swaps out the last 2 bytes of every
+/// 512 bytes so torn writes can be spotted
+fn apply_fixups(buf: &mut [u8]) -> uefi::Result {
+    let usa_offset = le16(buf, 4) as usize;
+    let usa_count = le16(buf, 6) as usize;
+    if usa_count == 0 || usa_offset + usa_count * 2 > buf.len() || (usa_count - 1) * FIXUP_STRIDE > buf.len() {
+        return Err(Status::VOLUME_CORRUPTED.into());
+    }
+    let usn = [buf[usa_offset], buf[usa_offset + 1]];
+    for i in 1..usa_count {
+        let end = i * FIXUP_STRIDE - 2;
+        if buf[end..end + 2] != usn {
+            error!("NTFS record was only partly written");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let fixup = [buf[usa_offset + i * 2], buf[usa_offset + i * 2 + 1]];
+        buf[end..end + 2].copy_from_slice(&fixup);
+    }
+    Ok(())
+}
+
+/// redoes the update sequence before a record goes back to the disk, with a
+/// new sequence number so stale sectors can be told apart
+fn protect(buf: &mut [u8]) {
+    let usa_offset = le16(buf, 4) as usize;
+    let usa_count = le16(buf, 6) as usize;
+    let usn = match le16(buf, usa_offset).wrapping_add(1) {
+        0 | 0xffff => 1,
+        usn => usn
+    };
+    set16(buf, usa_offset, usn);
+    for i in 1..usa_count {
+        let end = i * FIXUP_STRIDE - 2;
+        let saved = [buf[end], buf[end + 1]];
+        buf[usa_offset + i * 2..usa_offset + i * 2 + 2].copy_from_slice(&saved);
+        set16(buf, end, usn);
+    }
+}
+
+/// returns how many bytes a signed value needs in a mapping pairs array
+fn significant_bytes(value: i64) -> usize {
+    let mut bytes = 1;
+    while bytes < 8 {
+        let limit = 1i64 << (bytes * 8 - 1);
+        if value >= -limit && value < limit {
+            break;
+        }
+        bytes += 1;
+    }
+    bytes
+}
+
+/// encodes runs into a mapping pairs array, the reverse of `decode_runs`
+fn encode_runs(runs: &[Run]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut prev: i64 = 0;
+    for run in runs.iter() {
+        let len_size = significant_bytes(run.len as i64);
+        let (delta, off_size) = match run.lcn {
+            Some(lcn) => {
+                let delta = lcn as i64 - prev;
+                prev = lcn as i64;
+                (delta, significant_bytes(delta))
+            },
+            None => (0, 0)
+        };
+        out.push((off_size << 4 | len_size) as u8);
+        out.extend_from_slice(&run.len.to_le_bytes()[..len_size]);
+        out.extend_from_slice(&delta.to_le_bytes()[..off_size]);
+    }
+    out.push(0);
+    out
+}
+
+/// replaces the runs of the non-resident attribute at `attr` in a record,
+/// moving the attributes after it if the mapping pairs change size
+fn set_runs(record: &mut [u8], attr: usize, runs: &[Run]) -> uefi::Result {
+    let attr_len = le32(record, attr + 4) as usize;
+    let runs_offset = le16(record, attr + 0x20) as usize;
+    let pairs = encode_runs(runs);
+    let new_len = (runs_offset + pairs.len() + 7) & !7;
+
+    let used = le32(record, 0x18) as usize;
+    let allocated = cmp::min(le32(record, 0x1c) as usize, record.len());
+    if new_len != attr_len {
+        let new_used = used - attr_len + new_len;
+        if new_used > allocated {
+            warn!("NTFS file is too fragmented for its runs to fit in its MFT record");
+            return Err(Status::UNSUPPORTED.into());
+        }
+        record.copy_within(attr + attr_len..used, attr + new_len);
+        if new_used < used {
+            record[new_used..used].iter_mut().for_each(|b| *b = 0);
+        }
+        set32(record, 0x18, new_used as u32);
+        set32(record, attr + 4, new_len as u32);
+    }
+    record[attr + runs_offset..attr + new_len].iter_mut().for_each(|b| *b = 0);
+    record[attr + runs_offset..attr + runs_offset + pairs.len()].copy_from_slice(&pairs);
+
+    // the attribute records the last cluster its runs cover
+    if let Some(last) = runs.last() {
+        set64(record, attr + 0x18, last.vcn + last.len - 1);
+    }
+    Ok(())
+}
+
+/// points the runs at where clusters were moved to
+fn remap_runs(runs: &[Run], moves: &[(u64, u64, u64)]) -> Vec<Run> {
+    runs.iter().map(|run| match run.lcn {
+        Some(lcn) => match moves.iter().find(|(from, _, _)| *from == lcn) {
+            Some((_, to, _)) => Run { lcn: Some(*to), ..*run },
+            None => *run
+        },
+        None => *run
+    }).collect()
+}
+
+/// decodes a mapping pairs array into runs, starting at cluster `vcn`
+fn decode_runs(data: &[u8], vcn: u64) -> uefi::Result<Vec<Run>> {
+    let mut runs = Vec::new();
+    let mut vcn = vcn;
+    let mut lcn: i64 = 0;
+    let mut pos = 0;
+    while pos < data.len() && data[pos] != 0 {
+        // the header's nibbles give the sizes of the length and offset fields
+        let len_size = (data[pos] & 0x0f) as usize;
+        let off_size = (data[pos] >> 4) as usize;
+        pos += 1;
+        if len_size == 0 || len_size > 8 || off_size > 8 || pos + len_size + off_size > data.len() {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        let mut len = 0u64;
+        for (i, b) in data[pos..pos + len_size].iter().enumerate() {
+            len |= (*b as u64) << (i * 8);
+        }
+
+        // the offset is signed and relative to the previous run, and missing for sparse runs
+        let run_lcn = match off_size {
+            0 => None,
+            _ => {
+                let field = &data[pos + len_size..pos + len_size + off_size];
+                let mut delta = 0u64;
+                for (i, b) in field.iter().enumerate() {
+                    delta |= (*b as u64) << (i * 8);
+                }
+                if off_size < 8 && field[off_size - 1] & 0x80 != 0 {
+                    delta |= !0u64 << (off_size * 8);
+                }
+                lcn += delta as i64;
+                if lcn < 0 {
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+                Some(lcn as u64)
+            }
+        };
+
+        runs.push(Run { vcn, lcn: run_lcn, len });
+        vcn += len;
+        pos += len_size + off_size;
+    }
+    Ok(runs)
+}
+
+/// parses the attributes of an MFT record (with its fixups already undone)
+fn parse_record(record: &[u8]) -> uefi::Result<Vec<Attribute>> {
+    let mut attrs = Vec::new();
+    let mut offset = le16(record, 0x14) as usize;
+    loop {
+        if offset + 8 > record.len() {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let attr_type = le32(record, offset);
+        if attr_type == ATTR_END {
+            break;
+        }
+        let len = le32(record, offset + 4) as usize;
+        if len < 0x18 || offset + len > record.len() {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let attr = &record[offset..offset + len];
+
+        let name_offset = le16(attr, 10) as usize;
+        let name = utf16_name(slice(attr, name_offset..name_offset + attr[9] as usize * 2)?);
+        let value = match attr[8] {
+            0 => {
+                let value_offset = le16(attr, 0x14) as usize;
+                let value_len = le32(attr, 0x10) as usize;
+                AttrValue::Resident(slice(attr, value_offset..value_offset + value_len)?.to_vec())
+            },
+            _ => {
+                if len < 0x40 {
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+                let runs_offset = le16(attr, 0x20) as usize;
+                AttrValue::NonResident {
+                    runs:               decode_runs(slice(attr, runs_offset..len)?, le64(attr, 0x10))?,
+                    size:               le64(attr, 0x30),
+                    initialized:        le64(attr, 0x38),
+                    compression_unit:   attr[0x22]
+                }
+            }
+        };
+
+        attrs.push(Attribute {
+            attr_type,
+            name,
+            flags: le16(attr, 12),
+            offset,
+            value
+        });
+        offset += len;
+    }
+    Ok(attrs)
+}
+
+/// adds an attribute from an extension record, joining the pieces of
+/// non-resident attributes that were split across records
+fn merge_attribute(attrs: &mut Vec<Attribute>, attr: Attribute) {
+    let existing = attrs.iter_mut().find(|a| {
+        a.attr_type == attr.attr_type && a.name == attr.name && matches!(a.value, AttrValue::NonResident{..})
+    });
+    let existing = match (existing, &attr.value) {
+        (Some(existing), AttrValue::NonResident{..}) => existing,
+        _ => {
+            attrs.push(attr);
+            return;
+        }
+    };
+
+    if let (AttrValue::NonResident{runs, size, initialized, compression_unit},
+            AttrValue::NonResident{runs: more, size: more_size, initialized: more_init, compression_unit: more_unit})
+        = (&mut existing.value, attr.value) {
+        // only the first piece has the sizes filled in
+        if more.first().map(|r| r.vcn) == Some(0) {
+            *size = more_size;
+            *initialized = more_init;
+            *compression_unit = more_unit;
+            existing.flags = attr.flags;
+        }
+        runs.extend(more);
+        runs.sort_by_key(|r| r.vcn);
+    }
+}
+
+/// returns the runs of the unnamed data stream, which has to be non-resident
+fn data_runs(attrs: &[Attribute]) -> uefi::Result<Vec<Run>> {
+    match attrs.iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+        Some(Attribute{value: AttrValue::NonResident{runs, ..}, ..}) => Ok(runs.clone()),
+        _ => {
+            error!("NTFS metadata file has no data");
+            Err(Status::VOLUME_CORRUPTED.into())
+        }
+    }
+}
+
+/// parses the entries of an index node, starting at its index header
+fn parse_index_node(node: &[u8], entries: &mut Vec<IndexEntry>) -> uefi::Result {
+    if node.len() < 16 {
+        return Err(Status::VOLUME_CORRUPTED.into());
+    }
+    let end = cmp::min(le32(node, 4) as usize, node.len());
+    let mut pos = le32(node, 0) as usize;
+    while pos + 16 <= end {
+        let len = le16(node, pos + 8) as usize;
+        let key_len = le16(node, pos + 10) as usize;
+        if le16(node, pos + 12) & INDEX_ENTRY_LAST != 0 {
+            break;
+        }
+        if len < 16 || pos + len > end {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        // the key is the file's $FILE_NAME attribute
+        if key_len >= 0x42 {
+            let key = slice(node, pos + 16..pos + 16 + key_len)?;
+            let name_len = key[0x40] as usize;
+            entries.push(IndexEntry {
+                record:     le64(node, pos) & 0xffff_ffff_ffff,
+                name:       utf16_name(slice(key, 0x42..0x42 + name_len * 2)?),
+                namespace:  key[0x41],
+                is_dir:     le32(key, 0x38) & FILE_ATTR_DIRECTORY != 0
+            });
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+/// decompresses LZNT1 data into `out`, each chunk filling the next 4 KiB
+/// note anything the data doesn't cover is left as it was (zeros)
+fn lznt1_decompress(src: &[u8], out: &mut [u8]) -> Result<(), ()> {
+    let mut pos = 0;
+    let mut chunk_start = 0;
+    while pos + 2 <= src.len() && chunk_start < out.len() {
+        let header = le16(src, pos);
+        if header == 0 {
+            break;
+        }
+        let size = (header & 0x0fff) as usize + 1;
+        let data = src.get(pos + 2..pos + 2 + size).ok_or(())?;
+        pos += 2 + size;
+
+        let chunk_end = cmp::min(chunk_start + COMPRESSION_CHUNK, out.len());
+        let chunk = &mut out[chunk_start..chunk_end];
+        chunk_start += COMPRESSION_CHUNK;
+        if header & 0x8000 == 0 {
+            let len = cmp::min(data.len(), chunk.len());
+            chunk[..len].copy_from_slice(&data[..len]);
+            continue;
+        }
+
+        // each tag byte says which of the next 8 items are back references
+        let mut i = 0;
+        let mut o = 0;
+        while i < data.len() {
+            let tags = data[i];
+            i += 1;
+            for bit in 0..8 {
+                if i >= data.len() {
+                    break;
+                }
+                if tags & (1 << bit) == 0 {
+                    *chunk.get_mut(o).ok_or(())? = data[i];
+                    o += 1;
+                    i += 1;
+                    continue;
+                }
+
+                // the further into the chunk, the more bits go to the distance
+                let token = le16(data.get(i..i + 2).ok_or(())?, 0) as usize;
+                i += 2;
+                if o == 0 {
+                    return Err(());
+                }
+                let mut len_bits = 12;
+                let mut p = o - 1;
+                while p >= 0x10 {
+                    p >>= 1;
+                    len_bits -= 1;
+                }
+                let distance = (token >> len_bits) + 1;
+                let len = (token & ((1 << len_bits) - 1)) + 3;
+                if distance > o || o + len > chunk.len() {
+                    return Err(());
+                }
+                for _ in 0..len {
+                    chunk[o] = chunk[o - distance];
+                    o += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+
+////////////////////////// ATTRIBUTE IMPL //////////////////////////////
+impl Attribute {
+    /// returns the size of the attribute's value in bytes
+    fn size(&self) -> u64 {
+        match &self.value {
+            AttrValue::Resident(data) => data.len() as u64,
+            AttrValue::NonResident{size, ..} => *size
+        }
+    }
+}
+
+
+////////////////////////// NTFSFS IMPL //////////////////////////////
+impl<'a> NtfsFs<'a> {
+    /// checks to see if an MBR partition type is the one NTFS uses
+    /// note this is only a hint, the probe decides what is really there
+    pub fn matches_mbr_type(part_type: MbrPartTypes) -> bool {
+        part_type == MbrPartTypes::NTFS
+    }
+
+    /// checks to see if a GPT partition type is one NTFS uses
+    /// note Basic Data partitions may just as well hold FAT or exFAT
+    pub fn matches_gpt_type(type_guid: Guid) -> bool {
+        type_guid == BASIC_DATA_GUID
+    }
+
+    /// returns the size of a cluster in bytes
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// returns the NTFS version, e.g. (3, 1) for anything since Windows XP
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    /// checks to see if the volume is marked dirty, i.e. Windows didn't
+    /// shut down cleanly (or was hibernated) and wants to check it
+    pub fn is_dirty(&self) -> bool {
+        self.volume_flags & VOLUME_DIRTY != 0
+    }
+
+    /// returns the number of clusters in the volume
+    fn total_clusters(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector / self.cluster_size
+    }
+
+    /// reads bytes of a non-resident value through its runs, holes read as zeros
+    fn read_runs(&self, runs: &[Run], offset: u64, buf: &mut [u8]) -> uefi::Result {
+        let cs = self.cluster_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let vcn = pos / cs;
+            let run = match runs.iter().find(|r| vcn >= r.vcn && vcn < r.vcn + r.len) {
+                Some(run) => run,
+                None => {
+                    error!("NTFS attribute is shorter than its size says");
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+            };
+            let within = pos - run.vcn * cs;
+            let chunk = cmp::min(run.len * cs - within, (buf.len() - done) as u64) as usize;
+            match run.lcn {
+                Some(lcn) if lcn + run.len <= self.total_clusters() => {
+                    self.dev.read_at(lcn * cs + within, &mut buf[done..done + chunk])?;
+                },
+                Some(_) => return Err(Status::VOLUME_CORRUPTED.into()),
+                None => buf[done..done + chunk].iter_mut().for_each(|b| *b = 0)
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// writes bytes of a non-resident value through its runs
+    fn write_runs(&self, runs: &[Run], offset: u64, buf: &[u8]) -> uefi::Result {
+        let cs = self.cluster_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let vcn = pos / cs;
+            let (run, lcn) = match runs.iter().find(|r| vcn >= r.vcn && vcn < r.vcn + r.len) {
+                Some(run @ Run{lcn: Some(lcn), ..}) => (run, *lcn),
+                _ => return Err(Status::VOLUME_CORRUPTED.into())
+            };
+            let within = pos - run.vcn * cs;
+            let chunk = cmp::min(run.len * cs - within, (buf.len() - done) as u64) as usize;
+            self.dev.write_at(lcn * cs + within, &buf[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// writes an MFT record (with its fixups undone) back, along with its
+    /// copy in $MFTMirr if it has one
+    fn write_record(&self, mirror: &[Run], mirror_records: u64, index: u64, record: &[u8]) -> uefi::Result {
+        let mut raw = record.to_vec();
+        protect(&mut raw);
+        self.write_runs(&self.mft_runs, index * self.record_size, &raw)?;
+        if index < mirror_records {
+            self.write_runs(mirror, index * self.record_size, &raw)?;
+        }
+        Ok(())
+    }
+
+    /// returns the runs of $MFTMirr and the number of records it holds
+    fn mirror(&self) -> uefi::Result<(Vec<Run>, u64)> {
+        let (_, attrs) = self.attributes(MFT_MIRROR_RECORD)?;
+        let size = attrs.iter()
+                        .find(|a| a.attr_type == ATTR_DATA && a.name.is_empty())
+                        .map(|a| a.size())
+                        .unwrap_or(0);
+        Ok((data_runs(&attrs)?, size / self.record_size))
+    }
+
+    /// checks to see if Windows is hibernated (which includes Fast Startup),
+    /// in which case it would resume with a stale idea of the volume
+    pub fn is_hibernated(&self) -> uefi::Result<bool> {
+        let record = match self.lookup("/hiberfil.sys") {
+            Ok(record) => record,
+            Err(e) if e.status() == Status::NOT_FOUND => return Ok(false),
+            Err(e) => return Err(e)
+        };
+        let (_, attrs) = self.attributes(record)?;
+        let data = match attrs.iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+            Some(data) => data,
+            None => return Ok(false)
+        };
+        let mut magic = [0u8; 4];
+        if self.read_data(data, &RefCell::new(None), 0, &mut magic)? < 4 {
+            return Ok(false);
+        }
+        Ok(magic.eq_ignore_ascii_case(b"hibr"))
+    }
+
+    /// fails if the volume isn't in a state we can safely resize
+    fn check_resizable(&self) -> uefi::Result {
+        if self.dev.is_read_only() {
+            return Err(Status::WRITE_PROTECTED.into());
+        }
+        if self.is_dirty() {
+            warn!("NTFS volume is marked dirty, let Windows run chkdsk on it first");
+            return Err(Status::ACCESS_DENIED.into());
+        }
+        if self.is_hibernated()? {
+            warn!("Windows is hibernated on this volume, shut it down fully (no Fast Startup) first");
+            return Err(Status::ACCESS_DENIED.into());
+        }
+        Ok(())
+    }
+
+    /// reads an MFT record and undoes its fixups
+    fn read_record(&self, index: u64) -> uefi::Result<Vec<u8>> {
+        let mut record = vec![0u8; self.record_size as usize];
+        self.read_runs(&self.mft_runs, index * self.record_size, &mut record)?;
+        if record[0..4] != *b"FILE" {
+            error!("MFT record {} is damaged", index);
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        apply_fixups(&mut record)?;
+        Ok(record)
+    }
+
+    /// reads the attributes of a file, following its attribute list into
+    /// extension records if it has one
+    ///
+    /// Returns the record flags and the attributes
+    fn attributes(&self, index: u64) -> uefi::Result<(u16, Vec<Attribute>)> {
+        let record = self.read_record(index)?;
+        let flags = le16(&record, 0x16);
+        if flags & RECORD_IN_USE == 0 {
+            return Err(Status::NOT_FOUND.into());
+        }
+        let mut attrs = parse_record(&record)?;
+
+        let list = match attrs.iter().find(|a| a.attr_type == ATTR_ATTRIBUTE_LIST) {
+            Some(list) => self.attr_value(list)?,
+            None => return Ok((flags, attrs))
+        };
+
+        // every attribute is listed, along with the record it lives in
+        let mut records: Vec<u64> = Vec::new();
+        let mut pos = 0;
+        while pos + 0x18 <= list.len() {
+            let len = le16(&list, pos + 4) as usize;
+            if len == 0 {
+                break;
+            }
+            let record = le64(&list, pos + 0x10) & 0xffff_ffff_ffff;
+            if record != index && !records.contains(&record) {
+                records.push(record);
+            }
+            pos += len;
+        }
+        for record in records {
+            let extension = self.read_record(record)?;
+            for attr in parse_record(&extension)? {
+                merge_attribute(&mut attrs, attr);
+            }
+        }
+        Ok((flags, attrs))
+    }
+
+    /// reads a whole attribute value, only meant for small ones
+    fn attr_value(&self, attr: &Attribute) -> uefi::Result<Vec<u8>> {
+        match &attr.value {
+            AttrValue::Resident(data) => Ok(data.clone()),
+            AttrValue::NonResident{runs, size, ..} => {
+                if *size > MAX_ATTR_VALUE {
+                    error!("NTFS attribute claims to be {} bytes, too big to read whole", size);
+                    return Err(Status::VOLUME_CORRUPTED.into());
+                }
+                let mut data = vec![0u8; *size as usize];
+                self.read_runs(runs, 0, &mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// reads a compression unit of a compressed attribute
+    fn read_unit(&self, runs: &[Run], unit: u64, unit_clusters: u64) -> uefi::Result<Vec<u8>> {
+        let cs = self.cluster_size;
+        let first = unit * unit_clusters;
+        let mut data = vec![0u8; (unit_clusters * cs) as usize];
+
+        // the compressed data comes first, the rest of the unit is sparse
+        let allocated = (first..first + unit_clusters)
+            .filter(|vcn| runs.iter().any(|r| *vcn >= r.vcn && *vcn < r.vcn + r.len && r.lcn.is_some()))
+            .count() as u64;
+        if allocated == 0 {
+            return Ok(data);
+        }
+        if allocated == unit_clusters {
+            // it didn't compress, so is stored as is
+            self.read_runs(runs, first * cs, &mut data)?;
+            return Ok(data);
+        }
+
+        let mut compressed = vec![0u8; (allocated * cs) as usize];
+        self.read_runs(runs, first * cs, &mut compressed)?;
+        if lznt1_decompress(&compressed, &mut data).is_err() {
+            error!("NTFS compressed data is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        Ok(data)
+    }
+
+    /// reads from an attribute's value, decompressing it if needed
+    /// note `cache` keeps the last compression unit around between calls
+    fn read_data(
+        &self,
+        attr: &Attribute,
+        cache: &RefCell<Option<(u64, Vec<u8>)>>,
+        offset: u64,
+        buf: &mut [u8]
+    ) -> uefi::Result<usize> {
+        let size = attr.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, size - offset) as usize;
+        let buf = &mut buf[..len];
+
+        let (runs, initialized, compression_unit) = match &attr.value {
+            AttrValue::Resident(data) => {
+                buf.copy_from_slice(&data[offset as usize..offset as usize + len]);
+                return Ok(len);
+            },
+            AttrValue::NonResident{runs, initialized, compression_unit, ..} => (runs, *initialized, *compression_unit)
+        };
+        if attr.flags & ATTR_FLAG_ENCRYPTED != 0 {
+            warn!("File is encrypted with EFS, it can only be read in Windows");
+            return Err(Status::ACCESS_DENIED.into());
+        }
+
+        if attr.flags & ATTR_FLAG_COMPRESSED != 0 && compression_unit != 0 {
+            let unit_clusters = 1u64 << compression_unit;
+            let unit_bytes = unit_clusters * self.cluster_size;
+            let mut done = 0;
+            while done < len {
+                let pos = offset + done as u64;
+                let unit = pos / unit_bytes;
+                let within = (pos % unit_bytes) as usize;
+                let chunk = cmp::min(unit_bytes as usize - within, len - done);
+
+                // reading a file front to back hits the same unit many times
+                let mut cache = cache.borrow_mut();
+                if cache.as_ref().map(|(u, _)| *u) != Some(unit) {
+                    *cache = Some((unit, self.read_unit(runs, unit, unit_clusters)?));
+                }
+                let data = &cache.as_ref().unwrap().1;
+                buf[done..done + chunk].copy_from_slice(&data[within..within + chunk]);
+                done += chunk;
+            }
+        } else {
+            // only what was written is read, the rest is zeros
+            let valid = cmp::min(initialized.saturating_sub(offset), len as u64) as usize;
+            self.read_runs(runs, offset, &mut buf[..valid])?;
+            buf[valid..].iter_mut().for_each(|b| *b = 0);
+        }
+        Ok(len)
+    }
+
+    /// reads every entry of a directory's index, in no particular order
+    fn index_entries(&self, index: u64) -> uefi::Result<Vec<IndexEntry>> {
+        let (flags, attrs) = self.attributes(index)?;
+        if flags & RECORD_IS_DIR == 0 {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let find = |attr_type| attrs.iter().find(|a| a.attr_type == attr_type && a.name == I30);
+
+        let root = match find(ATTR_INDEX_ROOT) {
+            Some(root) => self.attr_value(root)?,
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+        let mut entries = Vec::new();
+        parse_index_node(slice(&root, 0x10..root.len())?, &mut entries)?;
+
+        // bigger directories keep the rest of the B-tree in index records
+        let (alloc, runs) = match find(ATTR_INDEX_ALLOCATION) {
+            Some(alloc @ Attribute{value: AttrValue::NonResident{runs, ..}, ..}) => (alloc, runs),
+            _ => return Ok(entries)
+        };
+        let bitmap = match find(ATTR_BITMAP) {
+            Some(bitmap) => self.attr_value(bitmap)?,
+            None => Vec::new()
+        };
+        let record_size = le32(&root, 8) as u64;
+        if record_size < FIXUP_STRIDE as u64 {
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        let mut block = vec![0u8; record_size as usize];
+        for i in 0..alloc.size() / record_size {
+            // records not marked in use may hold stale entries
+            let in_use = bitmap.get(i as usize / 8).map(|b| b & (1 << (i % 8)) != 0).unwrap_or(false);
+            if !in_use {
+                continue;
+            }
+            self.read_runs(runs, i * record_size, &mut block)?;
+            if block[0..4] != *b"INDX" {
+                error!("NTFS index record is damaged");
+                return Err(Status::VOLUME_CORRUPTED.into());
+            }
+            apply_fixups(&mut block)?;
+            parse_index_node(&block[0x18..], &mut entries)?;
+        }
+        Ok(entries)
+    }
+
+    /// works out the MFT record of the file at `path`
+    /// note names are matched exactly first, then ignoring case like Windows does
+    fn lookup(&self, path: &str) -> uefi::Result<u64> {
+        let mut record = ROOT_RECORD;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            let entries = self.index_entries(record)?;
+            let found = entries.iter()
+                               .find(|e| e.name == part)
+                               .or_else(|| entries.iter().find(|e| e.name.to_lowercase() == part.to_lowercase()));
+            record = match found {
+                Some(entry) => entry.record,
+                None => return Err(Status::NOT_FOUND.into())
+            };
+        }
+        Ok(record)
+    }
+
+    /// returns the size of a file's unnamed data stream
+    fn file_size(&self, index: u64) -> uefi::Result<u64> {
+        let (_, attrs) = self.attributes(index)?;
+        Ok(attrs.iter()
+                .find(|a| a.attr_type == ATTR_DATA && a.name.is_empty())
+                .map(|a| a.size())
+                .unwrap_or(0))
+    }
+}
+
+
+////////////////////////// RESIZE IMPL //////////////////////////////
+impl<'r, 'a> Resize<'r, 'a> {
+    /// loads the cluster bitmap, resized to `clusters`
+    fn new(fs: &'r NtfsFs<'a>, clusters: u64) -> uefi::Result<Self> {
+        let (_, attrs) = fs.attributes(BITMAP_RECORD)?;
+        let mut bitmap = match attrs.iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+            Some(attr) => fs.attr_value(attr)?,
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+
+        // $Bitmap is kept a multiple of 8 bytes long, with nothing set past the end
+        let old_clusters = fs.total_clusters();
+        bitmap.resize(((clusters + 63) / 64 * 8) as usize, 0);
+        for cluster in cmp::min(old_clusters, clusters)..bitmap.len() as u64 * 8 {
+            set_bit(&mut bitmap, cluster, false);
+        }
+
+        Ok(Resize {
+            fs,
+            bitmap,
+            clusters,
+            next_alloc: 0,
+            records:    BTreeMap::new(),
+            moves:      Vec::new()
+        })
+    }
+
+    /// returns a record to change, reading it the first time
+    fn record(&mut self, index: u64) -> uefi::Result<&mut Vec<u8>> {
+        if !self.records.contains_key(&index) {
+            let record = self.fs.read_record(index)?;
+            self.records.insert(index, record);
+        }
+        Ok(self.records.get_mut(&index).unwrap())
+    }
+
+    /// looks for `len` free clusters in a row between `from` and the end
+    fn find_free_run(&self, from: u64, len: u64) -> Option<u64> {
+        let mut run_start = from;
+        let mut run_len = 0;
+        let mut cluster = from;
+        while cluster < self.clusters {
+            // whole bytes in use can be skipped
+            if cluster % 8 == 0 && self.bitmap[(cluster / 8) as usize] == 0xff {
+                run_len = 0;
+                cluster += 8;
+                run_start = cluster;
+                continue;
+            }
+            if bit_is_set(&self.bitmap, cluster) {
+                run_len = 0;
+                run_start = cluster + 1;
+            } else {
+                run_len += 1;
+                if run_len == len {
+                    return Some(run_start);
+                }
+            }
+            cluster += 1;
+        }
+        None
+    }
+
+    /// finds `len` free clusters in a row in the new volume and marks them used
+    fn alloc(&mut self, len: u64) -> uefi::Result<u64> {
+        let start = match self.find_free_run(self.next_alloc, len).or_else(|| self.find_free_run(0, len)) {
+            Some(start) => start,
+            None => {
+                warn!("No run of {} free clusters left to move data into", len);
+                return Err(Status::VOLUME_FULL.into());
+            }
+        };
+        for cluster in start..start + len {
+            set_bit(&mut self.bitmap, cluster, true);
+        }
+        self.next_alloc = start + len;
+        Ok(start)
+    }
+
+    /// plans moving every run that reaches past the new end of the volume,
+    /// each one moving whole so no record needs more runs than before
+    fn relocate(&mut self) -> uefi::Result {
+        let fs = self.fs;
+        let (_, mft) = fs.attributes(MFT_RECORD)?;
+        let records = mft.iter()
+                         .find(|a| a.attr_type == ATTR_DATA && a.name.is_empty())
+                         .map(|a| a.size() / fs.record_size)
+                         .unwrap_or(0);
+        let in_use = match mft.iter().find(|a| a.attr_type == ATTR_BITMAP && a.name.is_empty()) {
+            Some(bitmap) => fs.attr_value(bitmap)?,
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+
+        for index in 0..records {
+            if (index / 8) as usize >= in_use.len() || !bit_is_set(&in_use, index) {
+                continue;
+            }
+            let record = match self.records.get(&index) {
+                Some(record) => record.clone(),
+                None => fs.read_record(index)?
+            };
+            if le16(&record, 0x16) & RECORD_IN_USE == 0 {
+                continue;
+            }
+
+            // going backwards keeps the offsets of earlier attributes valid
+            // when an attribute changes size
+            let mut changed = record.clone();
+            let mut dirty = false;
+            for attr in parse_record(&record)?.iter().rev() {
+                let runs = match &attr.value {
+                    AttrValue::NonResident{runs, ..} => runs,
+                    AttrValue::Resident(_) => continue
+                };
+                // bad clusters are dealt with on their own
+                if index == BADCLUS_RECORD && attr.name == BAD_STREAM {
+                    continue;
+                }
+
+                let mut new_runs = runs.clone();
+                let mut moved = false;
+                for run in new_runs.iter_mut() {
+                    let lcn = match run.lcn {
+                        Some(lcn) if lcn + run.len > self.clusters => lcn,
+                        _ => continue
+                    };
+                    // the part of the run still inside the volume stays in use
+                    // until the records stop pointing at it, see commit
+                    let new = self.alloc(run.len)?;
+                    self.moves.push((lcn, new, run.len));
+                    run.lcn = Some(new);
+                    moved = true;
+                }
+                if moved {
+                    set_runs(&mut changed, attr.offset, &new_runs)?;
+                    dirty = true;
+                }
+            }
+            if dirty {
+                self.records.insert(index, changed);
+            }
+        }
+        if !self.moves.is_empty() {
+            let clusters: u64 = self.moves.iter().map(|(_, _, len)| len).sum();
+            info!("Moving {} runs ({} clusters) out of the way", self.moves.len(), clusters);
+        }
+        Ok(())
+    }
+
+    /// sizes $Bitmap for the new volume, giving it more clusters if needed
+    fn resize_bitmap_file(&mut self) -> uefi::Result {
+        let cs = self.fs.cluster_size;
+        let size = self.bitmap.len() as u64;
+        let record = self.record(BITMAP_RECORD)?.clone();
+        let attr = match parse_record(&record)?.into_iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+            Some(attr) => attr,
+            None => {
+                warn!("$Bitmap's data isn't in its base record, refusing to resize");
+                return Err(Status::UNSUPPORTED.into());
+            }
+        };
+        let mut runs = match attr.value {
+            AttrValue::NonResident{runs, ..} => runs,
+            AttrValue::Resident(_) => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+
+        let allocated: u64 = runs.iter().map(|r| r.len).sum();
+        let needed = (size + cs - 1) / cs;
+        if needed > allocated {
+            let len = needed - allocated;
+            let lcn = self.alloc(len)?;
+            runs.push(Run { vcn: allocated, lcn: Some(lcn), len });
+        }
+
+        let record = self.record(BITMAP_RECORD)?;
+        if needed > allocated {
+            set_runs(record, attr.offset, &runs)?;
+            set64(record, attr.offset + 0x28, needed * cs);
+        }
+        set64(record, attr.offset + 0x30, size);
+        set64(record, attr.offset + 0x38, size);
+        Ok(())
+    }
+
+    /// sizes the bad cluster list for the new volume, it is a sparse stream as
+    /// big as the volume, with the bad clusters mapped onto themselves
+    fn resize_bad_clusters(&mut self) -> uefi::Result {
+        let clusters = self.clusters;
+        let cs = self.fs.cluster_size;
+        let record = self.record(BADCLUS_RECORD)?.clone();
+        let attr = match parse_record(&record)?.into_iter().find(|a| a.attr_type == ATTR_DATA && a.name == BAD_STREAM) {
+            Some(attr) => attr,
+            None => return Ok(())
+        };
+        let (runs, size, initialized) = match attr.value {
+            AttrValue::NonResident{runs, size, initialized, ..} => (runs, size, initialized),
+            AttrValue::Resident(_) => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+
+        let mut new_runs = Vec::new();
+        let mut vcn = 0;
+        for run in runs.iter().filter(|r| r.lcn.is_some()) {
+            if run.vcn + run.len > clusters {
+                warn!("There are bad clusters in the part being cut off, refusing to shrink past them");
+                return Err(Status::UNSUPPORTED.into());
+            }
+            if run.vcn > vcn {
+                new_runs.push(Run { vcn, lcn: None, len: run.vcn - vcn });
+            }
+            new_runs.push(*run);
+            vcn = run.vcn + run.len;
+        }
+        if vcn < clusters {
+            new_runs.push(Run { vcn, lcn: None, len: clusters - vcn });
+        }
+
+        let record = self.record(BADCLUS_RECORD)?;
+        set_runs(record, attr.offset, &new_runs)?;
+        set64(record, attr.offset + 0x28, clusters * cs);
+        set64(record, attr.offset + 0x30, clusters * cs);
+        let initialized = match initialized == size {
+            true => clusters * cs,
+            false => cmp::min(initialized, clusters * cs)
+        };
+        set64(record, attr.offset + 0x38, initialized);
+        Ok(())
+    }
+
+    /// sets the dirty flag in $Volume, so Windows runs chkdsk on its next boot
+    fn mark_dirty(&mut self) -> uefi::Result {
+        let record = self.record(VOLUME_RECORD)?.clone();
+        let attr = match parse_record(&record)?.into_iter().find(|a| a.attr_type == ATTR_VOLUME_INFORMATION) {
+            Some(attr) => attr,
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+        let record = self.record(VOLUME_RECORD)?;
+        let flags = attr.offset + le16(record, attr.offset + 0x14) as usize + 10;
+        let value = le16(record, flags) | VOLUME_DIRTY;
+        set16(record, flags, value);
+        Ok(())
+    }
+
+    /// copies a run of clusters, the two must not overlap
+    fn copy_clusters(&self, from: u64, to: u64, len: u64) -> uefi::Result {
+        let cs = self.fs.cluster_size;
+        let chunk_clusters = cmp::max(COPY_CHUNK_BYTES / cs, 1);
+        let mut buf = vec![0u8; (cmp::min(len, chunk_clusters) * cs) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, chunk_clusters);
+            let buf = &mut buf[..(chunk * cs) as usize];
+            self.fs.dev.read_at((from + done) * cs, buf)?;
+            self.fs.dev.write_at((to + done) * cs, buf)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// writes everything out, returning where the MFT now is
+    ///
+    /// $Volume is marked dirty first, so if we are interrupted chkdsk at least
+    /// knows to look
+    fn commit(&mut self, total_sectors: u64) -> uefi::Result<Vec<Run>> {
+        let fs = self.fs;
+        let (mirror, mirror_records) = fs.mirror()?;
+        if let Some(volume) = self.records.remove(&VOLUME_RECORD) {
+            fs.write_record(&mirror, mirror_records, VOLUME_RECORD, &volume)?;
+            fs.dev.flush()?;
+        }
+
+        // the data goes first, so the old copies stay valid until the
+        // records point at the new ones
+        for (from, to, len) in self.moves.iter() {
+            self.copy_clusters(*from, *to, *len)?;
+        }
+        fs.dev.flush()?;
+
+        // the MFT (and its mirror) may have moved too, which changes where
+        // the records themselves get written
+        let mft_runs = remap_runs(&fs.mft_runs, &self.moves);
+        let mirror = remap_runs(&mirror, &self.moves);
+        let moved_fs = NtfsFs {
+            mft_runs: mft_runs.clone(),
+            label:    None,
+            uuid:     None,
+            ..*fs
+        };
+        for (index, record) in self.records.iter() {
+            moved_fs.write_record(&mirror, mirror_records, *index, record)?;
+        }
+
+        // only now are the old places of the moved runs free, nothing else
+        // could be moved over them before
+        for (from, _, len) in self.moves.iter() {
+            for cluster in *from..cmp::min(from + len, self.clusters) {
+                set_bit(&mut self.bitmap, cluster, false);
+            }
+        }
+
+        // $Bitmap's record has its new runs by now
+        let bitmap_runs = data_runs(&parse_record(&moved_fs.read_record(BITMAP_RECORD)?)?)?;
+        moved_fs.write_runs(&bitmap_runs, 0, &self.bitmap)?;
+
+        // the backup boot sector lives in the last sector, past the clusters
+        let mut bs = vec![0u8; fs.bytes_per_sector as usize];
+        fs.dev.read_at(0, &mut bs)?;
+        set64(&mut bs, 0x28, total_sectors);
+        set64(&mut bs, 0x30, mft_runs[0].lcn.unwrap_or(0));
+        if let Some(lcn) = mirror.first().and_then(|r| r.lcn) {
+            set64(&mut bs, 0x38, lcn);
+        }
+        fs.dev.write_at(total_sectors * fs.bytes_per_sector, &bs)?;
+        fs.dev.write_at(0, &bs)?;
+        fs.dev.flush()?;
+        Ok(mft_runs)
+    }
+}
+
+
+////////////////////////// FILESYSTEM IMPL //////////////////////////////
+impl<'a> Filesystem<'a> for NtfsFs<'a> {
+    type File = NtfsFile;
+
+    fn probe(dev: &PartitionDevice) -> bool {
+        matches!(probe(dev).map(|info| info.kind), Some(FsKind::Ntfs))
+    }
+
+    fn mount(dev: PartitionDevice<'a>) -> uefi::Result<Self> {
+        let info = match probe(&dev) {
+            Some(info) if info.kind == FsKind::Ntfs => info,
+            _ => return Err(Status::UNSUPPORTED.into())
+        };
+        let mut bs = [0u8; 512];
+        dev.read_at(0, &mut bs)?;
+
+        // parse the boot sector, sizes that don't even fit fail the checks below
+        let bytes_per_sector = le16(&bs, 11) as u64;
+        let (cluster_size, record_size) = boot_sizes(&bs, bytes_per_sector).unwrap_or((0, 0));
+        let total_sectors = le64(&bs, 0x28);
+        if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 256 || bytes_per_sector > 4096
+            || cluster_size == 0 || cluster_size > 2 * 1024 * 1024
+            || record_size < FIXUP_STRIDE as u64 || record_size > 64 * 1024 {
+            error!("NTFS boot sector is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        if total_sectors.checked_mul(bytes_per_sector).map_or(true, |size| size > dev.size()) {
+            error!("NTFS filesystem is bigger than its partition");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+
+        // the start of the MFT is enough to read its own record, which then
+        // says where the rest of it is
+        let mut fs = NtfsFs {
+            dev,
+            label:          info.label,
+            uuid:           info.uuid,
+            bytes_per_sector,
+            cluster_size,
+            record_size,
+            total_sectors,
+            mft_runs:       vec![Run {
+                vcn: 0,
+                lcn: Some(le64(&bs, 0x30)),
+                len: (record_size + cluster_size - 1) / cluster_size
+            }],
+            version:        (0, 0),
+            volume_flags:   0
+        };
+        let mft = parse_record(&fs.read_record(MFT_RECORD)?)?;
+        fs.mft_runs = data_runs(&mft)?;
+
+        // a badly fragmented MFT continues in extension records, which can
+        // only be found once the first part is known
+        if mft.iter().any(|a| a.attr_type == ATTR_ATTRIBUTE_LIST) {
+            let (_, mft) = fs.attributes(MFT_RECORD)?;
+            fs.mft_runs = data_runs(&mft)?;
+        }
+
+        let (_, volume) = fs.attributes(VOLUME_RECORD)?;
+        if let Some(attr) = volume.iter().find(|a| a.attr_type == ATTR_VOLUME_INFORMATION) {
+            let info = fs.attr_value(attr)?;
+            if info.len() >= 12 {
+                fs.version = (info[8], info[9]);
+                fs.volume_flags = le16(&info, 10);
+            }
+        }
+        if fs.is_dirty() {
+            warn!("NTFS volume is marked dirty, Windows wants to check it");
+        }
+
+        // make sure the root directory is actually there
+        let (flags, _) = fs.attributes(ROOT_RECORD)?;
+        if flags & RECORD_IS_DIR == 0 {
+            error!("NTFS root directory is damaged");
+            return Err(Status::VOLUME_CORRUPTED.into());
+        }
+        Ok(fs)
+    }
+
+    fn name(&self) -> &'static str {
+        "NTFS"
+    }
+
+    fn label(&self) -> Option<String> {
+        self.label.clone()
+    }
+
+    fn uuid(&self) -> Option<String> {
+        self.uuid.clone()
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.total_sectors * self.bytes_per_sector
+    }
+
+    fn used_bytes(&mut self) -> uefi::Result<u64> {
+        // count the clusters marked in use in $Bitmap
+        let (_, attrs) = self.attributes(BITMAP_RECORD)?;
+        let bitmap = match attrs.iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+            Some(bitmap) => bitmap.clone(),
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+        let cache = RefCell::new(None);
+
+        let total = self.total_clusters();
+        let mut buf = vec![0u8; BITMAP_CHUNK_BYTES as usize];
+        let mut used = 0u64;
+        let mut offset = 0;
+        while offset * 8 < total {
+            let len = self.read_data(&bitmap, &cache, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            for (i, b) in buf[..len].iter().enumerate() {
+                // the last byte may cover clusters past the end
+                let first = (offset + i as u64) * 8;
+                let bits = cmp::min(8, total.saturating_sub(first)) as u32;
+                used += (*b as u32 & ((1u32 << bits) - 1)).count_ones() as u64;
+            }
+            offset += len as u64;
+        }
+        Ok(used * self.cluster_size)
+    }
+
+    fn used_ranges(&mut self) -> uefi::Result<Vec<Range<u64>>> {
+        // $Bitmap covers the boot sector and every other metadata file too
+        let (_, attrs) = self.attributes(BITMAP_RECORD)?;
+        let bitmap = match attrs.iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty()) {
+            Some(bitmap) => bitmap.clone(),
+            None => return Err(Status::VOLUME_CORRUPTED.into())
+        };
+        let cache = RefCell::new(None);
+
+        let total = self.total_clusters();
+        let mut buf = vec![0u8; BITMAP_CHUNK_BYTES as usize];
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while offset * 8 < total {
+            let len = self.read_data(&bitmap, &cache, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            let first = offset * 8;
+            for cluster in first..cmp::min(total, first + len as u64 * 8) {
+                if bit_is_set(&buf, cluster - first) {
+                    let start = cluster * self.cluster_size;
+                    add_range(&mut ranges, start..start + self.cluster_size);
+                }
+            }
+            offset += len as u64;
+        }
+
+        // except the backup boot sector, just past the last cluster
+        let end = self.total_bytes();
+        add_range(&mut ranges, end..end + self.bytes_per_sector);
+        Ok(ranges)
+    }
+
+    fn read_dir(&mut self, path: &str) -> uefi::Result<Vec<DirEntry>> {
+        let record = self.lookup(path)?;
+        let mut entries = Vec::new();
+        for entry in self.index_entries(record)? {
+            // skip 8.3 aliases, the directory itself and the metadata files
+            if entry.namespace == NAMESPACE_DOS || entry.record == record
+                || (entry.record < FIRST_USER_RECORD && entry.name.starts_with('$')) {
+                continue;
+            }
+            // note the size in the index is only updated lazily, so ask the file
+            let size = match entry.is_dir {
+                true => 0,
+                false => self.file_size(entry.record)?
+            };
+            entries.push(DirEntry {
+                name:   entry.name,
+                is_dir: entry.is_dir,
+                size
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open(&mut self, path: &str) -> uefi::Result<NtfsFile> {
+        let record = self.lookup(path)?;
+        let (flags, attrs) = self.attributes(record)?;
+        let is_dir = flags & RECORD_IS_DIR != 0;
+        Ok(NtfsFile {
+            is_dir,
+            data:       match is_dir {
+                true => None,
+                false => attrs.into_iter().find(|a| a.attr_type == ATTR_DATA && a.name.is_empty())
+            },
+            unit_cache: RefCell::new(None)
+        })
+    }
+
+    fn read_at(&mut self, file: &NtfsFile, offset: u64, buf: &mut [u8]) -> uefi::Result<usize> {
+        if file.is_dir {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        match &file.data {
+            Some(data) => self.read_data(data, &file.unit_cache, offset, buf),
+            None => Ok(0)
+        }
+    }
+
+    fn min_size(&mut self) -> uefi::Result<u64> {
+        // everything in use, plus the backup boot sector after the last cluster
+        // note fragmented files may still need more, runs are only moved whole
+        Ok(self.used_bytes()? + self.bytes_per_sector)
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        let resizable = !self.dev.is_read_only() && !self.is_dirty();
+        FsCapabilities {
+            grow:   resizable,
+            shrink: resizable,
+            ..FsCapabilities::default()
+        }
+    }
+
+    /// note like ntfsresize this leaves the volume marked dirty, so Windows
+    /// checks it on its next boot
+    fn resize(&mut self, num_sectors: u64) -> uefi::Result {
+        self.check_resizable()?;
+
+        // the last sector holds the backup boot sector
+        let sectors = num_sectors * self.dev.blocksize() as u64 / self.bytes_per_sector;
+        if sectors * self.bytes_per_sector > self.dev.size() {
+            warn!("Partition has to be grown before the filesystem");
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        if sectors < 2 {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let total_sectors = sectors - 1;
+        let clusters = total_sectors * self.bytes_per_sector / self.cluster_size;
+        if total_sectors == self.total_sectors {
+            return Ok(());
+        }
+        info!(
+            "Resizing NTFS volume from {} to {} clusters",
+            self.total_clusters(),
+            clusters
+        );
+
+        let mft_runs = {
+            let mut resize = Resize::new(self, clusters)?;
+            if clusters < self.total_clusters() {
+                resize.relocate()?;
+            }
+            resize.resize_bitmap_file()?;
+            resize.resize_bad_clusters()?;
+            resize.mark_dirty()?;
+            resize.commit(total_sectors)?
+        };
+        self.mft_runs = mft_runs;
+        self.total_sectors = total_sectors;
+        self.volume_flags |= VOLUME_DIRTY;
+        Ok(())
+    }
+}
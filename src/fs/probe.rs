@@ -0,0 +1,528 @@
+// Includes the probe registry, which works out what is on a partition by reading
+// its superblocks and boot sectors rather than trusting the partition type
+//
+// The offsets used here follow what libblkid looks at, see
+// https://github.com/util-linux/util-linux/tree/master/libblkid/src/superblocks
+use alloc::string::String;
+use core::convert::TryInto;
+
+use super::device::PartitionDevice;
+
+/// defines the kinds of filesystems (and other volume contents) we can recognise
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FsKind {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+    Ntfs,
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Swap,
+    Luks,
+    LvmPv,
+    Iso9660,
+    HfsPlus,
+    Apfs
+}
+
+/// defines what a probe found on a partition
+#[derive(Clone,Debug,PartialEq)]
+pub struct FsInfo {
+    pub kind:   FsKind,
+    pub label:  Option<String>,
+    pub uuid:   Option<String>
+}
+
+/// defines a probe, which returns None if the partition doesn't hold its filesystem
+type ProbeFn = fn(&PartitionDevice) -> Option<FsInfo>;
+
+/// every probe we know of
+/// note the order matters, the FAT probe is the least picky so it comes last
+const PROBES: [ProbeFn; 12] = [
+    probe_luks,
+    probe_lvm,
+    probe_swap,
+    probe_exfat,
+    probe_ntfs,
+    probe_ext,
+    probe_xfs,
+    probe_btrfs,
+    probe_iso9660,
+    probe_hfsplus,
+    probe_apfs,
+    probe_fat
+];
+
+
+////////////////////////// FSKIND IMPL //////////////////////////////
+impl FsKind {
+    /// returns the name of the filesystem, as blkid would report it
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsKind::Fat12 => "vfat (FAT12)",
+            FsKind::Fat16 => "vfat (FAT16)",
+            FsKind::Fat32 => "vfat (FAT32)",
+            FsKind::ExFat => "exfat",
+            FsKind::Ntfs => "ntfs",
+            FsKind::Ext2 => "ext2",
+            FsKind::Ext3 => "ext3",
+            FsKind::Ext4 => "ext4",
+            FsKind::Xfs => "xfs",
+            FsKind::Btrfs => "btrfs",
+            FsKind::Swap => "swap",
+            FsKind::Luks => "crypto_LUKS",
+            FsKind::LvmPv => "LVM2_member",
+            FsKind::Iso9660 => "iso9660",
+            FsKind::HfsPlus => "hfsplus",
+            FsKind::Apfs => "apfs"
+        }
+    }
+}
+
+
+/// works out what is on the partition behind `dev`
+pub fn probe(dev: &PartitionDevice) -> Option<FsInfo> {
+    PROBES.iter().find_map(|probe| probe(dev))
+}
+
+/// works out how many bytes the filesystem on `dev` spans, None if nothing we
+/// recognise is there or we don't know where its kind records its size
+pub fn fs_size(dev: &PartitionDevice) -> Option<u64> {
+    match probe(dev)?.kind {
+        FsKind::Fat12 | FsKind::Fat16 | FsKind::Fat32 => {
+            let bs: [u8; 512] = read(dev, 0)?;
+            let total = match le16(&bs, 19) {
+                0 => le32(&bs, 32) as u64,
+                total => total as u64
+            };
+            Some(total * le16(&bs, 11) as u64)
+        },
+        FsKind::ExFat => {
+            // sectors are 512 bytes to 4 KiB
+            let bs: [u8; 512] = read(dev, 0)?;
+            match bs[108] {
+                9..=12 => le64(&bs, 72).checked_mul(1 << bs[108]),
+                _ => None
+            }
+        },
+        FsKind::Ntfs => {
+            // the backup boot sector sits just past the sectors the volume counts
+            let bs: [u8; 512] = read(dev, 0)?;
+            le64(&bs, 0x28).checked_add(1)?.checked_mul(le16(&bs, 11) as u64)
+        },
+        FsKind::Ext2 | FsKind::Ext3 | FsKind::Ext4 => {
+            let sb: [u8; 1024] = read(dev, 1024)?;
+            let mut blocks = le32(&sb, 4) as u64;
+            if le32(&sb, 96) & 0x80 != 0 {
+                blocks |= (le32(&sb, 0x150) as u64) << 32;
+            }
+            // blocks are 1 KiB to 64 KiB
+            match le32(&sb, 24) {
+                0..=6 => blocks.checked_mul(1024 << le32(&sb, 24)),
+                _ => None
+            }
+        },
+        FsKind::Xfs => {
+            let sb: [u8; 512] = read(dev, 0)?;
+            let block_size = u32::from_be_bytes(sb[4..8].try_into().unwrap()) as u64;
+            u64::from_be_bytes(sb[8..16].try_into().unwrap()).checked_mul(block_size)
+        },
+        FsKind::Btrfs => {
+            let sb: [u8; 4096] = read(dev, 0x10000)?;
+            Some(le64(&sb, 0x70))
+        },
+        _ => None
+    }
+}
+
+/// reads `N` bytes at byte `offset` of the partition, None if that isn't possible
+fn read<const N: usize>(dev: &PartitionDevice, offset: u64) -> Option<[u8; N]> {
+    let mut buf = [0u8; N];
+    dev.read_at(offset, &mut buf).ok()?;
+    Some(buf)
+}
+
+/// helper function to read little endian integers out of a buffer
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// turns a space or NUL padded label into a string, None if it is blank
+fn ascii_label(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    let label = String::from_utf8_lossy(&bytes[..end]);
+    match label.trim_end() {
+        "" => None,
+        label => Some(String::from(label))
+    }
+}
+
+/// turns a NUL padded UTF-16 LE label into a string, None if it is blank
+fn utf16_label(bytes: &[u8]) -> Option<String> {
+    let chars = bytes.chunks_exact(2)
+                     .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                     .take_while(|c| *c != 0);
+    let label: String = core::char::decode_utf16(chars)
+                            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                            .collect();
+    match label.trim_end() {
+        "" => None,
+        label => Some(String::from(label))
+    }
+}
+
+/// formats 16 raw bytes as a dashed UUID, e.g. for ext4 and XFS
+fn format_uuid(bytes: &[u8]) -> Option<String> {
+    if bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    let mut uuid = String::new();
+    for (i, b) in bytes.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            uuid.push('-');
+        }
+        uuid.push_str(&format!("{:02x}", b));
+    }
+    Some(uuid)
+}
+
+/// detects FAT12, FAT16 and FAT32 from the BIOS parameter block
+fn probe_fat(dev: &PartitionDevice) -> Option<FsInfo> {
+    let bs: [u8; 512] = read(dev, 0)?;
+    if bs[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+
+    // make sure the BPB is sane before believing any of it
+    let bytes_per_sector = le16(&bs, 11) as u64;
+    let sectors_per_cluster = bs[13] as u64;
+    let reserved = le16(&bs, 14) as u64;
+    let num_fats = bs[16] as u64;
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved == 0
+        || num_fats == 0 {
+        return None;
+    }
+
+    // the FAT type is decided purely by the number of clusters
+    let root_dir_sectors = (le16(&bs, 17) as u64 * 32 + bytes_per_sector - 1) / bytes_per_sector;
+    let fat_size = match le16(&bs, 22) {
+        0 => le32(&bs, 36) as u64,
+        size => size as u64
+    };
+    let total = match le16(&bs, 19) {
+        0 => le32(&bs, 32) as u64,
+        total => total as u64
+    };
+    let meta = reserved + num_fats * fat_size + root_dir_sectors;
+    if fat_size == 0 || total <= meta {
+        return None;
+    }
+    let clusters = (total - meta) / sectors_per_cluster;
+    let (kind, ext) = match clusters {
+        c if c < 4085 => (FsKind::Fat12, 36),
+        c if c < 65525 => (FsKind::Fat16, 36),
+        _ => (FsKind::Fat32, 64)
+    };
+
+    // the serial and label are only there if the extended boot signature is
+    let (label, uuid) = match bs[ext + 2] {
+        0x28 | 0x29 => {
+            let serial = le32(&bs, ext + 3);
+            let label = match ascii_label(&bs[ext + 7..ext + 18]) {
+                Some(label) if label == "NO NAME" => None,
+                label => label
+            };
+            (label, Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)))
+        },
+        _ => (None, None)
+    };
+    Some(FsInfo { kind, label, uuid })
+}
+
+/// detects exFAT, whose label lives in the root directory
+fn probe_exfat(dev: &PartitionDevice) -> Option<FsInfo> {
+    let bs: [u8; 512] = read(dev, 0)?;
+    if bs[3..11] != *b"EXFAT   " {
+        return None;
+    }
+    let serial = le32(&bs, 100);
+    let uuid = Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff));
+
+    // look for the volume label entry in the first cluster of the root directory
+    let sector_shift = bs[108] as u64;
+    let cluster_shift = bs[109] as u64;
+    let heap_offset = le32(&bs, 88) as u64;
+    let root_cluster = le32(&bs, 96) as u64;
+    let mut label = None;
+    if sector_shift >= 9 && sector_shift <= 12 && cluster_shift <= 25 - sector_shift && root_cluster >= 2 {
+        let cluster_bytes = 1u64 << (sector_shift + cluster_shift);
+        let offset = (heap_offset << sector_shift) + (root_cluster - 2) * cluster_bytes;
+        let mut dir = vec![0u8; cluster_bytes as usize];
+        if dev.read_at(offset, &mut dir).is_ok() {
+            for entry in dir.chunks_exact(32) {
+                match entry[0] {
+                    0x00 => break,
+                    0x83 => {
+                        let len = core::cmp::min(entry[1] as usize, 11);
+                        label = utf16_label(&entry[2..2 + len * 2]);
+                        break;
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+    Some(FsInfo { kind: FsKind::ExFat, label, uuid })
+}
+
+/// detects NTFS, whose label lives in the $Volume file of the MFT
+fn probe_ntfs(dev: &PartitionDevice) -> Option<FsInfo> {
+    let bs: [u8; 512] = read(dev, 0)?;
+    if bs[3..11] != *b"NTFS    " {
+        return None;
+    }
+    let uuid = Some(format!("{:016X}", le64(&bs, 0x48)));
+
+    // work out where the MFT is and how big its records are
+    let bytes_per_sector = le16(&bs, 11) as u64;
+    // big clusters are stored as the log2 of the sectors per cluster, note a
+    // damaged boot sector can ask for shifts past 64 bits
+    let cluster_size = match bs[13] {
+        s if s > 0x80 => 1u64.checked_shl(256 - s as u32).and_then(|c| c.checked_mul(bytes_per_sector)),
+        s => Some(s as u64 * bytes_per_sector)
+    };
+    let record_size = match bs[0x40] as i8 {
+        s if s < 0 => 1u64.checked_shl(-(s as i32) as u32),
+        s => cluster_size.and_then(|c| c.checked_mul(s as u64))
+    };
+    let (cluster_size, record_size) = match (cluster_size, record_size) {
+        (Some(c), Some(r)) if bytes_per_sector >= 256 && r >= 512 && r <= 4096 => (c, r),
+        _ => return Some(FsInfo { kind: FsKind::Ntfs, label: None, uuid })
+    };
+
+    // $Volume is always record 3, and the first records are never fragmented
+    let offset = match le64(&bs, 0x30).checked_mul(cluster_size).and_then(|o| o.checked_add(3 * record_size)) {
+        Some(offset) => offset,
+        None => return Some(FsInfo { kind: FsKind::Ntfs, label: None, uuid })
+    };
+    let mut record = vec![0u8; record_size as usize];
+    let label = match dev.read_at(offset, &mut record) {
+        Ok(_) => ntfs_volume_name(&mut record),
+        Err(_) => None
+    };
+    Some(FsInfo { kind: FsKind::Ntfs, label, uuid })
+}
+
+/// pulls the volume name attribute out of the $Volume MFT record
+fn ntfs_volume_name(record: &mut [u8]) -> Option<String> {
+    if record[0..4] != *b"FILE" {
+        return None;
+    }
+
+    // undo the update sequence, which swaps out the last 2 bytes of every sector
+    let usa_offset = le16(record, 4) as usize;
+    let usa_count = le16(record, 6) as usize;
+    for i in 1..usa_count {
+        let end = i * 512 - 2;
+        if end + 2 > record.len() || usa_offset + i * 2 + 2 > record.len() {
+            return None;
+        }
+        let fixup = [record[usa_offset + i * 2], record[usa_offset + i * 2 + 1]];
+        record[end..end + 2].copy_from_slice(&fixup);
+    }
+
+    // walk the attributes looking for $VOLUME_NAME (0x60)
+    let mut offset = le16(record, 0x14) as usize;
+    while offset + 0x18 <= record.len() {
+        let attr_type = le32(record, offset);
+        let attr_len = le32(record, offset + 4) as usize;
+        if attr_type == 0xffff_ffff || attr_len == 0 {
+            break;
+        }
+        if attr_type == 0x60 && record[offset + 8] == 0 {
+            let len = le32(record, offset + 0x10) as usize;
+            let start = offset + le16(record, offset + 0x14) as usize;
+            return utf16_label(record.get(start..start + len)?);
+        }
+        offset += attr_len;
+    }
+    None
+}
+
+/// detects ext2, ext3 and ext4, telling them apart by their feature flags
+fn probe_ext(dev: &PartitionDevice) -> Option<FsInfo> {
+    let sb: [u8; 1024] = read(dev, 1024)?;
+    if le16(&sb, 56) != 0xef53 {
+        return None;
+    }
+    let compat = le32(&sb, 92);
+    let incompat = le32(&sb, 96);
+    let ro_compat = le32(&sb, 100);
+
+    // anything ext3 can't mount makes it ext4 (extents, 64bit, flex_bg, ...)
+    let kind = if incompat & !0x0007 != 0 || ro_compat & !0x0007 != 0 {
+        FsKind::Ext4
+    } else if compat & 0x0004 != 0 {
+        FsKind::Ext3
+    } else {
+        FsKind::Ext2
+    };
+    Some(FsInfo {
+        kind,
+        label: ascii_label(&sb[120..136]),
+        uuid: format_uuid(&sb[104..120])
+    })
+}
+
+/// detects XFS
+fn probe_xfs(dev: &PartitionDevice) -> Option<FsInfo> {
+    let sb: [u8; 512] = read(dev, 0)?;
+    if sb[0..4] != *b"XFSB" {
+        return None;
+    }
+    Some(FsInfo {
+        kind: FsKind::Xfs,
+        label: ascii_label(&sb[108..120]),
+        uuid: format_uuid(&sb[32..48])
+    })
+}
+
+/// detects Btrfs, whose superblock lives 64 KiB in
+fn probe_btrfs(dev: &PartitionDevice) -> Option<FsInfo> {
+    let sb: [u8; 4096] = read(dev, 0x10000)?;
+    if sb[0x40..0x48] != *b"_BHRfS_M" {
+        return None;
+    }
+    Some(FsInfo {
+        kind: FsKind::Btrfs,
+        label: ascii_label(&sb[0x12b..0x22b]),
+        uuid: format_uuid(&sb[0x20..0x30])
+    })
+}
+
+/// detects Linux swap, whose signature sits at the end of the first page
+fn probe_swap(dev: &PartitionDevice) -> Option<FsInfo> {
+    // the page size of whoever ran mkswap isn't recorded, so try the usual ones
+    for page_size in [4096u64, 8192, 16384, 65536].iter() {
+        let sig: [u8; 10] = match read(dev, page_size - 10) {
+            Some(sig) => sig,
+            None => continue
+        };
+        if sig == *b"SWAPSPACE2" || sig == *b"SWAP-SPACE" {
+            // only version 1 headers have a label and UUID
+            let header: [u8; 48] = read(dev, 1024)?;
+            let (label, uuid) = match sig == *b"SWAPSPACE2" {
+                true => (ascii_label(&header[28..44]), format_uuid(&header[12..28])),
+                false => (None, None)
+            };
+            return Some(FsInfo { kind: FsKind::Swap, label, uuid });
+        }
+    }
+    None
+}
+
+/// detects LUKS 1 and 2 encrypted volumes
+fn probe_luks(dev: &PartitionDevice) -> Option<FsInfo> {
+    let header: [u8; 512] = read(dev, 0)?;
+    if header[0..6] != *b"LUKS\xba\xbe" {
+        return None;
+    }
+
+    // note only LUKS 2 has a label, the UUID is stored as text in both
+    let label = match u16::from_be_bytes([header[6], header[7]]) {
+        2 => ascii_label(&header[24..72]),
+        _ => None
+    };
+    Some(FsInfo {
+        kind: FsKind::Luks,
+        label,
+        uuid: ascii_label(&header[168..208])
+    })
+}
+
+/// detects LVM physical volumes, whose label can be in any of the first 4 sectors
+fn probe_lvm(dev: &PartitionDevice) -> Option<FsInfo> {
+    for sector in 0..4u64 {
+        let label: [u8; 64] = read(dev, sector * 512)?;
+        if label[0..8] != *b"LABELONE" || label[24..32] != *b"LVM2 001" {
+            continue;
+        }
+
+        // the PV UUID is 32 characters, shown in groups like lvm does
+        let mut uuid = String::new();
+        for (i, c) in label[32..64].iter().enumerate() {
+            if [6, 10, 14, 18, 22, 26].contains(&i) {
+                uuid.push('-');
+            }
+            uuid.push(*c as char);
+        }
+        return Some(FsInfo { kind: FsKind::LvmPv, label: None, uuid: Some(uuid) });
+    }
+    None
+}
+
+/// detects ISO9660 images, e.g. a dd'd installer
+fn probe_iso9660(dev: &PartitionDevice) -> Option<FsInfo> {
+    let pvd: [u8; 2048] = read(dev, 32768)?;
+    if pvd[0] != 1 || pvd[1..6] != *b"CD001" {
+        return None;
+    }
+
+    // like blkid, use the creation date as the UUID
+    let date = &pvd[813..829];
+    let uuid = match date.iter().all(|c| c.is_ascii_digit()) && date.iter().any(|c| *c != b'0') {
+        true => {
+            let mut uuid = String::new();
+            for (i, c) in date.iter().enumerate() {
+                if i >= 4 && i % 2 == 0 {
+                    uuid.push('-');
+                }
+                uuid.push(*c as char);
+            }
+            Some(uuid)
+        },
+        false => None
+    };
+    Some(FsInfo { kind: FsKind::Iso9660, label: ascii_label(&pvd[40..72]), uuid })
+}
+
+/// detects HFS+ (and HFSX)
+/// note the label is in the catalog file, which we don't read
+fn probe_hfsplus(dev: &PartitionDevice) -> Option<FsInfo> {
+    let header: [u8; 512] = read(dev, 1024)?;
+    if header[0..2] != *b"H+" && header[0..2] != *b"HX" {
+        return None;
+    }
+
+    // blkid hashes this into a UUID, we just show the raw volume id
+    let id = u64::from_be_bytes(header[104..112].try_into().unwrap());
+    let uuid = match id {
+        0 => None,
+        id => Some(format!("{:016X}", id))
+    };
+    Some(FsInfo { kind: FsKind::HfsPlus, label: None, uuid })
+}
+
+/// detects APFS containers
+/// note the volume names are inside the container, which we don't read
+fn probe_apfs(dev: &PartitionDevice) -> Option<FsInfo> {
+    let sb: [u8; 512] = read(dev, 0)?;
+    if sb[32..36] != *b"NXSB" {
+        return None;
+    }
+    Some(FsInfo { kind: FsKind::Apfs, label: None, uuid: format_uuid(&sb[72..88]) })
+}
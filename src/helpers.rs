@@ -3,11 +3,20 @@ use uefi::prelude::*;
 use uefi::table::boot::{
     BootServices,
     MemoryDescriptor,
-    OpenProtocolParams
+    MemoryType,
+    OpenProtocolAttributes,
+    OpenProtocolParams,
+    ScopedProtocol
 };
+use uefi::proto::console::text::Key;
 use uefi::proto::media::block::BlockIO;
+use uefi::proto::rng::Rng;
+use uefi::Guid;
 
 use crate::alloc::vec::Vec;
+use crate::partitions::Extent;
+use crate::partitions::gpt::bytes_to_guid;
+use crate::fs::probe::FsInfo;
 use core::mem;
 use core::convert::TryInto;
 
@@ -15,31 +24,247 @@ use core::convert::TryInto;
 /// define a return type that we can use to help keep all information needed together
 pub struct BootRecord {
     pub data: [u8; 512],
-    pub media_id: u32
+    pub media_id: u32,
+    pub last_block: u64,
+    pub handle: Handle
 }
 
 
 
-/// helps determine the total free space in RAM
-pub fn get_free_ram_size(services: &BootServices) -> u64 {
+/// lookup table for the CRC32 (IEEE 802.3) polynomial used by GPT
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+
+/// lookup table for the CRC32C (Castagnoli) polynomial used by ext4
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// lookup table for the CRC16 (ANSI) polynomial used by older ext4 group descriptors
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+
+/// calculates the CRC32 of a buffer (same flavour as the GPT header checksums)
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// continues a CRC32 calculation over another buffer
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data.iter() {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+
+/// continues a CRC32C calculation over another buffer
+/// note unlike `crc32_update` nothing is inverted, ext4 leaves that to the caller
+pub fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc;
+    for byte in data.iter() {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// continues a CRC16 calculation over another buffer, again without inverting
+pub fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in data.iter() {
+        crc = CRC16_TABLE[((crc ^ *byte as u16) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// generates a new random (version 4) GUID
+///
+/// Fails if the firmware has no RNG and we have nothing to fall back on
+pub fn random_guid(bs: &BootServices) -> uefi::Result<Guid> {
+    let mut bytes = [0u8; 16];
+
+    // prefer the firmware's RNG, but not every firmware has one
+    let filled = match bs.locate_protocol::<Rng>() {
+        Ok(rng) => unsafe{&mut *rng.get()}.get_rng(None, &mut bytes).is_ok(),
+        Err(_) => false
+    };
+    if !filled {
+        fallback_random(&mut bytes)?;
+    }
+
+    // mark it as a version 4, variant 1 GUID
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(bytes_to_guid(bytes))
+}
+
+/// fills `bytes` with a xorshift seeded from the timestamp counter, which is
+/// plenty for GUIDs that only have to be unique
+#[cfg(target_arch = "x86_64")]
+fn fallback_random(bytes: &mut [u8; 16]) -> uefi::Result {
+    let mut state = unsafe { core::arch::x86_64::_rdtsc() } | 1;
+    for chunk in bytes.chunks_mut(8) {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        chunk.copy_from_slice(&state.to_ne_bytes());
+    }
+    Ok(())
+}
+
+/// without a timestamp counter to seed from there is nothing to fall back on
+#[cfg(not(target_arch = "x86_64"))]
+fn fallback_random(_bytes: &mut [u8; 16]) -> uefi::Result {
+    error!("Firmware has no RNG protocol, can't generate a GUID");
+    Err(Status::UNSUPPORTED.into())
+}
+
+
+/// finds the BlockIO handle of the whole disk with the given media id
+/// note logical partitions share the media id of their disk, so we skip them
+pub fn find_block_io_handle(
+    bs: &BootServices,
+    img_handle: Handle,
+    media_id: u32
+) -> Option<Handle> {
+    // get all handles available for BlockIO operations
+    let handles = bs.find_handles::<BlockIO>()
+                    .expect("Failed to find handles for `BlockIO`");
+
+    // loop over all handles and see if they are for the media we want
+    for handle in handles {
+        let bi = match open_handle_block_io(bs, img_handle, handle) {
+            Some(bi) => bi,
+            None => continue
+        };
+
+        let media = unsafe{&*bi.interface.get()}.media();
+        if media.media_id() == media_id && !media.is_logical_partition() {
+            return Some(handle);
+        }
+    }
+
+    None
+}
+
+/// opens the BlockIO protocol of a specific handle
+pub fn open_handle_block_io<'a>(
+    bs: &'a BootServices,
+    img_handle: Handle,
+    handle: Handle
+) -> Option<ScopedProtocol<'a, BlockIO>> {
+    let params = OpenProtocolParams{handle, agent: img_handle, controller: None};
+    bs.open_protocol::<BlockIO>(params, OpenProtocolAttributes::Exclusive).ok()
+}
+
+/// opens the BlockIO protocol for the whole disk with the given media id
+pub fn open_block_io<'a>(
+    bs: &'a BootServices,
+    img_handle: Handle,
+    media_id: u32
+) -> Option<ScopedProtocol<'a, BlockIO>> {
+    let handle = find_block_io_handle(bs, img_handle, media_id)?;
+    open_handle_block_io(bs, img_handle, handle)
+}
+
+
+/// returns a copy of the current memory map
+fn memory_map(services: &BootServices) -> Vec<MemoryDescriptor> {
     // get the memory size of the current memory map
     let mm_size = services.memory_map_size().map_size + 8 * mem::size_of::<MemoryDescriptor>();
 
     // get a vector so we can store data in it
-    let mut buf: Vec<u8> = vec![0u8; mm_size];
-    let (_key, desc_iter) = services.memory_map(&mut buf)
+    // note the buffer has to be aligned like a `MemoryDescriptor`, so use u64s
+    let mut buf: Vec<u64> = vec![0u64; mm_size / 8 + 1];
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len() * 8)
+    };
+    let (_key, desc_iter) = services.memory_map(buf)
                                     .expect("Failed to retrieve memory map");
-    
-    // loop over each descriptor and count its size
+    desc_iter.copied().collect()
+}
+
+/// helps determine the total free space in RAM
+/// note only conventional memory counts, everything else belongs to someone
+pub fn get_free_ram_size(services: &BootServices) -> u64 {
+    // loop over each free descriptor and count its size
     let mut mem_size = 0u64;
-    for desc in desc_iter {
-        mem_size += desc.page_count;
+    for desc in memory_map(services).iter() {
+        if desc.ty == MemoryType::CONVENTIONAL {
+            mem_size += desc.page_count;
+        }
     }
 
     // return the number of pages
     mem_size
 }
 
+/// determines the largest contiguous free region in RAM (in pages)
+pub fn get_largest_free_region(services: &BootServices) -> u64 {
+    memory_map(services).iter()
+                        .filter(|desc| desc.ty == MemoryType::CONVENTIONAL)
+                        .map(|desc| desc.page_count)
+                        .max()
+                        .unwrap_or(0)
+}
+
+
+/// blocks until the user presses a printable key and returns it
+pub fn read_char(st: &mut SystemTable<Boot>) -> char {
+    loop {
+        // wait for a key press so we aren't spinning the CPU
+        let mut events = [unsafe { st.stdin().wait_for_key_event().unsafe_clone() }];
+        st.boot_services()
+            .wait_for_event(&mut events)
+            .expect("Failed to wait for a key press");
+
+        if let Some(Key::Printable(c)) = st.stdin().read_key().expect("Failed to read key") {
+            return char::from(c);
+        }
+    }
+}
+
 
 /// function that prints system information
 pub fn print_system_info(st: &mut SystemTable<Boot>) {
@@ -65,6 +290,33 @@ pub fn print_system_info(st: &mut SystemTable<Boot>) {
         ram_size, 
         ram_size * 4096
     );
+    let largest = get_largest_free_region(st.boot_services());
+    info!(
+        "Largest free RAM region: {} pages ({} bytes)",
+        largest,
+        largest * 4096
+    );
+}
+
+/// prints a line describing a partition and what was found on it
+pub fn print_partition(name: &str, extent: Extent, info: Option<FsInfo>) {
+    match info {
+        Some(info) => info!(
+            "  {}: LBA {} - {}, {}, label: {}, UUID: {}",
+            name,
+            extent.first_lba(),
+            extent.last_lba(),
+            info.kind.name(),
+            info.label.as_deref().unwrap_or("-"),
+            info.uuid.as_deref().unwrap_or("-")
+        ),
+        None => info!(
+            "  {}: LBA {} - {}, unknown contents",
+            name,
+            extent.first_lba(),
+            extent.last_lba()
+        )
+    }
 }
 
 /// returns all disks protocol
@@ -105,7 +357,9 @@ pub fn read_all_bootsectors(st: &mut SystemTable<Boot>, img_handle: Handle) -> V
         ret.push(
             BootRecord{
                 data,
-                media_id
+                media_id,
+                last_block,
+                handle
             }
         );
     }
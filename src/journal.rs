@@ -0,0 +1,259 @@
+//! Keeps track of long running operations on disk, so that if we lose power
+//! halfway through shifting a partition we can pick up where we left off
+//! (or put everything back) the next time we are launched.
+//!
+//! The journal is a single sector that lives in space no partition uses. We
+//! try the first usable LBA (which is almost always free thanks to 1 MiB
+//! partition alignment) and then the last usable LBA of the GPT.
+
+use uefi::Guid;
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use core::convert::TryInto;
+
+use crate::helpers::crc32;
+use crate::partitions::{
+    Extent,
+    GPTDisk
+};
+use crate::partitions::gpt::{
+    bytes_to_guid,
+    guid_to_bytes
+};
+
+/// the signature at the start of a journal sector
+const JOURNAL_SIG: [u8; 8] = *b"PTBLJRNL";
+
+/// the journal format version we write
+const JOURNAL_VERSION: u32 = 1;
+
+/// defines the operations that can be journaled
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum JournalOp {
+    Move,       // id 1, copy a partition and point its entry at the copy
+    Restore     // id 2, copy data back to where it came from (roll back)
+}
+
+/// defines a journal record
+///
+/// Data is copied in chunks of `chunk_sectors`, in the direction returned by
+/// `block_shifter::copy_direction`. Every chunk before `next_chunk` is done,
+/// and `chunk_crc` is the checksum of the source data of `next_chunk`.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Journal {
+    lba:            u64, // where the journal itself lives
+    operation:      JournalOp,
+    part_guid:      Guid,
+    src_lba:        u64,
+    dst_lba:        u64,
+    num_sectors:    u64,
+    chunk_sectors:  u64,
+    next_chunk:     u64,
+    chunk_crc:      u32
+}
+
+
+////////////////////////// JOURNALOP IMPL //////////////////////////////
+impl JournalOp {
+    /// returns the on-disk id of the operation
+    fn id(&self) -> u32 {
+        match self {
+            JournalOp::Move => 1,
+            JournalOp::Restore => 2
+        }
+    }
+
+    /// parses an operation from its on-disk id
+    fn from_id(id: u32) -> Result<Self, ()> {
+        match id {
+            1 => Ok(JournalOp::Move),
+            2 => Ok(JournalOp::Restore),
+            _ => Err(())
+        }
+    }
+}
+
+
+////////////////////////// JOURNAL IMPL //////////////////////////////
+impl Journal {
+    /// creates a new journal record for an operation that hasn't started yet
+    pub fn new(
+        lba: u64,
+        operation: JournalOp,
+        part_guid: Guid,
+        src_lba: u64,
+        dst_lba: u64,
+        num_sectors: u64,
+        chunk_sectors: u64
+    ) -> Self {
+        Journal {
+            lba,
+            operation,
+            part_guid,
+            src_lba,
+            dst_lba,
+            num_sectors,
+            chunk_sectors,
+            next_chunk: 0,
+            chunk_crc: 0
+        }
+    }
+
+    /// parses a journal record from a raw sector
+    pub fn from_bytes(lba: u64, sector: &[u8]) -> Result<Self, ()> {
+        // make sure this is actually one of our journals and it isn't torn
+        if sector[0..8] != JOURNAL_SIG {
+            return Err(());
+        }
+        let crc = u32::from_ne_bytes(sector[80..84].try_into().unwrap());
+        if crc32(&sector[0..80]) != crc {
+            warn!("Found a journal at LBA {} with a bad checksum, ignoring it", lba);
+            return Err(());
+        }
+        let version = u32::from_ne_bytes(sector[8..12].try_into().unwrap());
+        if version != JOURNAL_VERSION {
+            warn!("Found a journal with unknown version {}, ignoring it", version);
+            return Err(());
+        }
+
+        let operation = JournalOp::from_id(u32::from_ne_bytes(sector[12..16].try_into().unwrap()))?;
+        Ok(Journal {
+            lba,
+            operation,
+            part_guid:      bytes_to_guid(sector[16..32].try_into().unwrap()),
+            src_lba:        u64::from_ne_bytes(sector[32..40].try_into().unwrap()),
+            dst_lba:        u64::from_ne_bytes(sector[40..48].try_into().unwrap()),
+            num_sectors:    u64::from_ne_bytes(sector[48..56].try_into().unwrap()),
+            chunk_sectors:  u64::from_ne_bytes(sector[56..64].try_into().unwrap()),
+            next_chunk:     u64::from_ne_bytes(sector[64..72].try_into().unwrap()),
+            chunk_crc:      u32::from_ne_bytes(sector[72..76].try_into().unwrap())
+        })
+    }
+
+    /// serialises the journal record into a sector
+    pub fn to_bytes(&self) -> [u8; 512] {
+        let mut sector = [0u8; 512];
+        sector[0..8].copy_from_slice(&JOURNAL_SIG);
+        sector[8..12].copy_from_slice(&JOURNAL_VERSION.to_ne_bytes());
+        sector[12..16].copy_from_slice(&self.operation.id().to_ne_bytes());
+        sector[16..32].copy_from_slice(&guid_to_bytes(self.part_guid));
+        sector[32..40].copy_from_slice(&self.src_lba.to_ne_bytes());
+        sector[40..48].copy_from_slice(&self.dst_lba.to_ne_bytes());
+        sector[48..56].copy_from_slice(&self.num_sectors.to_ne_bytes());
+        sector[56..64].copy_from_slice(&self.chunk_sectors.to_ne_bytes());
+        sector[64..72].copy_from_slice(&self.next_chunk.to_ne_bytes());
+        sector[72..76].copy_from_slice(&self.chunk_crc.to_ne_bytes());
+
+        // checksum everything so a torn write is never mistaken for a journal
+        let crc = crc32(&sector[0..80]);
+        sector[80..84].copy_from_slice(&crc.to_ne_bytes());
+        sector
+    }
+
+    /// finds a sector we can keep the journal in, avoiding the given extents
+    pub fn pick_location(disk: &GPTDisk, avoid: &[Extent]) -> Option<u64> {
+        let free = disk.free_space();
+        for lba in Journal::candidates(disk).iter() {
+            let sector = Extent::new(*lba, *lba);
+            if free.is_free(&sector) && !avoid.iter().any(|e| e.overlaps(&sector)) {
+                return Some(*lba);
+            }
+        }
+        None
+    }
+
+    /// returns the LBAs a journal may live in
+    fn candidates(disk: &GPTDisk) -> [u64; 2] {
+        [disk.header().first_lba(), disk.header().last_lba()]
+    }
+
+    /// looks for an interrupted operation on the disk
+    pub fn find(bi: &BlockIO, disk: &GPTDisk) -> Option<Self> {
+        let mut sector = vec![0u8; disk.blocksize() as usize];
+        for lba in Journal::candidates(disk).iter() {
+            if bi.read_blocks(disk.media_id(), *lba, &mut sector).is_err() {
+                continue;
+            }
+            if let Ok(journal) = Journal::from_bytes(*lba, &sector) {
+                return Some(journal);
+            }
+        }
+        None
+    }
+
+    /// writes the journal record to the disk and makes sure it sticks
+    pub fn write(&self, bi: &mut BlockIO, media_id: u32) -> uefi::Result {
+        let mut sector = vec![0u8; bi.media().block_size() as usize];
+        sector[..512].copy_from_slice(&self.to_bytes());
+        bi.write_blocks(media_id, self.lba, &sector)?;
+        bi.flush_blocks()
+    }
+
+    /// wipes the journal from the disk once the operation is finished
+    pub fn clear(&self, bi: &mut BlockIO, media_id: u32) -> uefi::Result {
+        let sector = vec![0u8; bi.media().block_size() as usize];
+        bi.write_blocks(media_id, self.lba, &sector)?;
+        bi.flush_blocks()
+    }
+
+    /// records that `chunk` is about to be written, and what its source looks like
+    pub fn start_chunk(&mut self, chunk: u64, chunk_crc: u32) {
+        self.next_chunk = chunk;
+        self.chunk_crc = chunk_crc;
+    }
+
+    /// returns the number of chunks the operation is split into
+    pub fn num_chunks(&self) -> u64 {
+        (self.num_sectors + self.chunk_sectors - 1) / self.chunk_sectors
+    }
+
+    /// checks to see if every chunk has been copied
+    pub fn is_copy_done(&self) -> bool {
+        self.next_chunk >= self.num_chunks()
+    }
+
+    /// returns the LBA the journal lives in
+    pub fn lba(&self) -> u64 {
+        self.lba
+    }
+
+    /// returns the journaled operation
+    pub fn operation(&self) -> JournalOp {
+        self.operation
+    }
+
+    /// returns the GUID of the partition being operated on
+    pub fn part_guid(&self) -> Guid {
+        self.part_guid
+    }
+
+    /// returns the first LBA being copied from
+    pub fn src_lba(&self) -> u64 {
+        self.src_lba
+    }
+
+    /// returns the first LBA being copied to
+    pub fn dst_lba(&self) -> u64 {
+        self.dst_lba
+    }
+
+    /// returns the number of sectors being copied
+    pub fn num_sectors(&self) -> u64 {
+        self.num_sectors
+    }
+
+    /// returns the number of sectors in each chunk
+    pub fn chunk_sectors(&self) -> u64 {
+        self.chunk_sectors
+    }
+
+    /// returns the index of the chunk that was in flight
+    pub fn next_chunk(&self) -> u64 {
+        self.next_chunk
+    }
+
+    /// returns the checksum of the source data of the chunk in flight
+    pub fn chunk_crc(&self) -> u32 {
+        self.chunk_crc
+    }
+}
@@ -23,6 +23,11 @@ use crate::alloc::vec::Vec;
 mod partitions;
 mod helpers;
 mod fs;
+mod block_shifter;
+mod journal;
+mod memory;
+mod cloner;
+mod table_backup;
 
 
 
@@ -46,7 +51,7 @@ fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
     // try to parse the MBRs of each bootsector
     let mut mbrs: Vec<partitions::MBR> = Vec::new();
     for bootsec in bootsectors.iter() {
-        match partitions::MBR::new(bootsec.data, bootsec.media_id) {
+        match partitions::MBR::new(bootsec.data, bootsec.media_id, bootsec.last_block) {
             Ok(a) => mbrs.push(a),
             Err(_) => ()
         }
@@ -67,13 +72,74 @@ fn efi_main(image: Handle, mut st: SystemTable<Boot>) -> Status {
         }
     }
 
+    // finish (or undo) anything we were doing when we last lost power
+    for gpt in gpts.iter_mut() {
+        if let Err(e) = block_shifter::recover_interrupted(&mut st, image, gpt) {
+            error!("Failed to recover interrupted operation: {:?}", e.status());
+        }
+    }
+
+    // check if any disk grew since its GPT was created
+    for gpt in gpts.iter_mut() {
+        if gpt.backup_misplaced() {
+            warn!(
+                "Backup GPT is at LBA {}, but the disk ends at LBA {}",
+                gpt.header().backup_lba(),
+                gpt.last_block()
+            );
+            info!("Press 'y' to move the backup GPT to the end of the disk, anything else to skip");
+            if helpers::read_char(&mut st) == 'y' {
+                // keep a copy of the tables on the ESP in case anything goes wrong
+                let path = format!("\\{}.gpt", gpt.header().guid());
+                if let Err(e) = table_backup::backup_tables(&mut st, image, gpt.handle(), &path) {
+                    error!("Failed to back up the partition tables: {:?}", e.status());
+                } else if let Err(e) = block_shifter::fix_gpt_backup(&mut st, image, gpt) {
+                    error!("Failed to move the backup GPT: {:?}", e.status());
+                }
+            }
+        }
+    }
+
     // print the number of partitions in each MBR we found
     for part in mbrs.iter() {
         info!("Partition has {} non-empty partitions", part.count_partitions());
+        if part.is_gpt_pmbr() {
+            continue;
+        }
+
+        // show what is actually on each partition, the type byte is only a hint
+        if let Some(bi) = part.open(st.boot_services(), image) {
+            let used = part.partitions().iter()
+                           .enumerate()
+                           .filter(|(_, p)| p.part_type() != partitions::mbr::MbrPartTypes::Empty && p.num_sectors() > 0);
+            for (i, p) in used {
+                let dev = fs::PartitionDevice::new(bi.interface, p.extent());
+                helpers::print_partition(&format!("#{}", i + 1), p.extent(), fs::probe::probe(&dev));
+            }
+        }
     }
 
     for part in gpts.iter() {
         info!("GPT has {} partitions.", part.num_parts());
+
+        // show what is actually on each partition, the type GUID is only a hint
+        if let Some(bi) = part.open(st.boot_services(), image) {
+            for p in part.partitions().iter().filter(|p| p.is_used()) {
+                let dev = fs::PartitionDevice::new(bi.interface, p.extent());
+                helpers::print_partition(&p.name(), p.extent(), fs::probe::probe(&dev));
+            }
+        }
+
+        // report the largest free region so we know how much room we have to work with
+        match part.free_space().largest_gap() {
+            Some(gap) => info!(
+                "Largest free gap: LBA {} - {} ({} sectors)",
+                gap.first_lba(),
+                gap.last_lba(),
+                gap.len()
+            ),
+            None => info!("GPT has no free space")
+        }
     }
 
     // wait a bit, then shutdown
@@ -0,0 +1,119 @@
+//! Plans and allocates the big buffers we shuffle disk data through.
+//!
+//! These buffers come straight from `allocate_pages` rather than the pool
+//! allocator, so a huge buffer doesn't need to fit in whatever the pool has
+//! lying around and is handed back to the firmware as soon as we are done.
+
+use uefi::prelude::*;
+use uefi::table::boot::{
+    AllocateType,
+    MemoryType
+};
+use core::cmp;
+
+use crate::helpers::{
+    get_free_ram_size,
+    get_largest_free_region
+};
+
+/// the size of a UEFI page
+pub const PAGE_SIZE: u64 = 4096;
+
+/// memory we leave alone for the firmware and our own pool allocations
+const RESERVED_BYTES: u64 = 32 * 1024 * 1024;
+
+/// the largest single copy buffer we will ever allocate
+const MAX_BUFFER_BYTES: u64 = 64 * 1024 * 1024;
+
+/// defines a buffer made of UEFI pages, which are freed when it is dropped
+pub struct PageBuffer<'a> {
+    bs:     &'a BootServices,
+    addr:   u64,
+    pages:  usize,
+    len:    usize
+}
+
+
+////////////////////////// PAGEBUFFER IMPL //////////////////////////////
+impl<'a> PageBuffer<'a> {
+    /// allocates a zeroed buffer of `len` bytes
+    pub fn new(bs: &'a BootServices, len: usize) -> uefi::Result<Self> {
+        let pages = (len + PAGE_SIZE as usize - 1) / PAGE_SIZE as usize;
+        let addr = bs.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)?;
+
+        let mut buf = PageBuffer {
+            bs,
+            addr,
+            pages,
+            len
+        };
+        buf.as_mut_slice().fill(0);
+        Ok(buf)
+    }
+
+    /// returns the buffer as a slice
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.addr as *mut u8, self.len) }
+    }
+
+    /// returns the length of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// returns the number of whole sectors the buffer can hold
+    pub fn sectors(&self, blocksize: u32) -> u64 {
+        self.len as u64 / blocksize as u64
+    }
+}
+
+impl<'a> Drop for PageBuffer<'a> {
+    fn drop(&mut self) {
+        // there isn't much we can do if this fails, so just complain about it
+        if self.bs.free_pages(self.addr, self.pages).is_err() {
+            warn!("Failed to free {} pages at {:#x}", self.pages, self.addr);
+        }
+    }
+}
+
+
+/// works out how many bytes we can afford to spend on a single buffer
+pub fn copy_budget(bs: &BootServices) -> u64 {
+    // only conventional memory is up for grabs, and a single allocation can
+    // never be larger than the biggest contiguous free region
+    let free = get_free_ram_size(bs) * PAGE_SIZE;
+    let largest = get_largest_free_region(bs) * PAGE_SIZE;
+    cmp::min(
+        cmp::min(largest, free.saturating_sub(RESERVED_BYTES)),
+        MAX_BUFFER_BYTES
+    )
+}
+
+/// allocates the biggest copy buffer we reasonably can, holding at most `max_sectors`
+///
+/// If the firmware can't give us what the memory map promised we keep halving
+/// the request, and only fail if we can't even get a single sector
+pub fn allocate_copy_buffer<'a>(
+    bs: &'a BootServices,
+    blocksize: u32,
+    max_sectors: u64
+) -> uefi::Result<PageBuffer<'a>> {
+    let blocksize = blocksize as u64;
+    let mut sectors = cmp::max(
+        cmp::min(copy_budget(bs) / blocksize, max_sectors),
+        1
+    );
+
+    loop {
+        match PageBuffer::new(bs, (sectors * blocksize) as usize) {
+            Ok(buf) => return Ok(buf),
+            Err(e) => {
+                if sectors == 1 {
+                    return Err(e);
+                }
+                debug!("Failed to allocate {} sectors, trying {}", sectors, sectors / 2);
+                sectors /= 2;
+            }
+        }
+    }
+}
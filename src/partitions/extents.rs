@@ -0,0 +1,178 @@
+// Includes structs and APIs for tracking allocated and free regions of a disk
+use crate::alloc::vec::Vec;
+
+/// defines a contiguous range of LBAs, note both ends are inclusive (like GPT)
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Extent {
+    first_lba:  u64,
+    last_lba:   u64
+}
+
+/// defines a map of the used regions within the usable area of a disk
+pub struct FreeSpaceMap {
+    first_lba:  u64,
+    last_lba:   u64,
+    used:       Vec<Extent> // kept sorted by first_lba
+}
+
+
+/// helper function to round an LBA up to the next multiple of `alignment`
+pub fn align_up(lba: u64, alignment: u64) -> u64 {
+    if alignment <= 1 {
+        return lba;
+    }
+    match lba % alignment {
+        0 => lba,
+        rem => lba + (alignment - rem)
+    }
+}
+
+
+////////////////////////// EXTENT IMPL //////////////////////////////
+impl Extent {
+    /// creates a new extent covering `first_lba..=last_lba`
+    pub fn new(first_lba: u64, last_lba: u64) -> Self {
+        Extent {
+            first_lba,
+            last_lba
+        }
+    }
+
+    /// creates a new extent that starts at `first_lba` and spans `num_sectors`
+    pub fn with_len(first_lba: u64, num_sectors: u64) -> Self {
+        Extent::new(first_lba, first_lba + num_sectors - 1)
+    }
+
+    /// returns the first LBA of the extent
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    /// returns the last LBA of the extent
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
+    }
+
+    /// returns the number of sectors in the extent
+    pub fn len(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    /// checks to see if two extents share at least one sector
+    pub fn overlaps(&self, other: &Extent) -> bool {
+        self.first_lba <= other.last_lba && other.first_lba <= self.last_lba
+    }
+
+    /// checks to see if `other` lies entirely within this extent
+    pub fn contains(&self, other: &Extent) -> bool {
+        self.first_lba <= other.first_lba && other.last_lba <= self.last_lba
+    }
+}
+
+
+////////////////////////// FREESPACEMAP IMPL //////////////////////////////
+impl FreeSpaceMap {
+    /// creates an empty map spanning the usable LBAs `first_lba..=last_lba`
+    pub fn new(first_lba: u64, last_lba: u64) -> Self {
+        FreeSpaceMap {
+            first_lba,
+            last_lba,
+            used: Vec::new()
+        }
+    }
+
+    /// returns the whole usable area of the disk as an extent
+    pub fn bounds(&self) -> Extent {
+        Extent::new(self.first_lba, self.last_lba)
+    }
+
+    /// marks an extent as used
+    pub fn reserve(&mut self, extent: Extent) {
+        // keep the list sorted so the gap walk stays simple
+        let idx = self.used.iter()
+                           .position(|e| e.first_lba > extent.first_lba)
+                           .unwrap_or(self.used.len());
+        self.used.insert(idx, extent);
+    }
+
+    /// marks a previously reserved extent as free again
+    pub fn release(&mut self, extent: Extent) {
+        self.used.retain(|e| *e != extent);
+    }
+
+    /// returns the list of used extents, sorted by their first LBA
+    pub fn used_extents(&self) -> &Vec<Extent> {
+        &self.used
+    }
+
+    /// returns every free region between the first and last usable LBA
+    pub fn free_extents(&self) -> Vec<Extent> {
+        let mut free: Vec<Extent> = Vec::new();
+        let mut cursor = self.first_lba;
+
+        for used in self.used.iter() {
+            // anything before the used extent (and after the cursor) is free
+            if used.first_lba > cursor {
+                let end = core::cmp::min(used.first_lba - 1, self.last_lba);
+                if end >= cursor {
+                    free.push(Extent::new(cursor, end));
+                }
+            }
+
+            // note extents may overlap on corrupt tables, so never walk backwards
+            if used.last_lba >= cursor {
+                cursor = used.last_lba + 1;
+            }
+            if cursor > self.last_lba {
+                return free;
+            }
+        }
+
+        // finally add whatever is left at the end of the disk
+        free.push(Extent::new(cursor, self.last_lba));
+        free
+    }
+
+    /// returns the total number of free sectors
+    pub fn free_sectors(&self) -> u64 {
+        self.free_extents().iter().map(|e| e.len()).sum()
+    }
+
+    /// returns the largest free region on the disk, if there is one
+    pub fn largest_gap(&self) -> Option<Extent> {
+        let mut largest: Option<Extent> = None;
+        for gap in self.free_extents() {
+            match largest {
+                Some(l) if l.len() >= gap.len() => (),
+                _ => largest = Some(gap)
+            }
+        }
+        largest
+    }
+
+    /// finds the first free region that can hold `num_sectors` sectors
+    /// starting at a multiple of `alignment`
+    pub fn first_fit(&self, num_sectors: u64, alignment: u64) -> Option<Extent> {
+        if num_sectors == 0 {
+            return None;
+        }
+
+        for gap in self.free_extents() {
+            let start = align_up(gap.first_lba, alignment);
+            if start <= gap.last_lba && gap.last_lba - start + 1 >= num_sectors {
+                return Some(Extent::with_len(start, num_sectors));
+            }
+        }
+        None
+    }
+
+    /// checks to see if `extent` overlaps any used region
+    pub fn overlaps(&self, extent: &Extent) -> bool {
+        self.used.iter().any(|e| e.overlaps(extent))
+    }
+
+    /// checks to see if `extent` lies within the usable area and is entirely free
+    pub fn is_free(&self, extent: &Extent) -> bool {
+        self.bounds().contains(extent) && !self.overlaps(extent)
+    }
+}
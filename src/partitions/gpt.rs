@@ -2,11 +2,26 @@
 use uefi::prelude::*;
 use uefi::Guid;
 use uefi::proto::media::block::BlockIO;
-use uefi::table::boot::OpenProtocolParams;
+use uefi::table::boot::ScopedProtocol;
+use crate::alloc::string::String;
 use crate::alloc::vec::Vec;
+use crate::helpers::{
+    crc32,
+    find_block_io_handle,
+    open_handle_block_io
+};
 use core::convert::TryInto;
 
-const EFI_SIG: [u8; 8] = *b"EFI PART";
+use super::extents::{
+    Extent,
+    FreeSpaceMap
+};
+
+pub const EFI_SIG: [u8; 8] = *b"EFI PART";
+
+/// the largest partition entry array we will read, far past anything real
+/// (the usual one is 128 entries of 128 bytes)
+const MAX_ENTRY_ARRAY_BYTES: u64 = 1024 * 1024;
 
 /// define our GPT Partition Table header
 #[derive(Copy,Clone,PartialEq)]
@@ -42,9 +57,12 @@ pub struct GPTPartition {
 }
 
 /// define out GPT struct, which will take an EFI disk to parse
+#[derive(Clone)]
 pub struct GPTDisk {
+    handle:     Handle, // the BlockIO handle the table was read from
     media_id:   u32,
     blocksize:  u32,
+    last_block: u64, // last LBA of the media, which is where the backup header belongs
     header:     GPTHeader,
     partitions: Vec<GPTPartition>
 }
@@ -55,11 +73,14 @@ pub struct GPTDisk {
 /// helper function to parse GUIDs from raw bytes
 pub fn bytes_to_guid(bytes: [u8; 16]) -> Guid {
     // convert the bytes to usable values
-    let time_low = u32::from_ne_bytes(bytes[0..4].try_into().unwrap());
-    let time_mid = u16::from_ne_bytes(bytes[4..6].try_into().unwrap());
-    let time_high = u16::from_ne_bytes(bytes[6..8].try_into().unwrap());
-    let clock_seq = u16::from_ne_bytes(bytes[8..10].try_into().unwrap());
-    let node: u64 = u64::from_ne_bytes(bytes[10..16].try_into().unwrap());
+    // note the first three fields are little endian, the rest are big endian
+    let time_low = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let time_mid = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+    let time_high = u16::from_le_bytes(bytes[6..8].try_into().unwrap());
+    let clock_seq = u16::from_be_bytes(bytes[8..10].try_into().unwrap());
+    let mut node_buf = [0u8; 8];
+    node_buf[2..].copy_from_slice(&bytes[10..16]);
+    let node: u64 = u64::from_be_bytes(node_buf);
 
     // generate the GUID structure
     Guid::from_values(
@@ -71,6 +92,13 @@ pub fn bytes_to_guid(bytes: [u8; 16]) -> Guid {
     )
 }
 
+/// helper function to turn a GUID back into its on-disk representation
+pub fn guid_to_bytes(guid: Guid) -> [u8; 16] {
+    // `Guid` is `repr(C)` and stores its fields in the same mixed-endian
+    // layout GPT uses on disk, so its raw bytes are exactly what we want
+    unsafe { core::mem::transmute::<Guid, [u8; 16]>(guid) }
+}
+
 
 ///////////////////////// GPTHEADER IMPL /////////////////////////////////
 impl GPTHeader{
@@ -107,10 +135,93 @@ impl GPTHeader{
         }
     } 
 
+    /// serialises the header back into a sector, filling in the header CRC32
+    pub fn to_bytes(&self) -> [u8; 512] {
+        let mut sector = [0u8; 512];
+        sector[0..8].copy_from_slice(&EFI_SIG);
+        sector[8..12].copy_from_slice(&self.revision.to_ne_bytes());
+        sector[12..16].copy_from_slice(&self.header_sz.to_ne_bytes());
+        sector[24..32].copy_from_slice(&self.curr_lba.to_ne_bytes());
+        sector[32..40].copy_from_slice(&self.backup_lba.to_ne_bytes());
+        sector[40..48].copy_from_slice(&self.first_lba.to_ne_bytes());
+        sector[48..56].copy_from_slice(&self.last_lba.to_ne_bytes());
+        sector[56..72].copy_from_slice(&guid_to_bytes(self.guid));
+        sector[72..80].copy_from_slice(&self.lba_part_entries.to_ne_bytes());
+        sector[80..84].copy_from_slice(&self.num_partitions.to_ne_bytes());
+        sector[84..88].copy_from_slice(&self.part_size.to_ne_bytes());
+        sector[88..92].copy_from_slice(&self.part_crc32.to_ne_bytes());
+
+        // the CRC is calculated over the header with the CRC field zeroed
+        let header_sz = core::cmp::min(self.header_sz as usize, 512);
+        let crc = crc32(&sector[..header_sz]);
+        sector[16..20].copy_from_slice(&crc.to_ne_bytes());
+        sector
+    }
+
+    /// creates the matching backup header for this (primary) header
+    pub fn to_backup(&self, lba_part_entries: u64) -> Self {
+        let mut backup = *self;
+        backup.curr_lba = self.backup_lba;
+        backup.backup_lba = self.curr_lba;
+        backup.lba_part_entries = lba_part_entries;
+        backup
+    }
+
     /// returns the number of partitions available 
     pub fn num_partitions(&self) -> u32 {
         self.num_partitions
     }
+
+    /// returns the first LBA usable by partitions
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    /// returns the last LBA usable by partitions
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
+    }
+
+    /// returns the LBA of the alternate (backup) header
+    pub fn backup_lba(&self) -> u64 {
+        self.backup_lba
+    }
+
+    /// returns the LBA of the partition entry array
+    pub fn lba_part_entries(&self) -> u64 {
+        self.lba_part_entries
+    }
+
+    /// returns the size of a single partition entry
+    pub fn part_size(&self) -> u32 {
+        self.part_size
+    }
+
+    /// returns the checksum of the partition entry array
+    pub fn part_crc32(&self) -> u32 {
+        self.part_crc32
+    }
+
+    /// checks to see if the header's own checksum matches its contents
+    pub fn checksum_ok(&self) -> bool {
+        // note an all zero sector would otherwise pass with an empty header
+        if self.header_sz < 92 {
+            return false;
+        }
+        let sector = self.to_bytes();
+        u32::from_ne_bytes(sector[16..20].try_into().unwrap()) == self.crc32
+    }
+
+    /// returns the GUID of the disk
+    pub fn guid(&self) -> Guid {
+        self.guid
+    }
+
+    /// returns the number of sectors taken up by the partition entry array
+    pub fn entry_array_sectors(&self, blocksize: u32) -> u64 {
+        let bytes = self.num_partitions as u64 * self.part_size as u64;
+        (bytes + blocksize as u64 - 1) / blocksize as u64
+    }
 }
 
 ////////////////////////// GPTPARTITION IMPL //////////////////////////////
@@ -139,6 +250,96 @@ impl GPTPartition {
             part_name
         }
     }
+
+    /// creates a new partition entry from its values
+    /// note names longer than 36 UTF-16 characters are cut short
+    pub fn from_values(
+        part_type_guid: Guid,
+        part_guid: Guid,
+        extent: Extent,
+        attr_flags: u64,
+        name: &str
+    ) -> Self {
+        // encode the name as UTF-16 LE
+        let mut part_name = [0u8; 72];
+        for (i, c) in name.encode_utf16().take(36).enumerate() {
+            part_name[i*2..i*2 + 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        GPTPartition {
+            part_type_guid,
+            part_guid,
+            first_lba: extent.first_lba(),
+            last_lba: extent.last_lba(),
+            attr_flags,
+            part_name
+        }
+    }
+
+    /// serialises the partition entry back into its on-disk form
+    pub fn to_bytes(&self) -> [u8; 128] {
+        let mut chunk = [0u8; 128];
+        chunk[0..16].copy_from_slice(&guid_to_bytes(self.part_type_guid));
+        chunk[16..32].copy_from_slice(&guid_to_bytes(self.part_guid));
+        chunk[32..40].copy_from_slice(&self.first_lba.to_ne_bytes());
+        chunk[40..48].copy_from_slice(&self.last_lba.to_ne_bytes());
+        chunk[48..56].copy_from_slice(&self.attr_flags.to_ne_bytes());
+        chunk[56..].copy_from_slice(&self.part_name);
+        chunk
+    }
+
+    /// moves the partition entry to cover a different range of LBAs
+    /// note this only changes the entry, not the data on disk
+    pub fn set_extent(&mut self, extent: Extent) {
+        self.first_lba = extent.first_lba();
+        self.last_lba = extent.last_lba();
+    }
+
+    /// returns the partition type GUID
+    pub fn part_type_guid(&self) -> Guid {
+        self.part_type_guid
+    }
+
+    /// returns the unique GUID of the partition
+    pub fn part_guid(&self) -> Guid {
+        self.part_guid
+    }
+
+    /// returns the first LBA of the partition
+    pub fn first_lba(&self) -> u64 {
+        self.first_lba
+    }
+
+    /// returns the last LBA of the partition (inclusive)
+    pub fn last_lba(&self) -> u64 {
+        self.last_lba
+    }
+
+    /// returns the name of the partition
+    pub fn name(&self) -> String {
+        let units = self.part_name
+                        .chunks(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .take_while(|c| *c != 0);
+        core::char::decode_utf16(units)
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    /// returns the attribute flags of the partition
+    pub fn attr_flags(&self) -> u64 {
+        self.attr_flags
+    }
+
+    /// checks to see if the entry is in use (unused entries have a zeroed type GUID)
+    pub fn is_used(&self) -> bool {
+        self.part_type_guid != Guid::default()
+    }
+
+    /// returns the LBAs covered by the partition
+    pub fn extent(&self) -> Extent {
+        Extent::new(self.first_lba, self.last_lba)
+    }
 }
 
 
@@ -152,81 +353,163 @@ impl GPTDisk {
             
         // alias the boot services for ease of access
         let bs = st.boot_services();
-                
-        // get all handles available for BlockIO operations
-        let handles2 = bs.find_handles::<BlockIO>()
-                         .expect("Failed to find handles for `BlockIO`");
-
-        // loop over all handles and see if they are for the media we want
-        for handle in handles2 {
-            let params = OpenProtocolParams{handle,agent: img_handle, controller: None};
-            let bi = bs.open_protocol::<BlockIO>(params, uefi::table::boot::OpenProtocolAttributes::Exclusive)
-                       .expect("Failed to get `BlockIO` protocol");
-
-            let bi = unsafe{&*bi.interface.get()};
-
-            // get the variables of the media we need
-            let test_media_id = bi.media().media_id();
-            let blocksize = bi.media().block_size();
-
-            // check the device's media id against the target one
-            if test_media_id == media_id {
-                // read the first lba
-                let mut first_lba = vec![0u8; blocksize as usize];
-                bi.read_blocks(media_id, 1, &mut first_lba)
-                    .expect("Failed to read bytes");
-
-                
-                // parse the GPT header
-                let header = GPTHeader::new(first_lba.try_into().unwrap());
-                let mut partitions: Vec<GPTPartition> = Vec::new();
-
-                // find the number of partitions and where they are located
-                let num_part    = header.num_partitions;
-                let array_lba   = header.lba_part_entries;
-                let read_total  = header.part_size * num_part;
-                let read_total  = read_total + read_total % blocksize;
-                
-
-                // find the device we are operating on, and get the UEFI BlockIO protocol
-                let mut buf: Vec<u8> = vec![0u8; read_total as usize];
-
-
-                // attempt to read from the buffer
-                loop{
-                    match bi.read_blocks(media_id, array_lba, &mut buf) {
-                        Ok(_) => {
-                            break;                            
-                        },
-                        Err(e) => match e {
-                            _ => panic!("Found unexpected error: {:?}", e) 
-                        }
-                    }
-                }
 
-                // now parse the data and add it to our partitions vector
-                for i in 0..num_part as usize {
-                    partitions.push(
-                        GPTPartition::new(
-                            buf[i*128 as usize..(i+1)*128 as usize]
-                            .try_into().unwrap()
-                        )
-                    );
-                }
+        // find the device we are operating on, and get the UEFI BlockIO protocol
+        let handle = match find_block_io_handle(bs, img_handle, media_id) {
+            Some(handle) => handle,
+            // if we get here, we coulnd't find the drive again so we die :)
+            None => panic!("Failed to find drive with media id: {}", media_id)
+        };
+        let bi = open_handle_block_io(bs, img_handle, handle)
+                     .expect("Failed to get `BlockIO` protocol");
+        let bi = unsafe{&*bi.interface.get()};
+
+        GPTDisk::from_block_io(handle, bi)
+            .expect("Failed to parse GPT")
+    }
 
-                // return the structure
-                return GPTDisk {
-                    blocksize,
-                    media_id,
-                    header,
-                    partitions
-                };
-                
-            }
+    /// reads the GPT of the disk behind an already opened BlockIO protocol
+    pub fn from_block_io(handle: Handle, bi: &BlockIO) -> Result<Self, ()> {
+        let media_id = bi.media().media_id();
+        let blocksize = bi.media().block_size();
+        let last_block = bi.media().last_block();
+
+        // read the first lba
+        let mut first_lba = vec![0u8; blocksize as usize];
+        if let Err(e) = bi.read_blocks(media_id, 1, &mut first_lba) {
+            info!("Failed to read the GPT header: {:?}", e.status());
+            return Err(());
+        }
+
+        // make sure there actually is a GPT here
+        if first_lba[0..8] != EFI_SIG {
+            info!("LBA 1 doesn't hold a GPT header");
+            return Err(());
+        }
+
+        // parse the GPT header
+        let header = GPTHeader::new(first_lba[..512].try_into().unwrap());
+        let mut partitions: Vec<GPTPartition> = Vec::new();
+
+        // don't trust the sizes in the header until we know it is intact
+        if !header.checksum_ok() {
+            info!("GPT header checksum doesn't match");
+            return Err(());
+        }
+        let array_bytes = header.num_partitions as u64 * header.part_size as u64;
+        if header.part_size < 128 || header.part_size % 8 != 0 || array_bytes > MAX_ENTRY_ARRAY_BYTES {
+            info!(
+                "GPT header describes a bogus entry array ({} entries of {} bytes)",
+                header.num_partitions,
+                header.part_size
+            );
+            return Err(());
+        }
+
+        // find the number of partitions and where they are located
+        let num_part    = header.num_partitions;
+        let part_size   = header.part_size as usize;
+        let array_lba   = header.lba_part_entries;
+        let read_total  = header.entry_array_sectors(blocksize) * blocksize as u64;
+
+        let mut buf: Vec<u8> = vec![0u8; read_total as usize];
+
+        // attempt to read from the buffer
+        if let Err(e) = bi.read_blocks(media_id, array_lba, &mut buf) {
+            info!("Failed to read the partition entry array: {:?}", e.status());
+            return Err(());
+        }
+
+        // now parse the data and add it to our partitions vector
+        // note entries may be larger than 128 bytes, the rest is reserved
+        for i in 0..num_part as usize {
+            partitions.push(
+                GPTPartition::new(
+                    buf[i*part_size..i*part_size + 128]
+                    .try_into().unwrap()
+                )
+            );
+        }
+
+        // return the structure
+        Ok(GPTDisk {
+            handle,
+            blocksize,
+            media_id,
+            last_block,
+            header,
+            partitions
+        })
+    }
+
+    /// builds the table for the disk behind `bi` from a saved header and entry
+    /// array, e.g. when restoring a backup
+    /// note the backup header is moved to the end of the disk if it has changed size
+    pub fn from_backup(handle: Handle, bi: &BlockIO, header: GPTHeader, entries: &[u8]) -> Result<Self, ()> {
+        let part_size = header.part_size as usize;
+        if part_size < 128 || entries.len() < header.num_partitions as usize * part_size {
+            info!("Saved partition entry array is truncated");
+            return Err(());
+        }
+
+        let partitions = (0..header.num_partitions as usize)
+            .map(|i| GPTPartition::new(entries[i*part_size..i*part_size + 128].try_into().unwrap()))
+            .collect();
+        let mut disk = GPTDisk {
+            handle,
+            blocksize:  bi.media().block_size(),
+            media_id:   bi.media().media_id(),
+            last_block: bi.media().last_block(),
+            header,
+            partitions
+        };
+        if disk.backup_misplaced() {
+            disk.relocate_backup()?;
+        }
+        Ok(disk)
+    }
+
+    /// opens the BlockIO protocol of the disk this table lives on
+    pub fn open<'a>(
+        &self,
+        bs: &'a BootServices,
+        img_handle: Handle
+    ) -> Option<ScopedProtocol<'a, BlockIO>> {
+        open_handle_block_io(bs, img_handle, self.handle)
+    }
+
+    /// points a copy of this table at another disk, e.g. to write it to a clone
+    /// note the backup header is moved to the end of the new disk
+    pub fn retarget(&mut self, handle: Handle, bi: &BlockIO) -> Result<(), ()> {
+        if bi.media().block_size() != self.blocksize {
+            info!("Disks have different block sizes");
+            return Err(());
         }
+        self.handle = handle;
+        self.media_id = bi.media().media_id();
+        self.last_block = bi.media().last_block();
+        self.relocate_backup()
+    }
+
+    /// gives the disk and every partition a new unique GUID
+    /// note nothing is changed if `new_guid` fails
+    pub fn regenerate_guids<F: FnMut() -> uefi::Result<Guid>>(&mut self, mut new_guid: F) -> uefi::Result {
+        let disk_guid = new_guid()?;
+        let mut part_guids: Vec<Guid> = Vec::new();
+        for _ in self.partitions.iter().filter(|p| p.is_used()) {
+            part_guids.push(new_guid()?);
+        }
+
+        self.header.guid = disk_guid;
+        for (part, guid) in self.partitions.iter_mut().filter(|p| p.is_used()).zip(part_guids) {
+            part.part_guid = guid;
+        }
+        Ok(())
+    }
 
-        // if we get here, we coulnd't find the drive again so we die :)
-        panic!("Failed to find drive with media id: {}", media_id);
+    /// returns the BlockIO handle of the disk this table lives on
+    pub fn handle(&self) -> Handle {
+        self.handle
     }
 
     /// returns the number of partitions found in the GPT Table
@@ -238,4 +521,323 @@ impl GPTDisk {
     pub fn partitions(&self) -> &Vec<GPTPartition> {
         &self.partitions
     }
+
+    /// returns the associated media id
+    pub fn media_id(&self) -> u32 {
+        self.media_id
+    }
+
+    /// returns the (primary) GPT header
+    pub fn header(&self) -> &GPTHeader {
+        &self.header
+    }
+
+    /// returns the block size of the disk
+    pub fn blocksize(&self) -> u32 {
+        self.blocksize
+    }
+
+    /// returns the partition with the given unique GUID
+    pub fn partition(&self, part_guid: Guid) -> Option<&GPTPartition> {
+        self.partitions.iter()
+                       .find(|p| p.is_used() && p.part_guid() == part_guid)
+    }
+
+    /// returns the partition with the given unique GUID for modification
+    /// note changes only reach the disk once `write` is called
+    pub fn partition_mut(&mut self, part_guid: Guid) -> Option<&mut GPTPartition> {
+        self.partitions.iter_mut()
+                       .find(|p| p.is_used() && p.part_guid() == part_guid)
+    }
+
+    /// changes the size of a partition, growing into the free space after it
+    /// note this only changes the entry, the filesystem has to be resized separately
+    pub fn resize_partition(&mut self, part_guid: Guid, num_sectors: u64) -> Result<(), ()> {
+        if num_sectors == 0 {
+            return Err(());
+        }
+        let part = match self.partition(part_guid) {
+            Some(part) => *part,
+            None => return Err(())
+        };
+
+        // make sure the new size only covers free space
+        let new_extent = Extent::with_len(part.first_lba(), num_sectors);
+        if !self.free_space_excluding(part_guid).is_free(&new_extent) {
+            info!("Not enough free space after the partition to grow it");
+            return Err(());
+        }
+
+        self.partition_mut(part_guid).unwrap().set_extent(new_extent);
+        Ok(())
+    }
+
+    /// adds a new partition in the first unused slot of the entry array
+    /// 
+    /// Returns the index of the slot it was put in
+    pub fn create_partition(&mut self, part: GPTPartition) -> Result<usize, ()> {
+        if !part.is_used() || part.first_lba() > part.last_lba() {
+            return Err(());
+        }
+        if !self.free_space().is_free(&part.extent()) {
+            info!("New partition overlaps an existing one");
+            return Err(());
+        }
+
+        match self.partitions.iter().position(|p| !p.is_used()) {
+            Some(idx) => {
+                self.partitions[idx] = part;
+                Ok(idx)
+            },
+            None => {
+                info!("No free slots left in the partition entry array");
+                Err(())
+            }
+        }
+    }
+
+    /// works out the first and last usable LBAs for an entry array of
+    /// `num_partitions` entries with the primary copy at `lba_part_entries`
+    pub fn usable_range_for(&self, lba_part_entries: u64, num_partitions: u32) -> Option<Extent> {
+        let mut header = self.header;
+        header.num_partitions = num_partitions;
+        let sectors = header.entry_array_sectors(self.blocksize);
+
+        // the backup array sits right before the backup header
+        let first_lba = lba_part_entries + sectors;
+        let last_lba = self.header.backup_lba.checked_sub(sectors + 1)?;
+        if lba_part_entries < 2 || first_lba > last_lba {
+            return None;
+        }
+        Some(Extent::new(first_lba, last_lba))
+    }
+
+    /// moves the primary partition entry array and/or changes how many entries
+    /// it holds (like `sgdisk --resize-table`)
+    /// 
+    /// note every used partition must already fit in the new usable range
+    pub fn resize_entry_array(&mut self, lba_part_entries: u64, num_partitions: u32) -> Result<(), ()> {
+        // make sure we don't drop any used entries off the end
+        if self.partitions.iter().skip(num_partitions as usize).any(|p| p.is_used()) {
+            info!("Used partition entries would be cut off, try compacting the entries first");
+            return Err(());
+        }
+        if num_partitions < 128 {
+            warn!("Fewer than 128 partition entries is against the spec, some tools may complain");
+        }
+
+        let usable = match self.usable_range_for(lba_part_entries, num_partitions) {
+            Some(usable) => usable,
+            None => {
+                info!("Partition entry array doesn't fit on the disk");
+                return Err(());
+            }
+        };
+        if self.partitions.iter().any(|p| p.is_used() && !usable.contains(&p.extent())) {
+            info!("Partitions are in the way of the new partition entry array");
+            return Err(());
+        }
+
+        // finally update the header and pad out (or trim) the entries
+        self.header.lba_part_entries = lba_part_entries;
+        self.header.num_partitions = num_partitions;
+        self.header.first_lba = usable.first_lba();
+        self.header.last_lba = usable.last_lba();
+        self.partitions.resize(num_partitions as usize, GPTPartition::new([0u8; 128]));
+        Ok(())
+    }
+
+    /// checks to see if the backup header isn't on the last LBA of the disk,
+    /// which happens after cloning to a bigger disk or growing a virtual one
+    pub fn backup_misplaced(&self) -> bool {
+        self.header.backup_lba != self.last_block
+    }
+
+    /// returns the last LBA of the disk
+    pub fn last_block(&self) -> u64 {
+        self.last_block
+    }
+
+    /// moves the backup header (and its entry array) to the end of the disk and
+    /// updates the last usable LBA to match
+    /// note the table has to be written for this to take effect
+    pub fn relocate_backup(&mut self) -> Result<(), ()> {
+        let sectors = self.header.entry_array_sectors(self.blocksize);
+        let last_lba = match self.last_block.checked_sub(sectors + 1) {
+            Some(lba) if lba >= self.header.first_lba => lba,
+            _ => return Err(())
+        };
+
+        // make sure a shrunken disk doesn't cut off any partitions
+        if self.partitions.iter().any(|p| p.is_used() && p.last_lba() > last_lba) {
+            info!("Partitions extend past the end of the disk");
+            return Err(());
+        }
+
+        self.header.backup_lba = self.last_block;
+        self.header.last_lba = last_lba;
+        Ok(())
+    }
+
+    /// checks to see if two partitions can be merged, meaning `second` comes
+    /// right after `first` (free space between them is fine) and they have the
+    /// same type
+    pub fn can_merge(&self, first: Guid, second: Guid) -> bool {
+        let (p1, p2) = match (self.partition(first), self.partition(second)) {
+            (Some(p1), Some(p2)) if first != second => (*p1, *p2),
+            _ => return false
+        };
+        if p1.part_type_guid() != p2.part_type_guid() || p1.last_lba() >= p2.first_lba() {
+            return false;
+        }
+
+        // make sure nothing else lives between them
+        let mut others = self.free_space_excluding(first);
+        others.release(p2.extent());
+        !others.overlaps(&Extent::new(p1.first_lba(), p2.last_lba()))
+    }
+
+    /// merges `second` into `first`, so `first` ends where `second` ended
+    /// note this only changes the entries, `second`'s entry is cleared
+    pub fn merge_partitions(&mut self, first: Guid, second: Guid) -> Result<(), ()> {
+        if !self.can_merge(first, second) {
+            info!("Partitions must be next to eachother and of the same type to merge");
+            return Err(());
+        }
+
+        let merged = Extent::new(
+            self.partition(first).unwrap().first_lba(),
+            self.partition(second).unwrap().last_lba()
+        );
+        *self.partition_mut(second).unwrap() = GPTPartition::new([0u8; 128]);
+        self.partition_mut(first).unwrap().set_extent(merged);
+        Ok(())
+    }
+
+    /// swaps two entries in the partition array
+    /// note this renumbers the partitions but leaves their data where it is
+    pub fn swap_entries(&mut self, a: usize, b: usize) -> Result<(), ()> {
+        if a >= self.partitions.len() || b >= self.partitions.len() {
+            return Err(());
+        }
+
+        let before = self.partitions.clone();
+        self.partitions.swap(a, b);
+        self.report_renumbering(&before);
+        Ok(())
+    }
+
+    /// moves all used entries to the front of the array, keeping their order
+    /// 
+    /// Returns true if any partition was renumbered
+    pub fn compact_entries(&mut self) -> bool {
+        let before = self.partitions.clone();
+        let (mut used, unused): (Vec<GPTPartition>, Vec<GPTPartition>) = 
+            self.partitions.iter().copied().partition(|p| p.is_used());
+        used.extend(unused);
+        self.partitions = used;
+        self.report_renumbering(&before)
+    }
+
+    /// sorts the used entries by their first LBA and packs them at the front
+    /// of the array, much like `sgdisk --sort`
+    /// 
+    /// Returns true if any partition was renumbered
+    pub fn sort_entries(&mut self) -> bool {
+        let before = self.partitions.clone();
+        // note the sort is stable and unused entries all sort last
+        self.partitions.sort_by_key(|p| match p.is_used() {
+            true => (0, p.first_lba()),
+            false => (1, 0)
+        });
+        self.report_renumbering(&before)
+    }
+
+    /// warns about every partition whose number changed since `before`
+    fn report_renumbering(&self, before: &[GPTPartition]) -> bool {
+        let mut changed = false;
+        for (new_idx, part) in self.partitions.iter().enumerate() {
+            if !part.is_used() {
+                continue;
+            }
+            let old_idx = before.iter()
+                                .position(|p| p.is_used() && p.part_guid() == part.part_guid());
+            if let Some(old_idx) = old_idx {
+                if old_idx != new_idx {
+                    info!(
+                        "Partition {} renumbered from {} to {}",
+                        part.part_guid(),
+                        old_idx + 1,
+                        new_idx + 1
+                    );
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            warn!("Partition numbers changed! Anything referring to partitions by number");
+            warn!("(fstab, /dev/sdXN, boot entries...) needs to be updated before rebooting");
+        }
+        changed
+    }
+
+    /// serialises the partition entry array, padded out to whole sectors
+    fn entries_to_bytes(&self) -> Vec<u8> {
+        let part_size = self.header.part_size as usize;
+        let total = self.header.entry_array_sectors(self.blocksize) * self.blocksize as u64;
+        let mut buf = vec![0u8; total as usize];
+        for (i, part) in self.partitions.iter().enumerate() {
+            buf[i*part_size..i*part_size + 128].copy_from_slice(&part.to_bytes());
+        }
+        buf
+    }
+
+    /// writes the partition entries and both headers back to the disk
+    /// 
+    /// The backup copy is written first, so if we lose power halfway through
+    /// there is always one consistent copy of the table on the disk
+    pub fn write(&mut self, bi: &mut BlockIO) -> uefi::Result {
+        // update the checksum of the entry array
+        let entries = self.entries_to_bytes();
+        let entries_len = self.header.num_partitions as usize * self.header.part_size as usize;
+        self.header.part_crc32 = crc32(&entries[..entries_len]);
+
+        // the backup entry array sits right before the backup header
+        let array_sectors = self.header.entry_array_sectors(self.blocksize);
+        let backup = self.header.to_backup(self.header.backup_lba - array_sectors);
+
+        // write the backup table, then the primary one
+        for header in [backup, self.header].iter() {
+            let mut sector = vec![0u8; self.blocksize as usize];
+            sector[..512].copy_from_slice(&header.to_bytes());
+
+            bi.write_blocks(self.media_id, header.lba_part_entries, &entries)?;
+            bi.write_blocks(self.media_id, header.curr_lba, &sector)?;
+        }
+
+        // make sure everything actually hit the disk
+        bi.flush_blocks()
+    }
+
+    /// builds a map of the free space between the first and last usable LBA
+    pub fn free_space(&self) -> FreeSpaceMap {
+        let mut map = FreeSpaceMap::new(self.header.first_lba(), self.header.last_lba());
+        for part in self.partitions.iter().filter(|p| p.is_used()) {
+            map.reserve(part.extent());
+        }
+        map
+    }
+
+    /// builds a free space map that treats the partition `part_guid` as free,
+    /// which is what we want when checking where it could be moved or grown to
+    pub fn free_space_excluding(&self, part_guid: Guid) -> FreeSpaceMap {
+        let mut map = FreeSpaceMap::new(self.header.first_lba(), self.header.last_lba());
+        for part in self.partitions.iter().filter(|p| p.is_used()) {
+            if part.part_guid() != part_guid {
+                map.reserve(part.extent());
+            }
+        }
+        map
+    }
 }
@@ -1,14 +1,25 @@
 // Includes structs and APIs for parsing and writing MBR-based disks and partition tables
 
-//use uefi::prelude::*;
+use uefi::prelude::*;
+use uefi::proto::media::block::BlockIO;
+use uefi::table::boot::ScopedProtocol;
 use crate::alloc::vec::Vec;
+use crate::helpers::open_block_io;
 use core::convert::TryInto;
 
+use super::extents::{
+    Extent,
+    FreeSpaceMap
+};
+
 // Link about MBR: https://en.wikipedia.org/wiki/Master_boot_record
 
 /// the signature of the MBR to ensure we actually read stuff
 const MBR_SIG: [u8; 2] = [0x55, 0xaa];
 
+/// the type ids of extended partitions, which hold a chain of EBRs
+pub const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+
 /// defines the types of MBR partitions
 /// note: we only include partitions we support here
 #[derive(Copy,Clone,Debug,PartialEq)]
@@ -36,6 +47,7 @@ pub struct MbrPartition {
     chs_start:      [u8; 3],  
     chs_end:        [u8; 3],
     part_type:      MbrPartTypes,
+    type_id:        u8, // the raw type byte, so we can write it back untouched
     lba_start:      u32,
     num_sectors:    u32
 }
@@ -44,10 +56,34 @@ pub struct MbrPartition {
 /// defines our MBR structure
 pub struct MBR {
     media_id: u32,
+    last_lba: u64,
+    bootsector: [u8; 512],
     partitions: Vec<MbrPartition>,
 }
 
 
+/// converts an LBA into the legacy cylinder-head-sector format
+fn lba_to_chs(lba: u64) -> [u8; 3] {
+    // assume the usual translated geometry of 255 heads and 63 sectors
+    const HEADS: u64 = 255;
+    const SECTORS: u64 = 63;
+
+    // anything past cylinder 1023 just gets the "use the LBA" marker
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / SECTORS) % HEADS;
+    let sector = (lba % SECTORS) + 1;
+
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) & 0xc0) as u8,
+        (cylinder & 0xff) as u8
+    ]
+}
+
+
 ////////////////////// PARTITION FUNCTIONS /////////////////////////////
 impl MbrPartition {
     /// create a new MbrPartition
@@ -92,11 +128,43 @@ impl MbrPartition {
             chs_start,
             chs_end,
             part_type,
+            type_id: partition_buffer[4],
             lba_start,
             num_sectors
         }
     }
 
+    /// serialises the partition back into its 16 byte table entry
+    fn to_bytes(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        buf[0] = if self.active { 0x80 } else { 0x00 };
+        buf[1..4].copy_from_slice(&self.chs_start);
+        buf[4] = self.type_id;
+        buf[5..8].copy_from_slice(&self.chs_end);
+        buf[8..12].copy_from_slice(&self.lba_start.to_ne_bytes());
+        buf[12..16].copy_from_slice(&self.num_sectors.to_ne_bytes());
+        buf
+    }
+
+    /// moves the partition to cover a different range of LBAs
+    /// note this only changes the entry, not the data on disk
+    pub fn set_extent(&mut self, extent: Extent) -> Result<(), ()> {
+        // MBR entries can't describe anything past 2^32 sectors
+        if extent.last_lba() > u32::MAX as u64 {
+            return Err(());
+        }
+        self.lba_start = extent.first_lba() as u32;
+        self.num_sectors = extent.len() as u32;
+        self.chs_start = lba_to_chs(extent.first_lba());
+        self.chs_end = lba_to_chs(extent.last_lba());
+        Ok(())
+    }
+
+    /// returns the raw type byte of the partition
+    pub fn type_id(&self) -> u8 {
+        self.type_id
+    }
+
     /// returns the status of the partition
     pub fn active(&self) -> bool {
         self.active
@@ -123,13 +191,19 @@ impl MbrPartition {
         // LBA_START + (#_Sectors x SECTOR_SIZE)
         self.lba_start() as u64 + (self.num_sectors as u64 * block_size)
     }
+
+    /// returns the LBAs covered by the partition
+    pub fn extent(&self) -> Extent {
+        Extent::with_len(self.lba_start as u64, self.num_sectors as u64)
+    }
 }
 
 
 ////////////////////// MBR MAIN FUNCTIONS ////////////////////////
 impl MBR {
     /// creates a bew MBR structure 
-    pub fn new(bootsector: [u8; 512], media_id: u32) -> Result<Self, ()> {
+    /// note `last_lba` is the last block of the media the bootsector came from
+    pub fn new(bootsector: [u8; 512], media_id: u32, last_lba: u64) -> Result<Self, ()> {
         // make sure the partition actually has the MBR signature
         if bootsector[510..512] != MBR_SIG {
             info!("Boot sector is not an MBR. Skipping...");
@@ -154,6 +228,8 @@ impl MBR {
 
         Ok(MBR {
             media_id,
+            last_lba,
+            bootsector,
             partitions
         })
     }
@@ -183,4 +259,80 @@ impl MBR {
     pub fn media_id(&self) -> u32 {
         self.media_id
     }
+
+    /// returns the partitions
+    pub fn partitions(&self) -> &Vec<MbrPartition> {
+        &self.partitions
+    }
+
+    /// opens the BlockIO protocol of the disk this table lives on
+    pub fn open<'a>(
+        &self,
+        bs: &'a BootServices,
+        img_handle: Handle
+    ) -> Option<ScopedProtocol<'a, BlockIO>> {
+        open_block_io(bs, img_handle, self.media_id)
+    }
+
+    /// writes the partition table back to the disk, leaving the boot code alone
+    pub fn write(&mut self, bi: &mut BlockIO) -> uefi::Result {
+        for (i, part) in self.partitions.iter().enumerate() {
+            self.bootsector[446 + i*16..462 + i*16].copy_from_slice(&part.to_bytes());
+        }
+
+        let mut sector = vec![0u8; bi.media().block_size() as usize];
+        sector[..512].copy_from_slice(&self.bootsector);
+        bi.write_blocks(self.media_id, 0, &sector)?;
+        bi.flush_blocks()
+    }
+
+    /// returns the extended partition holding the EBR chain, if there is one
+    pub fn extended_partition(&self) -> Option<&MbrPartition> {
+        self.partitions.iter().find(|p| EXTENDED_TYPES.contains(&p.type_id()))
+    }
+
+    /// makes the protective MBR partition cover the whole disk again
+    pub fn fix_protective(&mut self) -> Result<(), ()> {
+        let last_lba = core::cmp::min(self.last_lba, u32::MAX as u64);
+        match self.partitions.iter_mut().find(|p| p.part_type() == MbrPartTypes::EFIProtectiveMBR) {
+            Some(part) => part.set_extent(Extent::new(1, last_lba)),
+            None => Err(())
+        }
+    }
+
+    /// changes the size of a partition, growing into the free space after it
+    /// note this only changes the entry, the filesystem has to be resized separately
+    pub fn resize_partition(&mut self, index: usize, num_sectors: u64) -> Result<(), ()> {
+        let part = match self.partitions.get(index) {
+            Some(part) if part.part_type() != MbrPartTypes::Empty => *part,
+            _ => return Err(())
+        };
+        if num_sectors == 0 {
+            return Err(());
+        }
+
+        // make sure the new size only covers free space
+        let mut free = self.free_space();
+        free.release(part.extent());
+        let new_extent = Extent::with_len(part.lba_start() as u64, num_sectors);
+        if !free.is_free(&new_extent) {
+            info!("Not enough free space after the partition to grow it");
+            return Err(());
+        }
+
+        self.partitions[index].set_extent(new_extent)
+    }
+
+    /// builds a map of the free space on the disk
+    /// note MBR can only address the first 2^32 sectors, so we stop there
+    pub fn free_space(&self) -> FreeSpaceMap {
+        let last_lba = core::cmp::min(self.last_lba, u32::MAX as u64);
+        let mut map = FreeSpaceMap::new(1, last_lba);
+        for part in self.partitions.iter() {
+            if part.part_type() != MbrPartTypes::Empty && part.num_sectors() != 0 {
+                map.reserve(part.extent());
+            }
+        }
+        map
+    }
 }
\ No newline at end of file
@@ -2,10 +2,15 @@
 // re-export our modules
 pub mod mbr;
 pub mod gpt;
+pub mod extents;
 
 // export our commonly used structures
 pub use mbr::MBR;
 pub use gpt::{
     GPTDisk,
     GPTPartition
+};
+pub use extents::{
+    Extent,
+    FreeSpaceMap
 };
\ No newline at end of file
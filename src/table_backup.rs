@@ -0,0 +1,377 @@
+//! Saves the partition tables of a disk to a file on the EFI System Partition
+//! we were booted from, and puts them back later.
+//!
+//! The file uses the same layout as `sgdisk --backup`, so either tool can
+//! restore the other's backups:
+//!  - [0..512]     the MBR
+//!  - [512..1024]  the primary GPT header
+//!  - [1024..1536] the backup GPT header
+//!  - [1536..]     the partition entry array (entries * entry size bytes)
+//!
+//! sgdisk stops reading after the entry array, so the EBR chain of an extended
+//! partition (if any) is tacked on to the end: an 8 byte signature, a 4 byte
+//! count and 4 bytes of padding, followed by the LBA (8 bytes) and contents
+//! (512 bytes) of every EBR. Disks without a GPT get zeroed headers.
+
+use uefi::prelude::*;
+use uefi::CStr16;
+use uefi::proto::media::block::BlockIO;
+use uefi::proto::media::file::{
+    File,
+    FileAttribute,
+    FileMode,
+    FileType,
+    RegularFile
+};
+use crate::alloc::vec::Vec;
+use core::convert::TryInto;
+
+use crate::helpers::{
+    crc32,
+    open_handle_block_io
+};
+use crate::partitions::{
+    GPTDisk,
+    MBR
+};
+use crate::partitions::gpt::{
+    EFI_SIG,
+    GPTHeader
+};
+use crate::partitions::mbr::EXTENDED_TYPES;
+
+/// the size of every record in the backup file
+const RECORD_SIZE: usize = 512;
+
+/// the signature in front of the saved EBR chain
+const EBR_SIG: [u8; 8] = *b"PTBLEBR1";
+
+/// the most EBRs we will follow, so a looping chain can't hang us
+const MAX_EBRS: usize = 128;
+
+/// defines the saved partition tables of a disk
+pub struct TableBackup {
+    mbr:        [u8; RECORD_SIZE],
+    primary:    [u8; RECORD_SIZE], // all zeroes if the disk has no GPT
+    backup:     [u8; RECORD_SIZE],
+    entries:    Vec<u8>,
+    ebrs:       Vec<(u64, [u8; RECORD_SIZE])>
+}
+
+
+/// reads the first 512 bytes of a sector
+fn read_record(bi: &BlockIO, lba: u64) -> uefi::Result<[u8; RECORD_SIZE]> {
+    let mut sector = vec![0u8; bi.media().block_size() as usize];
+    bi.read_blocks(bi.media().media_id(), lba, &mut sector)?;
+    Ok(sector[..RECORD_SIZE].try_into().unwrap())
+}
+
+/// writes a 512 byte record to the start of a sector, zeroing the rest
+fn write_record(bi: &mut BlockIO, lba: u64, record: &[u8; RECORD_SIZE]) -> uefi::Result {
+    let mut sector = vec![0u8; bi.media().block_size() as usize];
+    sector[..RECORD_SIZE].copy_from_slice(record);
+    bi.write_blocks(bi.media().media_id(), lba, &sector)
+}
+
+/// follows the EBR chain of the extended partition in `mbr`, if there is one
+fn read_ebr_chain(bi: &BlockIO, mbr: &MBR) -> uefi::Result<Vec<(u64, [u8; RECORD_SIZE])>> {
+    let mut ebrs = Vec::new();
+    let (start, sectors) = match mbr.extended_partition() {
+        Some(part) => (part.lba_start() as u64, part.num_sectors() as u64),
+        None => return Ok(ebrs)
+    };
+
+    // note every link in the chain is relative to the start of the extended partition
+    let mut lba = start;
+    loop {
+        if ebrs.len() == MAX_EBRS {
+            warn!("EBR chain is longer than {} links, stopping there", MAX_EBRS);
+            break;
+        }
+        let ebr = read_record(bi, lba)?;
+        if ebr[510..512] != [0x55, 0xaa] {
+            warn!("EBR at LBA {} has no signature, stopping there", lba);
+            break;
+        }
+        ebrs.push((lba, ebr));
+
+        // the second entry links to the next EBR, if it has an extended type
+        let next = u32::from_le_bytes(ebr[470..474].try_into().unwrap()) as u64;
+        if !EXTENDED_TYPES.contains(&ebr[466]) || next == 0 {
+            break;
+        }
+        if next >= sectors || ebrs.iter().any(|(seen, _)| *seen == start + next) {
+            warn!("EBR at LBA {} links outside the chain, stopping there", lba);
+            break;
+        }
+        lba = start + next;
+    }
+    Ok(ebrs)
+}
+
+
+////////////////////////// TABLEBACKUP IMPL //////////////////////////////
+impl TableBackup {
+    /// reads the partition tables of the disk behind `bi`
+    pub fn read(bi: &BlockIO) -> uefi::Result<Self> {
+        let mbr = read_record(bi, 0)?;
+        let ebrs = match MBR::new(mbr, bi.media().media_id(), bi.media().last_block()) {
+            Ok(parsed) => read_ebr_chain(bi, &parsed)?,
+            Err(_) => Vec::new()
+        };
+
+        let primary = read_record(bi, 1)?;
+        if primary[0..8] != EFI_SIG {
+            info!("Disk has no GPT, only saving the MBR");
+            return Ok(TableBackup {
+                mbr,
+                primary: [0u8; RECORD_SIZE],
+                backup: [0u8; RECORD_SIZE],
+                entries: Vec::new(),
+                ebrs
+            });
+        }
+
+        // read the entry array, note only whole entries are saved
+        let header = GPTHeader::new(primary);
+        let blocksize = bi.media().block_size();
+        let mut entries = vec![0u8; (header.entry_array_sectors(blocksize) * blocksize as u64) as usize];
+        bi.read_blocks(bi.media().media_id(), header.lba_part_entries(), &mut entries)?;
+        entries.truncate(header.num_partitions() as usize * header.part_size() as usize);
+
+        // like sgdisk, fall back to rebuilding the backup header if it is unreadable
+        let backup = match read_record(bi, header.backup_lba()) {
+            Ok(backup) if backup[0..8] == primary[0..8] && GPTHeader::new(backup).checksum_ok() => backup,
+            _ => {
+                warn!("Backup GPT header is damaged, saving one rebuilt from the primary");
+                let array_sectors = header.entry_array_sectors(blocksize);
+                header.to_backup(header.backup_lba() - array_sectors).to_bytes()
+            }
+        };
+        if !header.checksum_ok() {
+            warn!("Primary GPT header has a bad checksum, saving it anyway");
+        }
+
+        Ok(TableBackup {
+            mbr,
+            primary,
+            backup,
+            entries,
+            ebrs
+        })
+    }
+
+    /// serialises the backup into the sgdisk compatible file format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.mbr);
+        buf.extend_from_slice(&self.primary);
+        buf.extend_from_slice(&self.backup);
+        buf.extend_from_slice(&self.entries);
+
+        if !self.ebrs.is_empty() {
+            buf.extend_from_slice(&EBR_SIG);
+            buf.extend_from_slice(&(self.ebrs.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&[0u8; 4]);
+            for (lba, ebr) in self.ebrs.iter() {
+                buf.extend_from_slice(&lba.to_le_bytes());
+                buf.extend_from_slice(ebr);
+            }
+        }
+        buf
+    }
+
+    /// parses a backup file, checking that everything in it is intact
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, ()> {
+        if buf.len() < RECORD_SIZE * 3 {
+            info!("Backup file is too short");
+            return Err(());
+        }
+        let mbr: [u8; RECORD_SIZE] = buf[0..512].try_into().unwrap();
+        let primary: [u8; RECORD_SIZE] = buf[512..1024].try_into().unwrap();
+        let backup: [u8; RECORD_SIZE] = buf[1024..1536].try_into().unwrap();
+
+        // find the entry array, trusting whichever header is intact
+        let mut offset = RECORD_SIZE * 3;
+        let mut entries = Vec::new();
+        if primary[0..8] == EFI_SIG || backup[0..8] == EFI_SIG {
+            let header = match GPTHeader::new(primary) {
+                h if h.checksum_ok() => h,
+                _ => GPTHeader::new(backup)
+            };
+            if !header.checksum_ok() {
+                info!("Both saved GPT headers are damaged");
+                return Err(());
+            }
+
+            let len = header.num_partitions() as usize * header.part_size() as usize;
+            if buf.len() < offset + len {
+                info!("Saved partition entry array is truncated");
+                return Err(());
+            }
+            entries.extend_from_slice(&buf[offset..offset + len]);
+            if crc32(&entries) != header.part_crc32() {
+                info!("Saved partition entry array has a bad checksum");
+                return Err(());
+            }
+            offset += len;
+        }
+
+        // finally pick up the EBR chain, sgdisk backups won't have one
+        let mut ebrs = Vec::new();
+        if buf.len() >= offset + 16 && buf[offset..offset + 8] == EBR_SIG {
+            let count = u32::from_le_bytes(buf[offset + 8..offset + 12].try_into().unwrap()) as usize;
+            offset += 16;
+            if count > MAX_EBRS || buf.len() < offset + count * (8 + RECORD_SIZE) {
+                info!("Saved EBR chain is truncated");
+                return Err(());
+            }
+            for _ in 0..count {
+                let lba = u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap());
+                let ebr: [u8; RECORD_SIZE] = buf[offset + 8..offset + 8 + RECORD_SIZE].try_into().unwrap();
+                ebrs.push((lba, ebr));
+                offset += 8 + RECORD_SIZE;
+            }
+        }
+
+        Ok(TableBackup {
+            mbr,
+            primary,
+            backup,
+            entries,
+            ebrs
+        })
+    }
+
+    /// checks to see if the backup holds a GPT
+    pub fn has_gpt(&self) -> bool {
+        !self.entries.is_empty()
+    }
+
+    /// returns the saved GPT header to restore from, preferring the primary
+    fn gpt_header(&self) -> GPTHeader {
+        match GPTHeader::new(self.primary) {
+            h if h.checksum_ok() => h,
+            // note the backup doesn't record where the primary array goes,
+            // so assume the usual LBA 2 like sgdisk does
+            _ => GPTHeader::new(self.backup).to_backup(2)
+        }
+    }
+
+    /// writes the saved tables to the disk behind `bi`
+    pub fn write(&self, handle: Handle, bi: &mut BlockIO) -> uefi::Result {
+        if bi.media().is_read_only() {
+            return Err(Status::WRITE_PROTECTED.into());
+        }
+
+        // the GPT goes first, so the protective MBR can be fitted to it
+        if self.has_gpt() {
+            let mut disk = GPTDisk::from_backup(handle, bi, self.gpt_header(), &self.entries)
+                .map_err(|_| uefi::Error::from(Status::VOLUME_FULL))?;
+            disk.write(bi)?;
+        }
+
+        for (lba, ebr) in self.ebrs.iter() {
+            write_record(bi, *lba, ebr)?;
+        }
+
+        match MBR::new(self.mbr, bi.media().media_id(), bi.media().last_block()) {
+            Ok(mut mbr) => {
+                if self.has_gpt() && mbr.fix_protective().is_err() {
+                    warn!("Saved MBR has no protective partition");
+                }
+                mbr.write(bi)
+            },
+            Err(_) => {
+                warn!("Saved MBR has no signature, leaving the MBR alone");
+                bi.flush_blocks()
+            }
+        }
+    }
+}
+
+
+/// opens a file on the ESP we were booted from
+fn open_esp_file(
+    bs: &BootServices,
+    img_handle: Handle,
+    path: &str,
+    mode: FileMode
+) -> uefi::Result<RegularFile> {
+    let sfs = bs.get_image_file_system(img_handle)?;
+    let sfs = unsafe{&mut *sfs.interface.get()};
+    let mut root = sfs.open_volume()?;
+
+    let mut name_buf = vec![0u16; path.len() + 1];
+    let name = CStr16::from_str_with_buf(path, &mut name_buf)
+        .map_err(|_| uefi::Error::from(Status::INVALID_PARAMETER))?;
+
+    // note creating a file that already exists just opens it, so start afresh
+    if mode == FileMode::CreateReadWrite {
+        if let Ok(old) = root.open(name, FileMode::ReadWrite, FileAttribute::empty()) {
+            old.delete()?;
+        }
+    }
+
+    match root.open(name, mode, FileAttribute::empty())?.into_type()? {
+        FileType::Regular(file) => Ok(file),
+        FileType::Dir(_) => Err(Status::INVALID_PARAMETER.into())
+    }
+}
+
+/// saves the partition tables of the disk behind `disk_handle` to `path` on the ESP
+pub fn backup_tables(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk_handle: Handle,
+    path: &str
+) -> uefi::Result {
+    let bs = st.boot_services();
+    let data = {
+        let bi = match open_handle_block_io(bs, img_handle, disk_handle) {
+            Some(bi) => bi,
+            None => return Err(Status::NOT_FOUND.into())
+        };
+        TableBackup::read(unsafe{&*bi.interface.get()})?.to_bytes()
+    };
+
+    let mut file = open_esp_file(bs, img_handle, path, FileMode::CreateReadWrite)?;
+    file.write(&data).map_err(|e| uefi::Error::from(e.status()))?;
+    file.flush()?;
+    info!("Saved {} bytes of partition tables to {}", data.len(), path);
+    Ok(())
+}
+
+/// restores the partition tables saved in `path` on the ESP to the disk behind
+/// `disk_handle`, note this overwrites whatever tables the disk has now
+pub fn restore_tables(
+    st: &mut SystemTable<Boot>,
+    img_handle: Handle,
+    disk_handle: Handle,
+    path: &str
+) -> uefi::Result {
+    let bs = st.boot_services();
+
+    // read the whole file, it is never more than a few KiB
+    let mut data: Vec<u8> = Vec::new();
+    let mut file = open_esp_file(bs, img_handle, path, FileMode::Read)?;
+    let mut chunk = [0u8; 4096];
+    loop {
+        let len = file.read(&mut chunk).map_err(|e| uefi::Error::from(e.status()))?;
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&chunk[..len]);
+    }
+
+    let backup = TableBackup::from_bytes(&data)
+        .map_err(|_| uefi::Error::from(Status::VOLUME_CORRUPTED))?;
+
+    let bi = match open_handle_block_io(bs, img_handle, disk_handle) {
+        Some(bi) => bi,
+        None => return Err(Status::NOT_FOUND.into())
+    };
+    backup.write(disk_handle, unsafe{&mut *bi.interface.get()})?;
+    info!("Restored partition tables from {}", path);
+    Ok(())
+}